@@ -0,0 +1,1140 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+
+use crate::blip::BlipBuffer;
+use crate::memory::ColorMode;
+use crate::state::{StateReader, StateWriter};
+use crate::vgm::VgmLog;
+
+/// Rate at which the APU is clocked, which is one tick per dot
+pub const CLOCK_RATE: u32 = 4_194_304;
+
+// Dots between steps of the frame sequencer, which runs at 512 Hz
+const FRAME_SEQUENCER_PERIOD: u32 = CLOCK_RATE / 512;
+
+const WAVE_RAM_SIZE: usize = 0x10;
+
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+// Fraction of the charge the high-pass filter's capacitor keeps every dot,
+// which differs between models
+const DMG_CHARGE_FACTOR: f64 = 0.999958;
+const CGB_CHARGE_FACTOR: f64 = 0.998943;
+
+/// Waveforms of the four duty cycles of the pulse channels
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+/// Bits of each register from NR10 to NR52 that always read as 1, either
+/// because they are unused or because the register is write only
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+];
+
+/// Receives the audio produced by the APU
+pub trait AudioSink {
+    /// Receive one stereo sample, with both channels in the -1.0 to 1.0 range
+    fn push_sample(&mut self, left: f32, right: f32);
+
+    /// Flush whatever the sink has buffered, once no more samples are coming
+    fn finish(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+/// The four sound channels, with the value of their bit in NR51 and NR52
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Channel {
+    Pulse1 = 0x01,
+    Pulse2 = 0x02,
+    Wave = 0x04,
+    Noise = 0x08,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 4] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Wave,
+        Channel::Noise,
+    ];
+}
+
+/// Length counter, which silences a channel after a set amount of time
+struct Length {
+    counter: u16,
+    max: u16,
+    enabled: bool,
+}
+
+impl Length {
+    fn new(max: u16) -> Self {
+        Self {
+            counter: 0,
+            max,
+            enabled: false,
+        }
+    }
+
+    /// Load the counter from the length bits of NRx1
+    fn load(&mut self, length: u16) {
+        self.counter = self.max - length;
+    }
+
+    /// Clock the counter, returning whether it expired and the channel
+    /// should be disabled
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    /// Write the enable bit of NRx4, returning whether the channel should be
+    /// disabled. When the next frame sequencer step does not clock lengths,
+    /// enabling the counter clocks it once right away.
+    fn write_enable(&mut self, enabled: bool, first_half: bool, trigger: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = enabled;
+        let mut expired = false;
+        if !was_enabled && enabled && first_half {
+            expired = self.clock();
+        }
+        if trigger && self.counter == 0 {
+            // Triggering with an expired counter reloads it, and it gets
+            // clocked right away under the same condition as above
+            self.counter = self.max;
+            if enabled && first_half {
+                self.counter -= 1;
+            }
+        }
+        expired && !trigger
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.counter);
+        writer.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.counter = reader.read_u16()?.min(self.max);
+        self.enabled = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+/// Volume envelope of the pulse and noise channels (NRx2)
+struct Envelope {
+    register: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Self {
+            register: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    /// Whether the channel's DAC is on, which takes any of the upper five bits
+    fn dac_enabled(&self) -> bool {
+        self.register & 0xF8 != 0
+    }
+
+    fn period(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.register >> 4;
+        self.timer = self.period();
+    }
+
+    fn clock(&mut self) {
+        if self.period() == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period();
+            if self.register & 0x08 != 0 && self.volume < 15 {
+                self.volume += 1;
+            } else if self.register & 0x08 == 0 && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.register);
+        writer.write_u8(self.volume);
+        writer.write_u8(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.register = reader.read_u8()?;
+        self.volume = reader.read_u8()? & 0x0F;
+        self.timer = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+/// Frequency sweep of the first pulse channel (NR10)
+struct Sweep {
+    register: u8,
+    enabled: bool,
+    shadow: u16,
+    timer: u8,
+    // Whether a calculation was made in negate mode since the last trigger,
+    // after which leaving negate mode disables the channel
+    negated: bool,
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Self {
+            register: 0,
+            enabled: false,
+            shadow: 0,
+            timer: 0,
+            negated: false,
+        }
+    }
+
+    fn period(&self) -> u8 {
+        (self.register >> 4) & 0x07
+    }
+
+    fn negate(&self) -> bool {
+        self.register & 0x08 != 0
+    }
+
+    fn shift(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    fn reload_timer(&mut self) {
+        // A period of 0 is treated as 8
+        self.timer = match self.period() {
+            0 => 8,
+            period => period,
+        };
+    }
+
+    /// Compute the next frequency, which is over 2047 when it overflows
+    fn calculate(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift();
+        if self.negate() {
+            self.negated = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.register);
+        writer.write_bool(self.enabled);
+        writer.write_u16(self.shadow);
+        writer.write_u8(self.timer);
+        writer.write_bool(self.negated);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.register = reader.read_u8()?;
+        self.enabled = reader.read_bool()?;
+        self.shadow = reader.read_u16()? & 0x07FF;
+        self.timer = reader.read_u8()?;
+        self.negated = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+/// Pulse channels 1 and 2, the first one with a frequency sweep
+struct Pulse {
+    enabled: bool,
+    sweep: Option<Sweep>,
+    duty: u8,
+    length: Length,
+    envelope: Envelope,
+    frequency: u16,
+    timer: u32,
+    duty_step: usize,
+}
+
+impl Pulse {
+    fn new(sweep: bool) -> Self {
+        Self {
+            enabled: false,
+            sweep: if sweep { Some(Sweep::new()) } else { None },
+            duty: 0,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            frequency: 0,
+            timer: 0,
+            duty_step: 0,
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = self.period();
+        self.envelope.trigger();
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow = self.frequency;
+            sweep.negated = false;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period() != 0 || sweep.shift() != 0;
+            // With a shift, the overflow check is made right away
+            if sweep.shift() != 0 && sweep.calculate() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let sweep = match self.sweep.as_mut() {
+            Some(sweep) => sweep,
+            None => return,
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period() == 0 {
+            return;
+        }
+        let frequency = sweep.calculate();
+        if frequency > 2047 {
+            self.enabled = false;
+        } else if sweep.shift() != 0 {
+            sweep.shadow = frequency;
+            self.frequency = frequency;
+            // The new frequency is checked for overflow again but not used
+            if sweep.calculate() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// Return the digital output of the channel, from 0 to 15
+    fn output(&self) -> u8 {
+        if self.enabled && DUTY_PATTERNS[self.duty as usize][self.duty_step] != 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        if let Some(sweep) = self.sweep.as_ref() {
+            sweep.save_state(writer);
+        }
+        writer.write_u8(self.duty);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.write_u16(self.frequency);
+        writer.write_u32(self.timer);
+        writer.write_u32(self.duty_step as u32);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.enabled = reader.read_bool()?;
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.load_state(reader)?;
+        }
+        self.duty = reader.read_u8()? & 0x03;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.frequency = reader.read_u16()? & 0x07FF;
+        self.timer = reader.read_u32()?;
+        self.duty_step = reader.read_below(8)? as usize;
+        Ok(())
+    }
+}
+
+/// Wave channel 3, which plays back the 32 4-bit samples in wave RAM
+struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    length: Length,
+    // Output level code from NR32, which shifts the samples right
+    level: u8,
+    frequency: u16,
+    timer: u32,
+    position: usize,
+    sample: u8,
+    ram: [u8; WAVE_RAM_SIZE],
+}
+
+impl Wave {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            length: Length::new(256),
+            level: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            ram: [0; WAVE_RAM_SIZE],
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.position = 0;
+        // The first sample is only fetched after a short delay
+        self.timer = self.period() + 6;
+    }
+
+    fn tick(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+            let byte = self.ram[self.position / 2];
+            self.sample = if self.position & 1 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match self.level {
+            0 => 0,
+            level => self.sample >> (level - 1),
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.dac_enabled);
+        self.length.save_state(writer);
+        writer.write_u8(self.level);
+        writer.write_u16(self.frequency);
+        writer.write_u32(self.timer);
+        writer.write_u32(self.position as u32);
+        writer.write_u8(self.sample);
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.enabled = reader.read_bool()?;
+        self.dac_enabled = reader.read_bool()?;
+        self.length.load_state(reader)?;
+        self.level = reader.read_u8()? & 0x03;
+        self.frequency = reader.read_u16()? & 0x07FF;
+        self.timer = reader.read_u32()?;
+        self.position = reader.read_below(32)? as usize;
+        self.sample = reader.read_u8()? & 0x0F;
+        reader.read_bytes(&mut self.ram)
+    }
+}
+
+/// Noise channel 4, driven by a linear feedback shift register
+struct Noise {
+    enabled: bool,
+    length: Length,
+    envelope: Envelope,
+    // Polynomial counter register (NR43)
+    polynomial: u8,
+    timer: u32,
+    lfsr: u16,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            polynomial: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+        }
+    }
+
+    fn period(&self) -> u32 {
+        let divisor = match self.polynomial & 0x07 {
+            0 => 8,
+            code => code as u32 * 16,
+        };
+        divisor << (self.polynomial >> 4)
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    fn tick(&mut self) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period();
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.polynomial & 0x08 != 0 {
+                // 7-bit mode also feeds the result back into bit 6
+                self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 1 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.write_u8(self.polynomial);
+        writer.write_u32(self.timer);
+        writer.write_u16(self.lfsr);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.enabled = reader.read_bool()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.polynomial = reader.read_u8()?;
+        self.timer = reader.read_u32()?;
+        self.lfsr = reader.read_u16()? & 0x7FFF;
+        Ok(())
+    }
+}
+
+/// High-pass filter formed by the capacitor on each side of the output,
+/// which removes the DC offset of the DACs
+struct HighPass {
+    capacitor: f32,
+}
+
+impl HighPass {
+    fn new() -> Self {
+        Self { capacitor: 0.0 }
+    }
+
+    /// Filter one sample, given the charge the capacitor keeps over it. The
+    /// filter is only connected while one of the DACs is on.
+    fn filter(&mut self, input: f32, charge_factor: f32, connected: bool) -> f32 {
+        if !connected {
+            return 0.0;
+        }
+        let output = input - self.capacitor;
+        self.capacitor = input - output * charge_factor;
+        output
+    }
+}
+
+/// Audio Processing Unit (APU)
+pub struct Apu {
+    color_mode: ColorMode,
+    powered: bool,
+    // Last values written to NR10-NR51, which are read back through masks
+    registers: [u8; 0x16],
+    pulse1: Pulse,
+    pulse2: Pulse,
+    wave: Wave,
+    noise: Noise,
+    // Dots until the next step of the frame sequencer, and that step
+    frame_timer: u32,
+    frame_step: u8,
+    // Masks of the channels left out of the mix, and of the only ones to
+    // mix when not empty
+    muted: u8,
+    soloed: u8,
+    sink: Option<Box<dyn AudioSink>>,
+    sample_rate: u32,
+    vgm: Option<VgmLog>,
+    // Band-limited buffers for either side of the output, fed with the
+    // changes of the mixed signal, and the mix they were last fed
+    blips: (BlipBuffer, BlipBuffer),
+    mixed: (f32, f32),
+    high_passes: (HighPass, HighPass),
+    charge_factor: f32,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Self {
+            color_mode: ColorMode::NoColor,
+            powered: true,
+            registers: [0; 0x16],
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            frame_timer: FRAME_SEQUENCER_PERIOD,
+            frame_step: 0,
+            muted: 0,
+            soloed: 0,
+            sink: None,
+            vgm: None,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            blips: (
+                BlipBuffer::new(CLOCK_RATE, DEFAULT_SAMPLE_RATE),
+                BlipBuffer::new(CLOCK_RATE, DEFAULT_SAMPLE_RATE),
+            ),
+            mixed: (0.0, 0.0),
+            high_passes: (HighPass::new(), HighPass::new()),
+            charge_factor: charge_factor(ColorMode::NoColor, DEFAULT_SAMPLE_RATE),
+        }
+    }
+
+    pub fn set_color_mode(&mut self, color_mode: ColorMode) {
+        self.color_mode = color_mode;
+        self.charge_factor = charge_factor(color_mode, self.sample_rate);
+    }
+
+    /// Send the audio to the given sink, producing samples at the given rate
+    pub fn set_sink(&mut self, sink: Box<dyn AudioSink>, sample_rate: u32) {
+        self.sink = Some(sink);
+        self.sample_rate = sample_rate;
+        self.blips = (
+            BlipBuffer::new(CLOCK_RATE, sample_rate),
+            BlipBuffer::new(CLOCK_RATE, sample_rate),
+        );
+        self.mixed = (0.0, 0.0);
+        self.high_passes = (HighPass::new(), HighPass::new());
+        self.charge_factor = charge_factor(self.color_mode, sample_rate);
+    }
+
+    /// Swap the sink for another, or for none, returning the previous one.
+    /// Unlike `set_sink`, the output carries on where it was.
+    pub fn replace_sink(&mut self, sink: Option<Box<dyn AudioSink>>) -> Option<Box<dyn AudioSink>> {
+        std::mem::replace(&mut self.sink, sink)
+    }
+
+    /// Flush the sink once no more samples are going to be sent to it
+    pub fn finish_sink(&mut self) -> Result<(), io::Error> {
+        match self.sink.as_mut() {
+            Some(sink) => sink.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Leave the given channel out of the mix. This only affects the output,
+    /// not the channel itself.
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        if muted {
+            self.muted |= channel as u8;
+        } else {
+            self.muted &= !(channel as u8);
+        }
+    }
+
+    /// Mix only the soloed channels while any of them is, muted or not
+    pub fn set_soloed(&mut self, channel: Channel, soloed: bool) {
+        if soloed {
+            self.soloed |= channel as u8;
+        } else {
+            self.soloed &= !(channel as u8);
+        }
+    }
+
+    /// Whether the given channel makes it to the mix
+    pub fn is_audible(&self, channel: Channel) -> bool {
+        if self.soloed != 0 {
+            self.soloed & channel as u8 != 0
+        } else {
+            self.muted & channel as u8 == 0
+        }
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF26 => {
+                let channels = [
+                    self.pulse1.enabled,
+                    self.pulse2.enabled,
+                    self.wave.enabled,
+                    self.noise.enabled,
+                ];
+                let mut value = READ_MASKS[0x16] | (self.powered as u8) << 7;
+                for (i, &enabled) in channels.iter().enumerate() {
+                    value |= (enabled as u8) << i;
+                }
+                value
+            }
+            0xFF10..=0xFF25 => {
+                let index = (address - 0xFF10) as usize;
+                self.registers[index] | READ_MASKS[index]
+            }
+            0xFF30..=0xFF3F => self.read_wave_ram(address),
+            // PCM12 and PCM34, which only exist on the CGB
+            0xFF76 => self.pulse2.output() << 4 | self.pulse1.output(),
+            0xFF77 => self.noise.output() << 4 | self.wave.output(),
+            _ => 0xFF,
+        }
+    }
+
+    /// Return the values last written to NR10-NR51, which include the bits
+    /// that cannot be read back
+    pub fn written_registers(&self) -> &[u8] {
+        &self.registers
+    }
+
+    /// Save the sound hardware, leaving out the settings and the audio
+    /// output, which belong to whoever is listening rather than to the game
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.powered);
+        writer.write_bytes(&self.registers);
+        self.pulse1.save_state(writer);
+        self.pulse2.save_state(writer);
+        self.wave.save_state(writer);
+        self.noise.save_state(writer);
+        writer.write_u32(self.frame_timer);
+        writer.write_u8(self.frame_step);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.powered = reader.read_bool()?;
+        reader.read_bytes(&mut self.registers)?;
+        self.pulse1.load_state(reader)?;
+        self.pulse2.load_state(reader)?;
+        self.wave.load_state(reader)?;
+        self.noise.load_state(reader)?;
+        self.frame_timer = reader.read_u32()?.clamp(1, FRAME_SEQUENCER_PERIOD);
+        self.frame_step = reader.read_u8()? % 8;
+        Ok(())
+    }
+
+    /// Start logging the register writes, beginning with the ones that bring
+    /// a freshly reset APU to its current state
+    pub fn start_vgm_log(&mut self) {
+        let mut writes = vec![(0xFF26, (self.powered as u8) << 7)];
+        if self.powered {
+            for (i, &value) in self.wave.ram.iter().enumerate() {
+                writes.push((0xFF30 + i as u16, value));
+            }
+            for (i, &value) in self.registers.iter().enumerate() {
+                let address = 0xFF10 + i as u16;
+                // Leave out the trigger bits, which would restart channels
+                let value = match address {
+                    0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => value & 0x7F,
+                    _ => value,
+                };
+                if address != 0xFF15 && address != 0xFF1F {
+                    writes.push((address, value));
+                }
+            }
+        }
+        self.vgm = Some(VgmLog::new(self.state_fingerprint(), &writes));
+    }
+
+    /// Stop logging the register writes, returning the log
+    pub fn take_vgm_log(&mut self) -> Option<VgmLog> {
+        self.vgm.take()
+    }
+
+    /// Swap the log for another, or for none, returning the previous one
+    pub fn replace_vgm_log(&mut self, vgm: Option<VgmLog>) -> Option<VgmLog> {
+        std::mem::replace(&mut self.vgm, vgm)
+    }
+
+    /// Hash the state of the APU that the music driver controls, leaving out
+    /// the timers that are unlikely to ever line up again
+    fn state_fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.powered.hash(&mut hasher);
+        self.registers.hash(&mut hasher);
+        self.wave.ram.hash(&mut hasher);
+        for pulse in &[&self.pulse1, &self.pulse2] {
+            pulse.enabled.hash(&mut hasher);
+            pulse.frequency.hash(&mut hasher);
+            pulse.envelope.volume.hash(&mut hasher);
+            pulse.length.counter.hash(&mut hasher);
+        }
+        self.wave.enabled.hash(&mut hasher);
+        self.wave.length.counter.hash(&mut hasher);
+        self.noise.enabled.hash(&mut hasher);
+        self.noise.envelope.volume.hash(&mut hasher);
+        self.noise.length.counter.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        if let (Some(vgm), 0xFF10..=0xFF3F) = (self.vgm.as_mut(), address) {
+            vgm.write(address, value);
+        }
+        match address {
+            0xFF26 => self.write_power(value & 0x80 != 0),
+            0xFF30..=0xFF3F => self.write_wave_ram(address, value),
+            // While powered off, only the length counters can be written, and
+            // only on the DMG
+            0xFF11 | 0xFF16 | 0xFF1B | 0xFF20
+                if !self.powered && self.color_mode == ColorMode::NoColor =>
+            {
+                self.write_length(address, value)
+            }
+            0xFF10..=0xFF25 if self.powered => {
+                self.registers[(address - 0xFF10) as usize] = value;
+                self.write_channel_register(address, value);
+            }
+            _ => {}
+        }
+    }
+
+    fn write_length(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF11 => self.pulse1.length.load((value & 0x3F) as u16),
+            0xFF16 => self.pulse2.length.load((value & 0x3F) as u16),
+            0xFF1B => self.wave.length.load(value as u16),
+            0xFF20 => self.noise.length.load((value & 0x3F) as u16),
+            _ => {}
+        }
+    }
+
+    fn write_channel_register(&mut self, address: u16, value: u8) {
+        // The frame sequencer is in the first half of a length period when
+        // its next step is not going to clock the length counters
+        let first_half = self.frame_step & 1 != 0;
+        let trigger = value & 0x80 != 0;
+        let length_enabled = value & 0x40 != 0;
+        match address {
+            0xFF10 => {
+                let sweep = self.pulse1.sweep.as_mut().unwrap();
+                let was_negate = sweep.negate();
+                sweep.register = value;
+                if was_negate && !sweep.negate() && sweep.negated {
+                    self.pulse1.enabled = false;
+                }
+            }
+            0xFF11 | 0xFF16 => {
+                let pulse = self.pulse_mut(address);
+                pulse.duty = value >> 6;
+                pulse.length.load((value & 0x3F) as u16);
+            }
+            0xFF12 | 0xFF17 => {
+                let pulse = self.pulse_mut(address);
+                pulse.envelope.register = value;
+                if !pulse.envelope.dac_enabled() {
+                    pulse.enabled = false;
+                }
+            }
+            0xFF13 | 0xFF18 => {
+                let pulse = self.pulse_mut(address);
+                pulse.frequency = (pulse.frequency & 0x700) | value as u16;
+            }
+            0xFF14 | 0xFF19 => {
+                let pulse = self.pulse_mut(address);
+                pulse.frequency = (pulse.frequency & 0xFF) | ((value & 0x07) as u16) << 8;
+                if pulse
+                    .length
+                    .write_enable(length_enabled, first_half, trigger)
+                {
+                    pulse.enabled = false;
+                }
+                if trigger {
+                    pulse.trigger();
+                }
+            }
+            0xFF1A => {
+                self.wave.dac_enabled = value & 0x80 != 0;
+                if !self.wave.dac_enabled {
+                    self.wave.enabled = false;
+                }
+            }
+            0xFF1B => self.wave.length.load(value as u16),
+            0xFF1C => self.wave.level = (value >> 5) & 0x03,
+            0xFF1D => self.wave.frequency = (self.wave.frequency & 0x700) | value as u16,
+            0xFF1E => {
+                self.wave.frequency = (self.wave.frequency & 0xFF) | ((value & 0x07) as u16) << 8;
+                if self
+                    .wave
+                    .length
+                    .write_enable(length_enabled, first_half, trigger)
+                {
+                    self.wave.enabled = false;
+                }
+                if trigger {
+                    self.wave.trigger();
+                }
+            }
+            0xFF20 => self.noise.length.load((value & 0x3F) as u16),
+            0xFF21 => {
+                self.noise.envelope.register = value;
+                if !self.noise.envelope.dac_enabled() {
+                    self.noise.enabled = false;
+                }
+            }
+            0xFF22 => self.noise.polynomial = value,
+            0xFF23 => {
+                if self
+                    .noise
+                    .length
+                    .write_enable(length_enabled, first_half, trigger)
+                {
+                    self.noise.enabled = false;
+                }
+                if trigger {
+                    self.noise.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn pulse_mut(&mut self, address: u16) -> &mut Pulse {
+        if address < 0xFF15 {
+            &mut self.pulse1
+        } else {
+            &mut self.pulse2
+        }
+    }
+
+    /// Switch the APU on or off through NR52. Switching it off clears every
+    /// register and keeps them from being written until it is switched
+    /// back on, except for the length counters on the DMG.
+    fn write_power(&mut self, powered: bool) {
+        if self.powered && !powered {
+            let lengths = [
+                self.pulse1.length.counter,
+                self.pulse2.length.counter,
+                self.wave.length.counter,
+                self.noise.length.counter,
+            ];
+            let ram = self.wave.ram;
+            self.registers = [0; 0x16];
+            self.pulse1 = Pulse::new(true);
+            self.pulse2 = Pulse::new(false);
+            self.wave = Wave::new();
+            self.noise = Noise::new();
+            self.wave.ram = ram;
+            if self.color_mode == ColorMode::NoColor {
+                self.pulse1.length.counter = lengths[0];
+                self.pulse2.length.counter = lengths[1];
+                self.wave.length.counter = lengths[2];
+                self.noise.length.counter = lengths[3];
+            }
+        } else if !self.powered && powered {
+            self.frame_step = 0;
+            self.frame_timer = FRAME_SEQUENCER_PERIOD;
+        }
+        self.powered = powered;
+    }
+
+    /// While the wave channel is playing, wave RAM can only be accessed at
+    /// the byte it is reading. The CGB redirects accesses there, while the
+    /// DMG reads 0xFF and ignores writes.
+    fn read_wave_ram(&self, address: u16) -> u8 {
+        if self.wave.enabled {
+            match self.color_mode {
+                ColorMode::Color => self.wave.ram[self.wave.position / 2],
+                ColorMode::NoColor => 0xFF,
+            }
+        } else {
+            self.wave.ram[(address - 0xFF30) as usize]
+        }
+    }
+
+    fn write_wave_ram(&mut self, address: u16, value: u8) {
+        if self.wave.enabled {
+            if self.color_mode == ColorMode::Color {
+                self.wave.ram[self.wave.position / 2] = value;
+            }
+        } else {
+            self.wave.ram[(address - 0xFF30) as usize] = value;
+        }
+    }
+
+    /// Advance the APU by the given number of dots
+    pub fn tick(&mut self, dots: u32) {
+        for _ in 0..dots {
+            if self.powered {
+                self.tick_dot();
+            }
+            if self.sink.is_some() {
+                self.update_output();
+            }
+            if self.vgm.as_mut().is_some_and(|vgm| vgm.tick()) {
+                let fingerprint = self.state_fingerprint();
+                if let Some(vgm) = self.vgm.as_mut() {
+                    vgm.start_frame(fingerprint);
+                }
+            }
+        }
+    }
+
+    fn tick_dot(&mut self) {
+        self.frame_timer -= 1;
+        if self.frame_timer == 0 {
+            self.frame_timer = FRAME_SEQUENCER_PERIOD;
+            self.step_frame_sequencer();
+        }
+        self.pulse1.tick();
+        self.pulse2.tick();
+        self.wave.tick();
+        self.noise.tick();
+    }
+
+    /// Clock the lengths on every other step, the sweep on steps 2 and 6,
+    /// and the envelopes on step 7
+    fn step_frame_sequencer(&mut self) {
+        if self.frame_step & 1 == 0 {
+            if self.pulse1.length.clock() {
+                self.pulse1.enabled = false;
+            }
+            if self.pulse2.length.clock() {
+                self.pulse2.enabled = false;
+            }
+            if self.wave.length.clock() {
+                self.wave.enabled = false;
+            }
+            if self.noise.length.clock() {
+                self.noise.enabled = false;
+            }
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.pulse1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.pulse1.envelope.clock();
+            self.pulse2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    /// Return the analog output of each channel's DAC, from -1.0 to 1.0
+    fn dac_outputs(&self) -> [f32; 4] {
+        let dac = |enabled: bool, digital: u8| {
+            if enabled {
+                1.0 - digital as f32 / 7.5
+            } else {
+                0.0
+            }
+        };
+        [
+            dac(self.pulse1.envelope.dac_enabled(), self.pulse1.output()),
+            dac(self.pulse2.envelope.dac_enabled(), self.pulse2.output()),
+            dac(self.wave.dac_enabled, self.wave.output()),
+            dac(self.noise.envelope.dac_enabled(), self.noise.output()),
+        ]
+    }
+
+    /// Mix the channels into a stereo sample following the panning in NR51
+    /// and the master volume in NR50
+    fn mix(&self) -> (f32, f32) {
+        let nr50 = self.registers[0x14];
+        let nr51 = self.registers[0x15];
+        let (mut left, mut right) = (0.0, 0.0);
+        for (i, output) in self.dac_outputs().iter().enumerate() {
+            if !self.is_audible(Channel::ALL[i]) {
+                continue;
+            }
+            if nr51 & (0x10 << i) != 0 {
+                left += output;
+            }
+            if nr51 & (0x01 << i) != 0 {
+                right += output;
+            }
+        }
+        let left_volume = ((nr50 >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (nr50 & 0x07) as f32 + 1.0;
+        (
+            left / 4.0 * left_volume / 8.0,
+            right / 4.0 * right_volume / 8.0,
+        )
+    }
+
+    /// Feed the changes of the mix to the band-limited buffers and send the
+    /// samples they complete to the sink
+    fn update_output(&mut self) {
+        let (left, right) = self.mix();
+        if left != self.mixed.0 {
+            self.blips.0.add_delta(left - self.mixed.0);
+        }
+        if right != self.mixed.1 {
+            self.blips.1.add_delta(right - self.mixed.1);
+        }
+        self.mixed = (left, right);
+        self.blips.0.advance(1);
+        self.blips.1.advance(1);
+        let connected = self.any_dac_enabled();
+        while let Some(left) = self.blips.0.read_sample() {
+            let right = self.blips.1.read_sample().unwrap_or(0.0);
+            let left = self
+                .high_passes
+                .0
+                .filter(left, self.charge_factor, connected);
+            let right = self
+                .high_passes
+                .1
+                .filter(right, self.charge_factor, connected);
+            if let Some(sink) = self.sink.as_mut() {
+                sink.push_sample(left, right);
+            }
+        }
+    }
+
+    fn any_dac_enabled(&self) -> bool {
+        self.pulse1.envelope.dac_enabled()
+            || self.pulse2.envelope.dac_enabled()
+            || self.wave.dac_enabled
+            || self.noise.envelope.dac_enabled()
+    }
+}
+
+/// Return the charge the capacitors keep over one sample at the given rate
+fn charge_factor(color_mode: ColorMode, sample_rate: u32) -> f32 {
+    let factor = match color_mode {
+        ColorMode::NoColor => DMG_CHARGE_FACTOR,
+        ColorMode::Color => CGB_CHARGE_FACTOR,
+    };
+    factor.powf(CLOCK_RATE as f64 / sample_rate as f64) as f32
+}
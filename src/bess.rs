@@ -0,0 +1,359 @@
+use std::io;
+
+use crate::memory::Model;
+
+const FOOTER_MAGIC: &[u8; 4] = b"BESS";
+const MAJOR_VERSION: u16 = 1;
+const MINOR_VERSION: u16 = 1;
+const CORE_SIZE: usize = 0xD0;
+const INFO_SIZE: usize = 0x12;
+const SGB_SIZE: usize = 0x39;
+const RTC_SIZE: usize = 0x30;
+pub const IO_SIZE: usize = 0x80;
+
+/// What the CPU was doing when a BESS state was saved
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Execution {
+    Running,
+    Halted,
+    Stopped,
+}
+
+/// Super Game Boy buffers of a BESS state, in the formats the Super Game
+/// Boy receives them in
+#[derive(Default)]
+pub struct BessSgb {
+    pub border_tiles: Vec<u8>,
+    pub border_map: Vec<u8>,
+    pub border_palettes: Vec<u8>,
+    pub palettes: Vec<u8>,
+    pub system_palettes: Vec<u8>,
+    pub attributes: Vec<u8>,
+    pub attribute_files: Vec<u8>,
+    // Number of joypads in the upper nibble, and the current one in the lower
+    pub multiplayer: u8,
+}
+
+/// Real time clock of an MBC3 cartridge in a BESS state
+#[derive(Default)]
+pub struct BessRtc {
+    // Seconds, minutes, hours, and the lower and upper day registers, both
+    // running and as last latched
+    pub registers: [u8; 5],
+    pub latched: [u8; 5],
+    // UNIX time the state was saved at, which emulators that run the clock
+    // on real time use to catch up with the time that went by since
+    pub timestamp: u64,
+}
+
+/// BESS (Best Effort Save State), a block format documented by SameBoy that
+/// emulators append to their own save states so that they can load each
+/// other's
+///
+/// The file ends with the offset of the first block and the "BESS" magic.
+/// Each block has a 4 character name and the length of its content. The
+/// CORE block points to the memory buffers, stored wherever in the file,
+/// MBC lists the register writes that restore the memory bank controller,
+/// and RTC holds the real time clock. Values are little endian.
+pub struct Bess {
+    // Title and global checksum of the ROM, from INFO
+    pub rom_info: Option<([u8; 0x10], u16)>,
+    pub model: Model,
+    pub pc: u16,
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+    pub ime: bool,
+    pub interrupt_enable: u8,
+    pub execution: Execution,
+    // Registers at 0xFF00-0xFF7F, as last written for the write-only ones
+    pub io: [u8; IO_SIZE],
+    pub ram: Vec<u8>,
+    pub vram: Vec<u8>,
+    pub mbc_ram: Vec<u8>,
+    pub oam: Vec<u8>,
+    pub hram: Vec<u8>,
+    pub bg_palettes: Vec<u8>,
+    pub obj_palettes: Vec<u8>,
+    pub mbc_writes: Vec<(u16, u8)>,
+    pub rtc: Option<BessRtc>,
+    pub sgb: Option<BessSgb>,
+}
+
+impl Default for Bess {
+    fn default() -> Self {
+        Self {
+            rom_info: None,
+            model: Model::Dmg,
+            pc: 0,
+            af: 0,
+            bc: 0,
+            de: 0,
+            hl: 0,
+            sp: 0,
+            ime: false,
+            interrupt_enable: 0,
+            execution: Execution::Running,
+            io: [0xFF; IO_SIZE],
+            ram: Vec::new(),
+            vram: Vec::new(),
+            mbc_ram: Vec::new(),
+            oam: Vec::new(),
+            hram: Vec::new(),
+            bg_palettes: Vec::new(),
+            obj_palettes: Vec::new(),
+            mbc_writes: Vec::new(),
+            rtc: None,
+            sgb: None,
+        }
+    }
+}
+
+impl Bess {
+    /// Parse the BESS blocks at the end of a save state. Blocks that do not
+    /// map onto anything emulated are skipped.
+    pub fn parse(data: &[u8]) -> Result<Self, io::Error> {
+        if data.len() < 8 || &data[data.len() - 4..] != FOOTER_MAGIC {
+            return Err(invalid_data("Not a BESS save state".to_string()));
+        }
+        let mut position = read_u32(data, data.len() - 8)? as usize;
+        let mut bess = Self::default();
+        let mut has_core = false;
+        loop {
+            let name = data.get(position..position + 4).ok_or_else(truncated)?;
+            let length = read_u32(data, position + 4)? as usize;
+            let block = data
+                .get(position + 8..position + 8 + length)
+                .ok_or_else(truncated)?;
+            match name {
+                b"END " => break,
+                b"INFO" if length >= INFO_SIZE => {
+                    let mut title = [0; 0x10];
+                    title.copy_from_slice(&block[..0x10]);
+                    bess.rom_info = Some((title, u16::from_be_bytes([block[0x10], block[0x11]])));
+                }
+                b"CORE" => {
+                    bess.parse_core(data, block)?;
+                    has_core = true;
+                }
+                b"MBC " => {
+                    bess.mbc_writes = block
+                        .chunks_exact(3)
+                        .map(|write| (u16::from_le_bytes([write[0], write[1]]), write[2]))
+                        .collect();
+                }
+                b"RTC " if length >= RTC_SIZE => {
+                    // Each register takes 4 bytes, of which only the first
+                    // one is used
+                    let mut rtc = BessRtc::default();
+                    for i in 0..5 {
+                        rtc.registers[i] = block[i * 4];
+                        rtc.latched[i] = block[0x14 + i * 4];
+                    }
+                    let mut timestamp = [0; 8];
+                    timestamp.copy_from_slice(&block[0x28..0x30]);
+                    rtc.timestamp = u64::from_le_bytes(timestamp);
+                    bess.rtc = Some(rtc);
+                }
+                b"SGB " if length >= SGB_SIZE => {
+                    let buffer = |offset: usize| read_buffer(data, block, offset);
+                    bess.sgb = Some(BessSgb {
+                        border_tiles: buffer(0x00)?,
+                        border_map: buffer(0x08)?,
+                        border_palettes: buffer(0x10)?,
+                        palettes: buffer(0x18)?,
+                        system_palettes: buffer(0x20)?,
+                        attributes: buffer(0x28)?,
+                        attribute_files: buffer(0x30)?,
+                        multiplayer: block[0x38],
+                    });
+                }
+                _ => {}
+            }
+            position += 8 + length;
+        }
+        if !has_core {
+            return Err(invalid_data("The BESS state has no CORE block".to_string()));
+        }
+        Ok(bess)
+    }
+
+    fn parse_core(&mut self, data: &[u8], core: &[u8]) -> Result<(), io::Error> {
+        if core.len() < CORE_SIZE {
+            return Err(invalid_data("The CORE block is too short".to_string()));
+        }
+        let word = |offset: usize| u16::from_le_bytes([core[offset], core[offset + 1]]);
+        if word(0x00) != MAJOR_VERSION {
+            let message = format!("Version {} of BESS is not supported", word(0x00));
+            return Err(invalid_data(message));
+        }
+        self.model = match core[0x04] {
+            b'G' => Model::Dmg,
+            b'S' => Model::Sgb,
+            b'C' => Model::Cgb,
+            _ => {
+                let model = String::from_utf8_lossy(&core[0x04..0x08]).into_owned();
+                return Err(invalid_data(format!("Unknown model {}", model)));
+            }
+        };
+        self.pc = word(0x08);
+        self.af = word(0x0A);
+        self.bc = word(0x0C);
+        self.de = word(0x0E);
+        self.hl = word(0x10);
+        self.sp = word(0x12);
+        self.ime = core[0x14] != 0;
+        self.interrupt_enable = core[0x15];
+        self.execution = match core[0x16] {
+            1 => Execution::Halted,
+            2 => Execution::Stopped,
+            _ => Execution::Running,
+        };
+        self.io.copy_from_slice(&core[0x18..0x18 + IO_SIZE]);
+        self.ram = read_buffer(data, core, 0x98)?;
+        self.vram = read_buffer(data, core, 0xA0)?;
+        self.mbc_ram = read_buffer(data, core, 0xA8)?;
+        self.oam = read_buffer(data, core, 0xB0)?;
+        self.hram = read_buffer(data, core, 0xB8)?;
+        self.bg_palettes = read_buffer(data, core, 0xC0)?;
+        self.obj_palettes = read_buffer(data, core, 0xC8)?;
+        Ok(())
+    }
+
+    /// Write the state as a BESS file, with the buffers first, followed by
+    /// the blocks and the footer
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut store = |buffer: &[u8]| {
+            let offset = data.len() as u32;
+            data.extend_from_slice(buffer);
+            (buffer.len() as u32, offset)
+        };
+        let core_buffers: Vec<(u32, u32)> = [
+            &self.ram,
+            &self.vram,
+            &self.mbc_ram,
+            &self.oam,
+            &self.hram,
+            &self.bg_palettes,
+            &self.obj_palettes,
+        ]
+        .iter()
+        .map(|buffer| store(buffer))
+        .collect();
+        let sgb_buffers: Option<Vec<(u32, u32)>> = self.sgb.as_ref().map(|sgb| {
+            [
+                &sgb.border_tiles,
+                &sgb.border_map,
+                &sgb.border_palettes,
+                &sgb.palettes,
+                &sgb.system_palettes,
+                &sgb.attributes,
+                &sgb.attribute_files,
+            ]
+            .iter()
+            .map(|buffer| store(buffer))
+            .collect()
+        });
+        let first_block = data.len() as u32;
+
+        let name = format!("tonzoboy v{}", env!("CARGO_PKG_VERSION"));
+        write_block(&mut data, b"NAME", name.as_bytes());
+
+        if let Some((title, checksum)) = self.rom_info {
+            let mut info = title.to_vec();
+            info.extend_from_slice(&checksum.to_be_bytes());
+            write_block(&mut data, b"INFO", &info);
+        }
+
+        let mut core = Vec::with_capacity(CORE_SIZE);
+        core.extend_from_slice(&MAJOR_VERSION.to_le_bytes());
+        core.extend_from_slice(&MINOR_VERSION.to_le_bytes());
+        core.extend_from_slice(match self.model {
+            Model::Dmg => b"GD  ",
+            Model::Sgb => b"SN  ",
+            Model::Cgb => b"CC  ",
+        });
+        for &register in &[self.pc, self.af, self.bc, self.de, self.hl, self.sp] {
+            core.extend_from_slice(&register.to_le_bytes());
+        }
+        core.push(self.ime as u8);
+        core.push(self.interrupt_enable);
+        core.push(match self.execution {
+            Execution::Running => 0,
+            Execution::Halted => 1,
+            Execution::Stopped => 2,
+        });
+        core.push(0);
+        core.extend_from_slice(&self.io);
+        for &(size, offset) in &core_buffers {
+            core.extend_from_slice(&size.to_le_bytes());
+            core.extend_from_slice(&offset.to_le_bytes());
+        }
+        write_block(&mut data, b"CORE", &core);
+
+        let mbc: Vec<u8> = self
+            .mbc_writes
+            .iter()
+            .flat_map(|&(address, value)| {
+                let address = address.to_le_bytes();
+                vec![address[0], address[1], value]
+            })
+            .collect();
+        write_block(&mut data, b"MBC ", &mbc);
+
+        if let Some(rtc) = self.rtc.as_ref() {
+            let mut block = Vec::with_capacity(RTC_SIZE);
+            for &register in rtc.registers.iter().chain(rtc.latched.iter()) {
+                block.extend_from_slice(&(register as u32).to_le_bytes());
+            }
+            block.extend_from_slice(&rtc.timestamp.to_le_bytes());
+            write_block(&mut data, b"RTC ", &block);
+        }
+
+        if let (Some(sgb), Some(buffers)) = (self.sgb.as_ref(), sgb_buffers) {
+            let mut block = Vec::with_capacity(SGB_SIZE);
+            for (size, offset) in buffers {
+                block.extend_from_slice(&size.to_le_bytes());
+                block.extend_from_slice(&offset.to_le_bytes());
+            }
+            block.push(sgb.multiplayer);
+            write_block(&mut data, b"SGB ", &block);
+        }
+
+        write_block(&mut data, b"END ", &[]);
+        data.extend_from_slice(&first_block.to_le_bytes());
+        data.extend_from_slice(FOOTER_MAGIC);
+        data
+    }
+}
+
+fn write_block(data: &mut Vec<u8>, name: &[u8; 4], content: &[u8]) {
+    data.extend_from_slice(name);
+    data.extend_from_slice(&(content.len() as u32).to_le_bytes());
+    data.extend_from_slice(content);
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, io::Error> {
+    let bytes = data.get(offset..offset + 4).ok_or_else(truncated)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Read a buffer of the file, given by the size and offset at the given
+/// position of a block
+fn read_buffer(data: &[u8], block: &[u8], position: usize) -> Result<Vec<u8>, io::Error> {
+    let size = read_u32(block, position)? as usize;
+    let offset = read_u32(block, position + 4)? as usize;
+    let buffer = data.get(offset..offset + size).ok_or_else(truncated)?;
+    Ok(buffer.to_vec())
+}
+
+fn truncated() -> io::Error {
+    invalid_data("The BESS state is truncated".to_string())
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,95 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+// Resolution of the position of a step between two output samples
+const PHASES: usize = 64;
+// Output samples each step is spread over, which also delays the output by
+// half as many samples
+const TAPS: usize = 16;
+// Cutoff of the low-pass filter, as a fraction of the output's Nyquist rate
+const CUTOFF: f64 = 0.9;
+
+/// Band-limited synthesis buffer
+///
+/// Rather than sampling a signal at the output rate, which aliases any of its
+/// content above the Nyquist rate, the changes in the signal are recorded as
+/// steps at the input clock they happen on. Each step is added to the output
+/// as a band-limited step, taken from a table of windowed sinc impulses that
+/// is integrated as samples are read out.
+pub struct BlipBuffer {
+    // Output samples per input clock
+    ratio: f64,
+    // Position of the current clock in output samples, relative to the
+    // first sample in the buffer
+    position: f64,
+    // Differences between consecutive output samples that are still being
+    // added to
+    buffer: VecDeque<f32>,
+    // Sum of the differences read out so far, which is the current sample
+    integrator: f32,
+    kernel: Vec<[f32; TAPS]>,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: u32, sample_rate: u32) -> Self {
+        Self {
+            ratio: sample_rate as f64 / clock_rate as f64,
+            position: 0.0,
+            buffer: std::iter::repeat_n(0.0, TAPS + 1).collect(),
+            integrator: 0.0,
+            kernel: (0..=PHASES)
+                .map(|phase| impulse(phase as f64 / PHASES as f64))
+                .collect(),
+        }
+    }
+
+    /// Add a step of the given size to the signal at the current clock
+    pub fn add_delta(&mut self, delta: f32) {
+        let index = self.position as usize;
+        let phase = ((self.position - index as f64) * PHASES as f64).round() as usize;
+        for (tap, &weight) in self.kernel[phase].iter().enumerate() {
+            self.buffer[index + tap] += delta * weight;
+        }
+    }
+
+    /// Advance the current clock by the given number of input clocks
+    pub fn advance(&mut self, clocks: u32) {
+        self.position += clocks as f64 * self.ratio;
+    }
+
+    /// Return the next output sample, once no later step can affect it
+    pub fn read_sample(&mut self) -> Option<f32> {
+        if self.position < 1.0 {
+            return None;
+        }
+        self.position -= 1.0;
+        self.integrator += self.buffer.pop_front().unwrap_or(0.0);
+        self.buffer.push_back(0.0);
+        Some(self.integrator)
+    }
+}
+
+/// Compute the impulse for a step the given fraction of a sample past the
+/// start of a phase, normalized so that it adds up to exactly the step size
+fn impulse(offset: f64) -> [f32; TAPS] {
+    let center = (TAPS / 2) as f64;
+    let mut taps = [0.0; TAPS];
+    for (tap, weight) in taps.iter_mut().enumerate() {
+        let x = tap as f64 - center - offset;
+        let sinc = if x == 0.0 {
+            CUTOFF
+        } else {
+            (PI * CUTOFF * x).sin() / (PI * x)
+        };
+        // Blackman window, spanning one sample past either end of the taps
+        let t = (x + center + 1.0) / (TAPS as f64 + 1.0);
+        let window = 0.42 - 0.5 * (2.0 * PI * t).cos() + 0.08 * (4.0 * PI * t).cos();
+        *weight = sinc * window;
+    }
+    let sum: f64 = taps.iter().sum();
+    let mut kernel = [0.0; TAPS];
+    for (weight, &tap) in kernel.iter_mut().zip(taps.iter()) {
+        *weight = (tap / sum) as f32;
+    }
+    kernel
+}
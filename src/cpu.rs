@@ -1,49 +1,541 @@
+use std::io;
 use std::path::Path;
 
 use crate::register::{Register, Flag, Flag::*};
 use crate::util::{make_word, lsb, msb, swap, rotate_left, rotate_right};
-use crate::memory::Mmu;
+use crate::apu::{AudioSink, Channel};
+use crate::bess::{Bess, Execution};
+use crate::infrared::InfraredDevice;
+use crate::joypad::{Button, MAX_PLAYERS};
+use crate::memory::{Mmu, Model};
+use crate::ppu::DOTS_PER_FRAME;
+use crate::rewind::Rewind;
+use crate::serial::SerialDevice;
+use crate::serial_log::SerialLog;
+use crate::sgb::Sgb;
+use crate::state::{State, StateWriter};
+use crate::vgm::VgmLog;
 
 pub struct Cpu {
     reg: Register,
     mmu: Mmu,
+    // Cycles already spent on memory accesses by the current instruction
+    access_cycles: u32,
+    // Cycles the current instruction was halted for by DMA transfers
+    // or a speed switch
+    stalled_cycles: u32,
+    // Whether STOP put the CPU into its low power mode
+    stopped: bool,
+    // Interrupt master enable, and whether EI is going to set it after the
+    // next instruction
+    ime: bool,
+    ime_scheduled: bool,
+    // Whether HALT is waiting for an interrupt, and whether it failed to
+    // because of the HALT bug, which keeps the next fetch from advancing PC
+    halted: bool,
+    halt_bug: bool,
+    // Whether an illegal opcode locked the CPU up, which only a reset undoes
+    locked: bool,
+    // History of the machine to go back in, when enabled
+    rewind: Option<Rewind>,
+}
+
+/// Whatever is plugged into the machine from the outside, listening to it
+/// or talking to it
+#[derive(Default)]
+struct Peripherals {
+    sink: Option<Box<dyn AudioSink>>,
+    vgm: Option<VgmLog>,
+    serial_device: Option<Box<dyn SerialDevice>>,
+    serial_log: Option<SerialLog>,
+    infrared_device: Option<Box<dyn InfraredDevice>>,
 }
 
 impl Cpu {
     pub fn new(rom_path: &Path) -> Self {
-        let mut cpu = Self {
-            reg: Register::new(),
-            mmu: Mmu::new(),
-        };
+        let mut cpu = Self::with_mmu(Mmu::new());
         cpu.mmu.load_rom(rom_path).expect("Failed to load the ROM");
         cpu
     }
 
+    /// Create a CPU running the given ROM image, rather than one from a file
+    pub fn from_rom(rom: Vec<u8>) -> Self {
+        let mut cpu = Self::with_mmu(Mmu::new());
+        cpu.mmu.load_rom_data(rom);
+        cpu
+    }
+
+    /// Create a CPU running the given code from the entry point at 0x0100,
+    /// in a ROM that is otherwise empty
+    #[cfg(test)]
+    pub(crate) fn with_code(code: &[u8]) -> Self {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + code.len()].copy_from_slice(code);
+        Self::from_rom(rom)
+    }
+
+    fn with_mmu(mmu: Mmu) -> Self {
+        Self {
+            reg: Register::new(),
+            mmu,
+            access_cycles: 0,
+            stalled_cycles: 0,
+            stopped: false,
+            ime: false,
+            ime_scheduled: false,
+            halted: false,
+            halt_bug: false,
+            locked: false,
+            rewind: None,
+        }
+    }
+
+    pub fn model(&self) -> Model {
+        self.mmu.model()
+    }
+
+    /// Emulate the given hardware rather than the one the game is for,
+    /// which has to be chosen before running
+    pub fn set_model(&mut self, model: Model) {
+        self.mmu.set_model(model);
+    }
+
+    /// Return the last frame on the Super Game Boy, colored and with the
+    /// border around it, as RGB555 colors. None unless the game runs with
+    /// the Super Game Boy features.
+    pub fn sgb_screen(&self) -> Option<&[u16]> {
+        self.mmu.sgb.as_ref().map(Sgb::screen)
+    }
+
+    /// Take a snapshot of the whole machine, which `load_state` can bring
+    /// it back to. What is plugged into the ports, the audio output and the
+    /// settings are not part of it.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.section(b"CPU ", |writer| {
+            for &value in &[self.reg.af(), self.reg.bc(), self.reg.de(), self.reg.hl()] {
+                writer.write_u16(value);
+            }
+            writer.write_u16(self.reg.sp);
+            writer.write_u16(self.reg.pc);
+            for &flag in &[self.stopped, self.ime, self.ime_scheduled, self.halted, self.halt_bug, self.locked] {
+                writer.write_bool(flag);
+            }
+        });
+        self.mmu.save_state(&mut writer);
+        writer.finish()
+    }
+
+    /// Bring the machine back to a snapshot taken by `save_state` for the
+    /// same ROM. The machine is left as it was if the state is invalid.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        let backup = self.save_state();
+        self.restore_state(data).inspect_err(|_| {
+            self.restore_state(&backup).expect("Failed to restore the state before loading");
+        })?;
+        self.clear_rewind();
+        Ok(())
+    }
+
+    fn restore_state(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        let state = State::parse(data)?;
+        state.read_section(b"CPU ", |reader| {
+            self.reg.set_af(reader.read_u16()?);
+            self.reg.set_bc(reader.read_u16()?);
+            self.reg.set_de(reader.read_u16()?);
+            self.reg.set_hl(reader.read_u16()?);
+            self.reg.sp = reader.read_u16()?;
+            self.reg.pc = reader.read_u16()?;
+            for flag in [
+                &mut self.stopped,
+                &mut self.ime,
+                &mut self.ime_scheduled,
+                &mut self.halted,
+                &mut self.halt_bug,
+                &mut self.locked,
+            ] {
+                *flag = reader.read_bool()?;
+            }
+            Ok(())
+        })?;
+        self.mmu.load_state(&state)
+    }
+
+    /// Export the machine as a BESS state, which other emulators can load
+    pub fn save_bess(&self) -> Vec<u8> {
+        let mut bess = Bess {
+            pc: self.reg.pc,
+            af: self.reg.af(),
+            bc: self.reg.bc(),
+            de: self.reg.de(),
+            hl: self.reg.hl(),
+            sp: self.reg.sp,
+            ime: self.ime,
+            execution: if self.stopped {
+                Execution::Stopped
+            } else if self.halted {
+                Execution::Halted
+            } else {
+                Execution::Running
+            },
+            ..Bess::default()
+        };
+        self.mmu.export_bess(&mut bess);
+        bess.to_bytes()
+    }
+
+    /// Import a BESS state saved by another emulator for the same ROM. As
+    /// BESS only covers what every emulator has in common, the rest of the
+    /// machine starts over from power on.
+    pub fn load_bess(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        let bess = Bess::parse(data)?;
+        self.mmu.import_bess(&bess)?;
+        self.reg.pc = bess.pc;
+        self.reg.set_af(bess.af);
+        self.reg.set_bc(bess.bc);
+        self.reg.set_de(bess.de);
+        self.reg.set_hl(bess.hl);
+        self.reg.sp = bess.sp;
+        self.ime = bess.ime;
+        self.ime_scheduled = false;
+        self.halted = bess.execution == Execution::Halted;
+        self.halt_bug = false;
+        self.stopped = bess.execution == Execution::Stopped;
+        self.clear_rewind();
+        Ok(())
+    }
+
+    /// Keep snapshots of the machine to go back to with `rewind`, or stop
+    /// keeping them with None
+    pub fn set_rewind(&mut self, rewind: Option<Rewind>) {
+        self.rewind = rewind;
+    }
+
+    /// Go back the given number of frames worth of real time, by restoring
+    /// the latest snapshot from before then and emulating forward from it
+    /// with the joypads as they were. Return the number of frames actually
+    /// gone back, which is fewer if the history does not go back that far.
+    ///
+    /// Whatever is plugged into the machine is unplugged while emulating
+    /// forward, so the audio, logs and link partners only ever see those
+    /// frames once. The machine is left as it was if the snapshot is
+    /// invalid, and the history is cleared.
+    pub fn rewind(&mut self, frames: u32) -> Result<u32, io::Error> {
+        let now = self.mmu.dots();
+        let target = now.saturating_sub(frames as u64 * DOTS_PER_FRAME as u64);
+        let snapshot = match self.rewind.as_mut().and_then(|rewind| rewind.go_back(target)) {
+            Some(snapshot) => snapshot,
+            None => return Ok(0),
+        };
+        let backup = self.save_state();
+        if let Err(error) = self.restore_state(&snapshot.state) {
+            self.clear_rewind();
+            self.restore_state(&backup)?;
+            return Err(error);
+        }
+        let peripherals = self.replace_peripherals(Peripherals::default());
+        let mut inputs = snapshot.inputs.into_iter().peekable();
+        while self.mmu.dots() < target {
+            // Inputs apply from the first instruction starting at or after
+            // the dots they were logged at, as they did the first time
+            while let Some((_, pressed)) = inputs.next_if(|&(dots, _)| dots <= self.mmu.dots()) {
+                self.mmu.joypad.set_pressed(pressed);
+            }
+            self.step();
+        }
+        self.replace_peripherals(peripherals);
+        let dots = self.mmu.dots();
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.truncate_inputs(dots);
+        }
+        Ok((now.saturating_sub(dots) / DOTS_PER_FRAME as u64) as u32)
+    }
+
+    /// Plug the given peripherals into the machine, returning the ones that
+    /// were plugged in
+    fn replace_peripherals(&mut self, peripherals: Peripherals) -> Peripherals {
+        Peripherals {
+            sink: self.mmu.apu.replace_sink(peripherals.sink),
+            vgm: self.mmu.apu.replace_vgm_log(peripherals.vgm),
+            serial_device: self.mmu.serial.replace_device(peripherals.serial_device),
+            serial_log: self.mmu.serial.replace_log(peripherals.serial_log),
+            infrared_device: self.mmu.infrared.replace_device(peripherals.infrared_device),
+        }
+    }
+
+    fn clear_rewind(&mut self) {
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.clear();
+        }
+    }
+
+    /// Log the joypads for `rewind` to press the buttons again
+    fn record_input(&mut self) {
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.record_input(self.mmu.dots(), self.mmu.joypad.pressed());
+        }
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.mmu.joypad.press(0, button);
+        self.record_input();
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.mmu.joypad.release(0, button);
+        self.record_input();
+    }
+
+    /// Press a button on the joypad of another player, counting from 0, for
+    /// Super Game Boy games that read up to 4; other players are ignored
+    pub fn press_player(&mut self, player: usize, button: Button) {
+        self.mmu.joypad.press(player, button);
+        self.record_input();
+    }
+
+    pub fn release_player(&mut self, player: usize, button: Button) {
+        self.mmu.joypad.release(player, button);
+        self.record_input();
+    }
+
+    /// Allow holding opposite directions of the D-pad at the same time,
+    /// which is impossible on real hardware but useful for TAS and glitches
+    pub fn set_allow_opposite_directions(&mut self, allow: bool) {
+        self.mmu.joypad.set_allow_opposite_directions(allow);
+    }
+
+    pub fn allow_opposite_directions(&self) -> bool {
+        self.mmu.joypad.allow_opposite_directions()
+    }
+
+    /// Return the mask of the buttons held on each joypad, with the bits of
+    /// `Button`
+    pub fn pressed(&self) -> [u8; MAX_PLAYERS] {
+        self.mmu.joypad.pressed()
+    }
+
+    /// Hold exactly the buttons of the given masks on every joypad
+    pub fn set_pressed(&mut self, pressed: [u8; MAX_PLAYERS]) {
+        if pressed != self.mmu.joypad.pressed() {
+            self.mmu.joypad.set_pressed(pressed);
+            self.record_input();
+        }
+    }
+
+    /// Return the CRC-32 of the ROM image
+    pub fn rom_checksum(&self) -> u32 {
+        self.mmu.rom_checksum()
+    }
+
+    /// Return the RAM of the cartridge, as it would be saved by its battery
+    pub fn save_ram(&self) -> &[u8] {
+        self.mmu.save_ram()
+    }
+
+    /// Restore the RAM of the cartridge from a battery save, before running
+    pub fn load_save_ram(&mut self, data: &[u8]) {
+        self.mmu.load_save_ram(data);
+    }
+
+    /// Set the real time clock of the cartridge, for those that have one, to
+    /// the given number of seconds, before running
+    pub fn set_rtc_seconds(&mut self, seconds: u64) {
+        self.mmu.set_rtc_seconds(seconds);
+    }
+
+    /// Send the audio to the given sink, at the given sample rate
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>, sample_rate: u32) {
+        self.mmu.apu.set_sink(sink, sample_rate);
+    }
+
+    /// Flush the audio sink once the emulation is over
+    pub fn finish_audio(&mut self) -> Result<(), io::Error> {
+        self.mmu.apu.finish_sink()
+    }
+
+    /// Return the last complete frame as RGB555 colors
+    pub fn framebuffer(&self) -> &[u16] {
+        self.mmu.ppu.framebuffer()
+    }
+
+    /// Return the dots elapsed since power on, which measure real time
+    pub fn dots(&self) -> u64 {
+        self.mmu.dots()
+    }
+
+    /// Read a byte of the memory map as the CPU would see it, without
+    /// ticking the hardware, for debuggers and tests
+    pub fn peek(&self, address: u16) -> u8 {
+        self.mmu.read_byte_at(address)
+    }
+
+    /// Plug a device into the link port, or unplug it with `None`
+    pub fn set_serial_device(&mut self, device: Option<Box<dyn SerialDevice>>) {
+        self.mmu.serial.set_device(device);
+    }
+
+    /// Point the infrared port at a device, or at nothing with `None`
+    pub fn set_infrared_device(&mut self, device: Option<Box<dyn InfraredDevice>>) {
+        self.mmu.infrared.set_device(device);
+    }
+
+    /// Log every byte exchanged over the link port from now on, or stop
+    /// logging with `None`
+    pub fn set_serial_log(&mut self, log: Option<SerialLog>) {
+        self.mmu.serial.set_log(log);
+    }
+
+    pub fn take_serial_log(&mut self) -> Option<SerialLog> {
+        self.mmu.serial.take_log()
+    }
+
+    /// Start logging the writes to the APU registers
+    pub fn start_vgm_log(&mut self) {
+        self.mmu.apu.start_vgm_log();
+    }
+
+    /// Stop logging the writes to the APU registers, returning the log
+    pub fn take_vgm_log(&mut self) -> Option<VgmLog> {
+        self.mmu.apu.take_vgm_log()
+    }
+
+    pub fn set_channel_muted(&mut self, channel: Channel, muted: bool) {
+        self.mmu.apu.set_muted(channel, muted);
+    }
+
+    pub fn set_channel_soloed(&mut self, channel: Channel, soloed: bool) {
+        self.mmu.apu.set_soloed(channel, soloed);
+    }
+
     /// Read the next byte at the position of the PC register,
     /// and advance the PC register
     pub fn fetch_byte(&mut self) -> u8 {
-        let byte = self.mmu.read_byte_at(self.reg.pc);
-        self.reg.pc += 1;
+        let byte = self.read_byte(self.reg.pc);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.reg.pc = self.reg.pc.wrapping_add(1);
+        }
         byte
     }
 
     /// Read the next two bytes at the position of the PC register,
     /// the first one being the LSB, and advance the PC register
     pub fn fetch_word(&mut self) -> u16 {
-        let byte1 = self.mmu.read_byte_at(self.reg.pc);
-        let byte2 = self.mmu.read_byte_at(self.reg.pc + 1);
-        self.reg.pc += 2;
+        let byte1 = self.read_byte(self.reg.pc);
+        let byte2 = self.read_byte(self.reg.pc.wrapping_add(1));
+        self.reg.pc = self.reg.pc.wrapping_add(2);
         (byte1 as u16) | ((byte2 as u16) << 8)
     }
 
-    pub fn run (&mut self) {
-        let mut cycles = 0;
-        cycles += self.step();
+    /// Read a byte from memory, which takes one machine cycle during which
+    /// the rest of the hardware keeps running
+    fn read_byte(&mut self, address: u16) -> u8 {
+        self.tick(4);
+        self.mmu.read_byte_at(address)
+    }
+
+    /// Write a byte to memory, which takes one machine cycle during which
+    /// the rest of the hardware keeps running
+    fn write_byte(&mut self, address: u16, value: u8) {
+        self.tick(4);
+        self.mmu.write_byte_at(address, value);
+        self.stall();
+    }
+
+    /// Advance the rest of the hardware by the given number of cycles
+    fn tick(&mut self, cycles: u32) {
+        self.access_cycles += cycles;
+        self.mmu.tick(cycles);
+        self.stall();
+    }
+
+    /// Keep the hardware running for as long as DMA transfers halt the CPU
+    fn stall(&mut self) {
+        loop {
+            let cycles = self.mmu.take_stall_cycles();
+            if cycles == 0 {
+                break;
+            }
+            self.stalled_cycles += cycles;
+            self.mmu.tick(cycles);
+        }
+    }
+
+    pub fn run(&mut self) {
+        loop {
+            self.step();
+        }
+    }
+
+    /// Run for the given number of frames worth of real time, whether the
+    /// LCD is on or not
+    pub fn run_frames(&mut self, frames: u32) {
+        let end = self.mmu.dots() + frames as u64 * DOTS_PER_FRAME as u64;
+        while self.mmu.dots() < end {
+            self.step();
+        }
+    }
+
+    /// Execute the next instruction, returning the number of CPU cycles it
+    /// took, which only match dots of real time when not at double speed.
+    /// The hardware is ticked on every memory access, so that the accesses
+    /// see it in the state it would be in at that point of the instruction,
+    /// and then for whatever internal cycles the instruction has left.
+    pub fn step(&mut self) -> u32 {
+        let frame_dots = DOTS_PER_FRAME as u64;
+        if self.rewind.as_ref().is_some_and(|rewind| rewind.is_due(self.mmu.dots(), frame_dots)) {
+            let state = self.save_state();
+            if let Some(rewind) = self.rewind.as_mut() {
+                rewind.push(self.mmu.dots(), state);
+            }
+        }
+        self.access_cycles = 0;
+        self.stalled_cycles = 0;
+        if self.locked {
+            // Not even interrupts get the CPU going again, while the rest of
+            // the hardware keeps running
+            self.tick(4);
+            return 4 + self.stalled_cycles;
+        }
+        if self.stopped {
+            if !self.mmu.joypad.any_line_low() {
+                self.tick(4);
+                return 4;
+            }
+            // Pressing a button of a selected group wakes the CPU up
+            self.stopped = false;
+        }
+        if self.halted {
+            if self.mmu.pending_interrupts() == 0 {
+                self.tick(4);
+                return 4 + self.stalled_cycles;
+            }
+            // A pending interrupt wakes the CPU up even with IME off
+            self.halted = false;
+        }
+        if self.ime && self.mmu.pending_interrupts() != 0 {
+            self.service_interrupt();
+            return self.access_cycles + self.stalled_cycles;
+        }
+        let enable_interrupts = self.ime_scheduled;
+        let cycles = self.execute();
+        // EI takes effect after the instruction that follows it, unless that
+        // one is DI
+        if enable_interrupts && self.ime_scheduled {
+            self.ime = true;
+            self.ime_scheduled = false;
+        }
+        let access_cycles = self.access_cycles;
+        if cycles > access_cycles {
+            self.tick(cycles - access_cycles);
+        }
+        cycles.max(access_cycles) + self.stalled_cycles
     }
 
     /// Read the next opcode from memory and execute it,
     /// returning the number of cycles that the instruction cost
-    fn step(&mut self) -> u32 {
+    fn execute(&mut self) -> u32 {
         let opcode = self.fetch_byte();
         match opcode {
             // LD nn,n
@@ -98,25 +590,25 @@ impl Cpu {
             0x6C => { self.reg.l = self.reg.h; 4 },
             0x6D => { 4 },
             // LD r, (HR)
-            0x7E => { self.reg.a = self.mmu.read_byte_at(self.reg.hl()); 8 },
-            0x46 => { self.reg.b = self.mmu.read_byte_at(self.reg.hl()); 8 },
-            0x4E => { self.reg.c = self.mmu.read_byte_at(self.reg.hl()); 8 },
-            0x56 => { self.reg.d = self.mmu.read_byte_at(self.reg.hl()); 8 },
-            0x5E => { self.reg.d = self.mmu.read_byte_at(self.reg.hl()); 8 },
-            0x66 => { self.reg.h = self.mmu.read_byte_at(self.reg.hl()); 8 },
-            0x6E => { self.reg.l = self.mmu.read_byte_at(self.reg.hl()); 8 },
+            0x7E => { self.reg.a = self.read_byte(self.reg.hl()); 8 },
+            0x46 => { self.reg.b = self.read_byte(self.reg.hl()); 8 },
+            0x4E => { self.reg.c = self.read_byte(self.reg.hl()); 8 },
+            0x56 => { self.reg.d = self.read_byte(self.reg.hl()); 8 },
+            0x5E => { self.reg.e = self.read_byte(self.reg.hl()); 8 },
+            0x66 => { self.reg.h = self.read_byte(self.reg.hl()); 8 },
+            0x6E => { self.reg.l = self.read_byte(self.reg.hl()); 8 },
             // LD (HL), r
-            0x70 => { self.mmu.write_byte_at(self.reg.hl(), self.reg.b); 8 },
-            0x71 => { self.mmu.write_byte_at(self.reg.hl(), self.reg.c); 8 },
-            0x72 => { self.mmu.write_byte_at(self.reg.hl(), self.reg.d); 8 },
-            0x73 => { self.mmu.write_byte_at(self.reg.hl(), self.reg.e); 8 },
-            0x74 => { self.mmu.write_byte_at(self.reg.hl(), self.reg.h); 8 },
-            0x75 => { self.mmu.write_byte_at(self.reg.hl(), self.reg.l); 8 },
-            0x36 => { let v = self.fetch_byte(); self.mmu.write_byte_at(self.reg.hl(), v); 12 },
+            0x70 => { self.write_byte(self.reg.hl(), self.reg.b); 8 },
+            0x71 => { self.write_byte(self.reg.hl(), self.reg.c); 8 },
+            0x72 => { self.write_byte(self.reg.hl(), self.reg.d); 8 },
+            0x73 => { self.write_byte(self.reg.hl(), self.reg.e); 8 },
+            0x74 => { self.write_byte(self.reg.hl(), self.reg.h); 8 },
+            0x75 => { self.write_byte(self.reg.hl(), self.reg.l); 8 },
+            0x36 => { let v = self.fetch_byte(); self.write_byte(self.reg.hl(), v); 12 },
             // LD A,n
-            0x0A => { self.reg.a = self.mmu.read_byte_at(self.reg.bc()); 8 },
-            0x1A => { self.reg.a = self.mmu.read_byte_at(self.reg.de()); 8 },
-            0xFA => { let addr = self.fetch_word(); self.reg.a = self.mmu.read_byte_at(addr); 16 },
+            0x0A => { self.reg.a = self.read_byte(self.reg.bc()); 8 },
+            0x1A => { self.reg.a = self.read_byte(self.reg.de()); 8 },
+            0xFA => { let addr = self.fetch_word(); self.reg.a = self.read_byte(addr); 16 },
             0x3E => { self.reg.a = self.fetch_byte(); 8 },
             // LD n,A
             0x47 => { self.reg.b = self.reg.a; 4 },
@@ -125,35 +617,35 @@ impl Cpu {
             0x5F => { self.reg.e = self.reg.a; 4 },
             0x67 => { self.reg.h = self.reg.a; 4 },
             0x6F => { self.reg.l = self.reg.a; 4 },
-            0x02 => { self.mmu.write_byte_at(self.reg.bc(), self.reg.a); 8 },
-            0x12 => { self.mmu.write_byte_at(self.reg.de(), self.reg.a); 8 },
-            0x77 => { self.mmu.write_byte_at(self.reg.hl(), self.reg.a); 8 },
-            0xEA => { let addr = self.fetch_word(); self.mmu.write_byte_at(addr, self.reg.a); 16 },
+            0x02 => { self.write_byte(self.reg.bc(), self.reg.a); 8 },
+            0x12 => { self.write_byte(self.reg.de(), self.reg.a); 8 },
+            0x77 => { self.write_byte(self.reg.hl(), self.reg.a); 8 },
+            0xEA => { let addr = self.fetch_word(); self.write_byte(addr, self.reg.a); 16 },
             // LD A,(C)
-            0xF2 => { self.reg.a = self.mmu.read_byte_at(0xFF00 | self.reg.c as u16); 8 },
+            0xF2 => { self.reg.a = self.read_byte(0xFF00 | self.reg.c as u16); 8 },
             // LD (C),A
-            0xE2 => { self.mmu.write_byte_at(0xFF00 | self.reg.c as u16, self.reg.a); 8 }
+            0xE2 => { self.write_byte(0xFF00 | self.reg.c as u16, self.reg.a); 8 }
             // LD A,(HLD)
-            0x3A => { let hl = self.reg.hl(); self.reg.a = self.mmu.read_byte_at(hl); self.reg.set_hl(hl - 1); 8 },
+            0x3A => { let hl = self.reg.hl(); self.reg.a = self.read_byte(hl); self.reg.set_hl(hl.wrapping_sub(1)); 8 },
             // LD (HLD),A
-            0x32 => { let hl = self.reg.hl(); self.mmu.write_byte_at(hl, self.reg.a); self.reg.set_hl(hl - 1); 8 },
+            0x32 => { let hl = self.reg.hl(); self.write_byte(hl, self.reg.a); self.reg.set_hl(hl.wrapping_sub(1)); 8 },
             // LD A,(HLI)
-            0x2A => { let hl = self.reg.hl(); self.reg.a = self.mmu.read_byte_at(hl); self.reg.set_hl(hl + 1); 8 },
+            0x2A => { let hl = self.reg.hl(); self.reg.a = self.read_byte(hl); self.reg.set_hl(hl.wrapping_add(1)); 8 },
             // LD (HLI),A
-            0x22 => { let hl = self.reg.hl(); self.mmu.write_byte_at(hl, self.reg.a); self.reg.set_hl(hl + 1); 8 },
+            0x22 => { let hl = self.reg.hl(); self.write_byte(hl, self.reg.a); self.reg.set_hl(hl.wrapping_add(1)); 8 },
             // LDH (n),A
-            0xE0 => { let addr = 0xFF00 | self.fetch_byte() as u16; self.mmu.write_byte_at(addr, self.reg.a); 12 },
+            0xE0 => { let addr = 0xFF00 | self.fetch_byte() as u16; self.write_byte(addr, self.reg.a); 12 },
             // LDH A,(n)
-            0xF0 => { let addr = 0xFF00 | self.fetch_byte() as u16; self.reg.a = self.mmu.read_byte_at(addr); 12 },
+            0xF0 => { let addr = 0xFF00 | self.fetch_byte() as u16; self.reg.a = self.read_byte(addr); 12 },
             // LD n,nn
             0x01 => { let v = self.fetch_word(); self.reg.set_bc(v); 12 },
             0x11 => { let v = self.fetch_word(); self.reg.set_de(v); 12 },
             0x21 => { let v = self.fetch_word(); self.reg.set_hl(v); 12 },
             0x31 => { self.reg.sp = self.fetch_word(); 12 },
             // LD SP,HL
-            0xF9 => { self.reg.sp = self.reg.hl(); 12 },
+            0xF9 => { self.reg.sp = self.reg.hl(); 8 },
             // LD (nn),SP
-            0x08 => { let addr = self.fetch_word(); self.mmu.write_byte_at(addr, lsb(self.reg.sp)); self.mmu.write_byte_at(addr + 1, msb(self.reg.sp)); 20 },
+            0x08 => { let addr = self.fetch_word(); self.write_byte(addr, lsb(self.reg.sp)); self.write_byte(addr.wrapping_add(1), msb(self.reg.sp)); 20 },
             // PUSH nn
             0xF5 => { self.push(self.reg.af()); 16 },
             0xC5 => { self.push(self.reg.bc()); 16 },
@@ -172,7 +664,7 @@ impl Cpu {
             0x83 => { self.add(self.reg.e); 4 },
             0x84 => { self.add(self.reg.h); 4 },
             0x85 => { self.add(self.reg.l); 4 },
-            0x86 => { self.add(self.mmu.read_byte_at(self.reg.hl())); 8 },
+            0x86 => { let v = self.read_byte(self.reg.hl()); self.add(v); 8 },
             0xC6 => { let v = self.fetch_byte(); self.add(v); 8 },
             // ADC A,n
             0x8F => { self.adc(self.reg.a); 4 },
@@ -182,7 +674,7 @@ impl Cpu {
             0x8B => { self.adc(self.reg.e); 4 },
             0x8C => { self.adc(self.reg.h); 4 },
             0x8D => { self.adc(self.reg.l); 4 },
-            0x8E => { self.adc(self.mmu.read_byte_at(self.reg.hl())); 8 },
+            0x8E => { let v = self.read_byte(self.reg.hl()); self.adc(v); 8 },
             0xCE => { let v = self.fetch_byte(); self.adc(v); 8 },
             // SUB n
             0x97 => { self.sub(self.reg.a); 4 },
@@ -192,7 +684,7 @@ impl Cpu {
             0x93 => { self.sub(self.reg.e); 4 },
             0x94 => { self.sub(self.reg.h); 4 },
             0x95 => { self.sub(self.reg.l); 4 },
-            0x96 => { self.sub(self.mmu.read_byte_at(self.reg.hl())); 8 },
+            0x96 => { let v = self.read_byte(self.reg.hl()); self.sub(v); 8 },
             0xD6 => { let v = self.fetch_byte(); self.sub(v); 8 },
             // SBC A,n
             0x9F => { self.sbc(self.reg.a); 4 },
@@ -202,7 +694,8 @@ impl Cpu {
             0x9B => { self.sbc(self.reg.e); 4 },
             0x9C => { self.sbc(self.reg.h); 4 },
             0x9D => { self.sbc(self.reg.l); 4 },
-            0x9E => { self.sbc(self.mmu.read_byte_at(self.reg.hl())); 8 },
+            0x9E => { let v = self.read_byte(self.reg.hl()); self.sbc(v); 8 },
+            0xDE => { let v = self.fetch_byte(); self.sbc(v); 8 },
             // AND n
             0xA7 => { self.and(self.reg.a); 4 },
             0xA0 => { self.and(self.reg.b); 4 },
@@ -211,7 +704,7 @@ impl Cpu {
             0xA3 => { self.and(self.reg.e); 4 },
             0xA4 => { self.and(self.reg.h); 4 },
             0xA5 => { self.and(self.reg.l); 4 },
-            0xA6 => { self.and(self.mmu.read_byte_at(self.reg.hl())); 8 },
+            0xA6 => { let v = self.read_byte(self.reg.hl()); self.and(v); 8 },
             0xE6 => { let v = self.fetch_byte(); self.and(v); 8 },
             // OR n
             0xB7 => { self.or(self.reg.a); 4 },
@@ -221,7 +714,7 @@ impl Cpu {
             0xB3 => { self.or(self.reg.e); 4 },
             0xB4 => { self.or(self.reg.h); 4 },
             0xB5 => { self.or(self.reg.l); 4 },
-            0xB6 => { self.or(self.mmu.read_byte_at(self.reg.hl())); 8 },
+            0xB6 => { let v = self.read_byte(self.reg.hl()); self.or(v); 8 },
             0xF6 => { let v = self.fetch_byte(); self.or(v); 8 },
             // XOR n
             0xAF => { self.xor(self.reg.a); 4 },
@@ -231,7 +724,7 @@ impl Cpu {
             0xAB => { self.xor(self.reg.e); 4 },
             0xAC => { self.xor(self.reg.h); 4 },
             0xAD => { self.xor(self.reg.l); 4 },
-            0xAE => { self.xor(self.mmu.read_byte_at(self.reg.hl())); 8 },
+            0xAE => { let v = self.read_byte(self.reg.hl()); self.xor(v); 8 },
             0xEE => { let v = self.fetch_byte(); self.xor(v); 8 },
             // CP n
             0xBF => { self.cp(self.reg.a); 4 },
@@ -241,7 +734,7 @@ impl Cpu {
             0xBB => { self.cp(self.reg.e); 4 },
             0xBC => { self.cp(self.reg.h); 4 },
             0xBD => { self.cp(self.reg.l); 4 },
-            0xBE => { self.cp(self.mmu.read_byte_at(self.reg.hl())); 8 },
+            0xBE => { let v = self.read_byte(self.reg.hl()); self.cp(v); 8 },
             0xFE => { let v = self.fetch_byte(); self.cp(v); 8 },
             // INC n
             0x3C => { self.reg.a = self.inc(self.reg.a); 4 },
@@ -251,7 +744,7 @@ impl Cpu {
             0x1C => { self.reg.e = self.inc(self.reg.e); 4 },
             0x24 => { self.reg.h = self.inc(self.reg.h); 4 },
             0x2C => { self.reg.l = self.inc(self.reg.l); 4 },
-            0x34 => { let hl = self.reg.hl(); let v = self.inc(self.mmu.read_byte_at(hl)); self.mmu.write_byte_at(hl, v); 12 },
+            0x34 => { let hl = self.reg.hl(); let v = self.read_byte(hl); let v = self.inc(v); self.write_byte(hl, v); 12 },
             // DEC n
             0x3D => { self.reg.a = self.dec(self.reg.a); 4 },
             0x05 => { self.reg.b = self.dec(self.reg.b); 4 },
@@ -260,7 +753,28 @@ impl Cpu {
             0x1D => { self.reg.e = self.dec(self.reg.e); 4 },
             0x25 => { self.reg.h = self.dec(self.reg.h); 4 },
             0x2D => { self.reg.l = self.dec(self.reg.l); 4 },
-            0x35 => { let hl = self.reg.hl(); let v = self.dec(self.mmu.read_byte_at(hl)); self.mmu.write_byte_at(hl, v); 12 },
+            0x35 => { let hl = self.reg.hl(); let v = self.read_byte(hl); let v = self.dec(v); self.write_byte(hl, v); 12 },
+            // INC nn
+            0x03 => { self.reg.set_bc(self.reg.bc().wrapping_add(1)); 8 },
+            0x13 => { self.reg.set_de(self.reg.de().wrapping_add(1)); 8 },
+            0x23 => { self.reg.set_hl(self.reg.hl().wrapping_add(1)); 8 },
+            0x33 => { self.reg.sp = self.reg.sp.wrapping_add(1); 8 },
+            // DEC nn
+            0x0B => { self.reg.set_bc(self.reg.bc().wrapping_sub(1)); 8 },
+            0x1B => { self.reg.set_de(self.reg.de().wrapping_sub(1)); 8 },
+            0x2B => { self.reg.set_hl(self.reg.hl().wrapping_sub(1)); 8 },
+            0x3B => { self.reg.sp = self.reg.sp.wrapping_sub(1); 8 },
+            // ADD HL,n
+            0x09 => { self.add_hl(self.reg.bc()); 8 },
+            0x19 => { self.add_hl(self.reg.de()); 8 },
+            0x29 => { self.add_hl(self.reg.hl()); 8 },
+            0x39 => { self.add_hl(self.reg.sp); 8 },
+            // ADD SP,n
+            0xE8 => { let v = self.fetch_byte(); self.reg.sp = self.add_sp(v); 16 },
+            // LD HL,SP+n
+            0xF8 => { let v = self.fetch_byte(); let hl = self.add_sp(v); self.reg.set_hl(hl); 12 },
+            // DAA
+            0x27 => { self.daa(); 4 },
             // CB
             0xCB => { self.cb() },
             // CPL
@@ -271,24 +785,54 @@ impl Cpu {
             0x37 => { self.scf(); 4 },
             // NOP
             0x00 => { 4 },
+            // STOP
+            0x10 => { self.fetch_byte(); self.stop(); 4 },
             // Rotates (RLCA, RLA, RRCA, RRA)
-            0x07 => { self.reg.a = self.rlc(self.reg.a); 4 },
-            0x17 => { self.reg.a = self.rl(self.reg.a); 4 },
-            0x0F => { self.reg.a = self.rrc(self.reg.a); 4 },
-            0x1F => { self.reg.a = self.rr(self.reg.a); 4 },
+            0x07 => { self.reg.a = self.rlc(self.reg.a); self.reg.set_flag(Z, false); 4 },
+            0x17 => { self.reg.a = self.rl(self.reg.a); self.reg.set_flag(Z, false); 4 },
+            0x0F => { self.reg.a = self.rrc(self.reg.a); self.reg.set_flag(Z, false); 4 },
+            0x1F => { self.reg.a = self.rr(self.reg.a); self.reg.set_flag(Z, false); 4 },
             // Jumps
-            0xC3 => { self.reg.pc = self.fetch_word(); 12 },
-            0xC2 => { let addr = self.fetch_word(); self.jump_conditional(addr, Z, false); 12 },
-            0xCA => { let addr = self.fetch_word(); self.jump_conditional(addr, Z, true); 12 },
-            0xD2 => { let addr = self.fetch_word(); self.jump_conditional(addr, C, false); 12 },
-            0xDA => { let addr = self.fetch_word(); self.jump_conditional(addr, C, true); 12 },
+            0xC3 => { self.reg.pc = self.fetch_word(); 16 },
+            0xC2 => { let addr = self.fetch_word(); self.jump_conditional(addr, Z, false) },
+            0xCA => { let addr = self.fetch_word(); self.jump_conditional(addr, Z, true) },
+            0xD2 => { let addr = self.fetch_word(); self.jump_conditional(addr, C, false) },
+            0xDA => { let addr = self.fetch_word(); self.jump_conditional(addr, C, true) },
             0xE9 => { self.reg.pc = self.reg.hl(); 4 },
-            0x18 => { self.reg.pc = self.reg.pc + (self.fetch_byte() as u16); 8 },
-            0x20 => { let addr = self.fetch_byte() as u16; self.jump_conditional(addr, Z, false); 8 },
-            0x28 => { let addr = self.fetch_byte() as u16; self.jump_conditional(addr, Z, true); 8 },
-            0x30 => { let addr = self.fetch_byte() as u16; self.jump_conditional(addr, C, false); 8 },
-            0x38 => { let addr = self.fetch_byte() as u16; self.jump_conditional(addr, C, true); 8 },
-            _ => panic!("Unknown opcode {:x} found at address {:x}", opcode, self.reg.pc),
+            0x18 => { let offset = self.fetch_byte() as i8; self.jump_relative(offset); 12 },
+            0x20 => { let offset = self.fetch_byte() as i8; self.jump_relative_conditional(offset, Z, false) },
+            0x28 => { let offset = self.fetch_byte() as i8; self.jump_relative_conditional(offset, Z, true) },
+            0x30 => { let offset = self.fetch_byte() as i8; self.jump_relative_conditional(offset, C, false) },
+            0x38 => { let offset = self.fetch_byte() as i8; self.jump_relative_conditional(offset, C, true) },
+            // Calls
+            0xCD => { let addr = self.fetch_word(); self.call(addr); 24 },
+            0xC4 => { let addr = self.fetch_word(); self.call_conditional(addr, Z, false) },
+            0xCC => { let addr = self.fetch_word(); self.call_conditional(addr, Z, true) },
+            0xD4 => { let addr = self.fetch_word(); self.call_conditional(addr, C, false) },
+            0xDC => { let addr = self.fetch_word(); self.call_conditional(addr, C, true) },
+            // Restarts
+            0xC7 => { self.call(0x00); 16 },
+            0xCF => { self.call(0x08); 16 },
+            0xD7 => { self.call(0x10); 16 },
+            0xDF => { self.call(0x18); 16 },
+            0xE7 => { self.call(0x20); 16 },
+            0xEF => { self.call(0x28); 16 },
+            0xF7 => { self.call(0x30); 16 },
+            0xFF => { self.call(0x38); 16 },
+            // Returns
+            0xC9 => { self.reg.pc = self.pop(); 16 },
+            0xC0 => { self.return_conditional(Z, false) },
+            0xC8 => { self.return_conditional(Z, true) },
+            0xD0 => { self.return_conditional(C, false) },
+            0xD8 => { self.return_conditional(C, true) },
+            0xD9 => { self.reg.pc = self.pop(); self.ime = true; 16 },
+            // DI, EI
+            0xF3 => { self.ime = false; self.ime_scheduled = false; 4 },
+            0xFB => { self.ime_scheduled = true; 4 },
+            // HALT
+            0x76 => { self.halt(); 4 },
+            // Illegal opcodes, which lock the CPU up
+            0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => { self.locked = true; 4 },
         }
     }
 
@@ -297,14 +841,14 @@ impl Cpu {
         let opcode = self.fetch_byte();
         match opcode {
             // SWAP n
-            0x37 => { self.swap(self.reg.a); 8 },
-            0x30 => { self.swap(self.reg.b); 8 },
-            0x31 => { self.swap(self.reg.c); 8 },
-            0x32 => { self.swap(self.reg.d); 8 },
-            0x33 => { self.swap(self.reg.e); 8 },
-            0x34 => { self.swap(self.reg.h); 8 },
-            0x35 => { self.swap(self.reg.l); 8 },
-            0x36 => { let hl = self.reg.hl(); let v = self.swap(self.mmu.read_byte_at(hl)); self.mmu.write_byte_at(hl, v); 16 },
+            0x37 => { self.reg.a = self.swap(self.reg.a); 8 },
+            0x30 => { self.reg.b = self.swap(self.reg.b); 8 },
+            0x31 => { self.reg.c = self.swap(self.reg.c); 8 },
+            0x32 => { self.reg.d = self.swap(self.reg.d); 8 },
+            0x33 => { self.reg.e = self.swap(self.reg.e); 8 },
+            0x34 => { self.reg.h = self.swap(self.reg.h); 8 },
+            0x35 => { self.reg.l = self.swap(self.reg.l); 8 },
+            0x36 => { let hl = self.reg.hl(); let v = self.read_byte(hl); let v = self.swap(v); self.write_byte(hl, v); 16 },
             // RLC n
             0x07 => { self.reg.a = self.rlc(self.reg.a); 8 },
             0x00 => { self.reg.b = self.rlc(self.reg.b); 8 },
@@ -313,7 +857,7 @@ impl Cpu {
             0x03 => { self.reg.e = self.rlc(self.reg.e); 8 },
             0x04 => { self.reg.h = self.rlc(self.reg.h); 8 },
             0x05 => { self.reg.l = self.rlc(self.reg.l); 8 },
-            0x06 => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.rlc(v); self.mmu.write_byte_at(hl, v); 16 },
+            0x06 => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.rlc(v); self.write_byte(hl, v); 16 },
             // RL n
             0x17 => { self.reg.a = self.rl(self.reg.a); 8 },
             0x10 => { self.reg.b = self.rl(self.reg.b); 8 },
@@ -322,7 +866,7 @@ impl Cpu {
             0x13 => { self.reg.e = self.rl(self.reg.e); 8 },
             0x14 => { self.reg.h = self.rl(self.reg.h); 8 },
             0x15 => { self.reg.l = self.rl(self.reg.l); 8 },
-            0x16 => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.rl(v); self.mmu.write_byte_at(hl, v); 16 },
+            0x16 => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.rl(v); self.write_byte(hl, v); 16 },
             // RRC n
             0x0F => { self.reg.a = self.rrc(self.reg.a); 8 },
             0x08 => { self.reg.b = self.rrc(self.reg.b); 8 },
@@ -331,7 +875,7 @@ impl Cpu {
             0x0B => { self.reg.e = self.rrc(self.reg.e); 8 },
             0x0C => { self.reg.h = self.rrc(self.reg.h); 8 },
             0x0D => { self.reg.l = self.rrc(self.reg.l); 8 },
-            0x0E => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.rrc(v); self.mmu.write_byte_at(hl, v); 16 },
+            0x0E => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.rrc(v); self.write_byte(hl, v); 16 },
             // RR n
             0x1F => { self.reg.a = self.rr(self.reg.a); 8 },
             0x18 => { self.reg.b = self.rr(self.reg.b); 8 },
@@ -340,7 +884,7 @@ impl Cpu {
             0x1B => { self.reg.e = self.rr(self.reg.e); 8 },
             0x1C => { self.reg.h = self.rr(self.reg.h); 8 },
             0x1D => { self.reg.l = self.rr(self.reg.l); 8 },
-            0x1E => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.rr(v); self.mmu.write_byte_at(hl, v); 16 },
+            0x1E => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.rr(v); self.write_byte(hl, v); 16 },
             // SLA n
             0x27 => { self.reg.a = self.sla(self.reg.a); 8 },
             0x20 => { self.reg.b = self.sla(self.reg.b); 8 },
@@ -349,7 +893,7 @@ impl Cpu {
             0x23 => { self.reg.e = self.sla(self.reg.e); 8 },
             0x24 => { self.reg.h = self.sla(self.reg.h); 8 },
             0x25 => { self.reg.l = self.sla(self.reg.l); 8 },
-            0x26 => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.sla(v); self.mmu.write_byte_at(hl, v); 16 },
+            0x26 => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.sla(v); self.write_byte(hl, v); 16 },
             // SRA n
             0x2F => { self.reg.a = self.sra(self.reg.a); 8 },
             0x28 => { self.reg.b = self.sra(self.reg.b); 8 },
@@ -358,7 +902,7 @@ impl Cpu {
             0x2B => { self.reg.e = self.sra(self.reg.e); 8 },
             0x2C => { self.reg.h = self.sra(self.reg.h); 8 },
             0x2D => { self.reg.l = self.sra(self.reg.l); 8 },
-            0x2E => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.sra(v); self.mmu.write_byte_at(hl, v); 16 },
+            0x2E => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.sra(v); self.write_byte(hl, v); 16 },
             // SRL n
             0x3F => { self.reg.a = self.srl(self.reg.a); 8 },
             0x38 => { self.reg.b = self.srl(self.reg.b); 8 },
@@ -367,7 +911,7 @@ impl Cpu {
             0x3B => { self.reg.e = self.srl(self.reg.e); 8 },
             0x3C => { self.reg.h = self.srl(self.reg.h); 8 },
             0x3D => { self.reg.l = self.srl(self.reg.l); 8 },
-            0x3E => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.srl(v); self.mmu.write_byte_at(hl, v); 16 },
+            0x3E => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.srl(v); self.write_byte(hl, v); 16 },
             // BIT b,r
             0x40 => { self.bit(self.reg.b, 0); 8 },
             0x41 => { self.bit(self.reg.c, 0); 8 },
@@ -375,7 +919,7 @@ impl Cpu {
             0x43 => { self.bit(self.reg.e, 0); 8 },
             0x44 => { self.bit(self.reg.h, 0); 8 },
             0x45 => { self.bit(self.reg.l, 0); 8 },
-            0x46 => { self.bit(self.mmu.read_byte_at(self.reg.hl()), 0); 16 },
+            0x46 => { let v = self.read_byte(self.reg.hl()); self.bit(v, 0); 12 },
             0x47 => { self.bit(self.reg.a, 0); 8 },
             0x48 => { self.bit(self.reg.b, 1); 8 },
             0x49 => { self.bit(self.reg.c, 1); 8 },
@@ -383,7 +927,7 @@ impl Cpu {
             0x4B => { self.bit(self.reg.e, 1); 8 },
             0x4C => { self.bit(self.reg.h, 1); 8 },
             0x4D => { self.bit(self.reg.l, 1); 8 },
-            0x4E => { self.bit(self.mmu.read_byte_at(self.reg.hl()), 1); 16 },
+            0x4E => { let v = self.read_byte(self.reg.hl()); self.bit(v, 1); 12 },
             0x4F => { self.bit(self.reg.a, 1); 8 },
             0x50 => { self.bit(self.reg.b, 2); 8 },
             0x51 => { self.bit(self.reg.c, 2); 8 },
@@ -391,7 +935,7 @@ impl Cpu {
             0x53 => { self.bit(self.reg.e, 2); 8 },
             0x54 => { self.bit(self.reg.h, 2); 8 },
             0x55 => { self.bit(self.reg.l, 2); 8 },
-            0x56 => { self.bit(self.mmu.read_byte_at(self.reg.hl()), 2); 16 },
+            0x56 => { let v = self.read_byte(self.reg.hl()); self.bit(v, 2); 12 },
             0x57 => { self.bit(self.reg.a, 2); 8 },
             0x58 => { self.bit(self.reg.b, 3); 8 },
             0x59 => { self.bit(self.reg.c, 3); 8 },
@@ -399,7 +943,7 @@ impl Cpu {
             0x5B => { self.bit(self.reg.e, 3); 8 },
             0x5C => { self.bit(self.reg.h, 3); 8 },
             0x5D => { self.bit(self.reg.l, 3); 8 },
-            0x5E => { self.bit(self.mmu.read_byte_at(self.reg.hl()), 3); 16 },
+            0x5E => { let v = self.read_byte(self.reg.hl()); self.bit(v, 3); 12 },
             0x5F => { self.bit(self.reg.a, 3); 8 },
             0x60 => { self.bit(self.reg.b, 4); 8 },
             0x61 => { self.bit(self.reg.c, 4); 8 },
@@ -407,7 +951,7 @@ impl Cpu {
             0x63 => { self.bit(self.reg.e, 4); 8 },
             0x64 => { self.bit(self.reg.h, 4); 8 },
             0x65 => { self.bit(self.reg.l, 4); 8 },
-            0x66 => { self.bit(self.mmu.read_byte_at(self.reg.hl()), 4); 16 },
+            0x66 => { let v = self.read_byte(self.reg.hl()); self.bit(v, 4); 12 },
             0x67 => { self.bit(self.reg.a, 4); 8 },
             0x68 => { self.bit(self.reg.b, 5); 8 },
             0x69 => { self.bit(self.reg.c, 5); 8 },
@@ -415,7 +959,7 @@ impl Cpu {
             0x6B => { self.bit(self.reg.e, 5); 8 },
             0x6C => { self.bit(self.reg.h, 5); 8 },
             0x6D => { self.bit(self.reg.l, 5); 8 },
-            0x6E => { self.bit(self.mmu.read_byte_at(self.reg.hl()), 5); 16 },
+            0x6E => { let v = self.read_byte(self.reg.hl()); self.bit(v, 5); 12 },
             0x6F => { self.bit(self.reg.a, 5); 8 },
             0x70 => { self.bit(self.reg.b, 6); 8 },
             0x71 => { self.bit(self.reg.c, 6); 8 },
@@ -423,7 +967,7 @@ impl Cpu {
             0x73 => { self.bit(self.reg.e, 6); 8 },
             0x74 => { self.bit(self.reg.h, 6); 8 },
             0x75 => { self.bit(self.reg.l, 6); 8 },
-            0x76 => { self.bit(self.mmu.read_byte_at(self.reg.hl()), 6); 16 },
+            0x76 => { let v = self.read_byte(self.reg.hl()); self.bit(v, 6); 12 },
             0x77 => { self.bit(self.reg.a, 6); 8 },
             0x78 => { self.bit(self.reg.b, 7); 8 },
             0x79 => { self.bit(self.reg.c, 7); 8 },
@@ -431,7 +975,7 @@ impl Cpu {
             0x7B => { self.bit(self.reg.e, 7); 8 },
             0x7C => { self.bit(self.reg.h, 7); 8 },
             0x7D => { self.bit(self.reg.l, 7); 8 },
-            0x7E => { self.bit(self.mmu.read_byte_at(self.reg.hl()), 7); 16 },
+            0x7E => { let v = self.read_byte(self.reg.hl()); self.bit(v, 7); 12 },
             0x7F => { self.bit(self.reg.a, 7); 8 },
             // RES b,r
             0x80 => { self.reg.b = self.set(self.reg.b, 0, false); 8 },
@@ -440,15 +984,15 @@ impl Cpu {
             0x83 => { self.reg.e = self.set(self.reg.e, 0, false); 8 },
             0x84 => { self.reg.h = self.set(self.reg.h, 0, false); 8 },
             0x85 => { self.reg.l = self.set(self.reg.l, 0, false); 8 },
-            0x86 => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.set(v, 0, false); self.mmu.write_byte_at(hl, v); 16 },
+            0x86 => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.set(v, 0, false); self.write_byte(hl, v); 16 },
             0x87 => { self.reg.a = self.set(self.reg.a, 0, false); 8 },
-            0x88 => { self.reg.c = self.set(self.reg.b, 1, false); 8 },
-            0x89 => { self.reg.d = self.set(self.reg.c, 1, false); 8 },
-            0x8A => { self.reg.e = self.set(self.reg.d, 1, false); 8 },
-            0x8B => { self.reg.h = self.set(self.reg.e, 1, false); 8 },
-            0x8C => { self.reg.l = self.set(self.reg.h, 1, false); 8 },
-            0x8D => { self.reg.b = self.set(self.reg.l, 1, false); 8 },
-            0x8E => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.set(v, 0, false); self.mmu.write_byte_at(hl, v); 16 },
+            0x88 => { self.reg.b = self.set(self.reg.b, 1, false); 8 },
+            0x89 => { self.reg.c = self.set(self.reg.c, 1, false); 8 },
+            0x8A => { self.reg.d = self.set(self.reg.d, 1, false); 8 },
+            0x8B => { self.reg.e = self.set(self.reg.e, 1, false); 8 },
+            0x8C => { self.reg.h = self.set(self.reg.h, 1, false); 8 },
+            0x8D => { self.reg.l = self.set(self.reg.l, 1, false); 8 },
+            0x8E => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.set(v, 1, false); self.write_byte(hl, v); 16 },
             0x8F => { self.reg.a = self.set(self.reg.a, 1, false); 8 },
             0x90 => { self.reg.b = self.set(self.reg.b, 2, false); 8 },
             0x91 => { self.reg.c = self.set(self.reg.c, 2, false); 8 },
@@ -456,7 +1000,7 @@ impl Cpu {
             0x93 => { self.reg.e = self.set(self.reg.e, 2, false); 8 },
             0x94 => { self.reg.h = self.set(self.reg.h, 2, false); 8 },
             0x95 => { self.reg.l = self.set(self.reg.l, 2, false); 8 },
-            0x96 => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.set(v, 0, false); self.mmu.write_byte_at(hl, v); 16 },
+            0x96 => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.set(v, 2, false); self.write_byte(hl, v); 16 },
             0x97 => { self.reg.a = self.set(self.reg.a, 2, false); 8 },
             0x98 => { self.reg.b = self.set(self.reg.b, 3, false); 8 },
             0x99 => { self.reg.c = self.set(self.reg.c, 3, false); 8 },
@@ -464,7 +1008,7 @@ impl Cpu {
             0x9B => { self.reg.e = self.set(self.reg.e, 3, false); 8 },
             0x9C => { self.reg.h = self.set(self.reg.h, 3, false); 8 },
             0x9D => { self.reg.l = self.set(self.reg.l, 3, false); 8 },
-            0x9E => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.set(v, 0, false); self.mmu.write_byte_at(hl, v); 16 },
+            0x9E => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.set(v, 3, false); self.write_byte(hl, v); 16 },
             0x9F => { self.reg.a = self.set(self.reg.a, 3, false); 8 },
             0xA0 => { self.reg.b = self.set(self.reg.b, 4, false); 8 },
             0xA1 => { self.reg.c = self.set(self.reg.c, 4, false); 8 },
@@ -472,7 +1016,7 @@ impl Cpu {
             0xA3 => { self.reg.e = self.set(self.reg.e, 4, false); 8 },
             0xA4 => { self.reg.h = self.set(self.reg.h, 4, false); 8 },
             0xA5 => { self.reg.l = self.set(self.reg.l, 4, false); 8 },
-            0xA6 => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.set(v, 0, false); self.mmu.write_byte_at(hl, v); 16 },
+            0xA6 => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.set(v, 4, false); self.write_byte(hl, v); 16 },
             0xA7 => { self.reg.a = self.set(self.reg.a, 4, false); 8 },
             0xA8 => { self.reg.b = self.set(self.reg.b, 5, false); 8 },
             0xA9 => { self.reg.c = self.set(self.reg.c, 5, false); 8 },
@@ -480,7 +1024,7 @@ impl Cpu {
             0xAB => { self.reg.e = self.set(self.reg.e, 5, false); 8 },
             0xAC => { self.reg.h = self.set(self.reg.h, 5, false); 8 },
             0xAD => { self.reg.l = self.set(self.reg.l, 5, false); 8 },
-            0xAE => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.set(v, 0, false); self.mmu.write_byte_at(hl, v); 16 },
+            0xAE => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.set(v, 5, false); self.write_byte(hl, v); 16 },
             0xAF => { self.reg.a = self.set(self.reg.a, 5, false); 8 },
             0xB0 => { self.reg.b = self.set(self.reg.b, 6, false); 8 },
             0xB1 => { self.reg.c = self.set(self.reg.c, 6, false); 8 },
@@ -488,7 +1032,7 @@ impl Cpu {
             0xB3 => { self.reg.e = self.set(self.reg.e, 6, false); 8 },
             0xB4 => { self.reg.h = self.set(self.reg.h, 6, false); 8 },
             0xB5 => { self.reg.l = self.set(self.reg.l, 6, false); 8 },
-            0xB6 => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.set(v, 0, false); self.mmu.write_byte_at(hl, v); 16 },
+            0xB6 => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.set(v, 6, false); self.write_byte(hl, v); 16 },
             0xB7 => { self.reg.a = self.set(self.reg.a, 6, false); 8 },
             0xB8 => { self.reg.b = self.set(self.reg.b, 7, false); 8 },
             0xB9 => { self.reg.c = self.set(self.reg.c, 7, false); 8 },
@@ -496,7 +1040,7 @@ impl Cpu {
             0xBB => { self.reg.e = self.set(self.reg.e, 7, false); 8 },
             0xBC => { self.reg.h = self.set(self.reg.h, 7, false); 8 },
             0xBD => { self.reg.l = self.set(self.reg.l, 7, false); 8 },
-            0xBE => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.set(v, 0, false); self.mmu.write_byte_at(hl, v); 16 },
+            0xBE => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.set(v, 7, false); self.write_byte(hl, v); 16 },
             0xBF => { self.reg.a = self.set(self.reg.a, 7, false); 8 },
             // SET b,r
             0xC0 => { self.reg.b = self.set(self.reg.b, 0, true); 8 },
@@ -505,15 +1049,15 @@ impl Cpu {
             0xC3 => { self.reg.e = self.set(self.reg.e, 0, true); 8 },
             0xC4 => { self.reg.h = self.set(self.reg.h, 0, true); 8 },
             0xC5 => { self.reg.l = self.set(self.reg.l, 0, true); 8 },
-            0xC6 => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.set(v, 0, true); self.mmu.write_byte_at(hl, v); 16 },
+            0xC6 => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.set(v, 0, true); self.write_byte(hl, v); 16 },
             0xC7 => { self.reg.a = self.set(self.reg.a, 0, true); 8 },
-            0xC8 => { self.reg.c = self.set(self.reg.b, 1, true); 8 },
-            0xC9 => { self.reg.d = self.set(self.reg.c, 1, true); 8 },
-            0xCA => { self.reg.e = self.set(self.reg.d, 1, true); 8 },
-            0xCB => { self.reg.h = self.set(self.reg.e, 1, true); 8 },
-            0xCC => { self.reg.l = self.set(self.reg.h, 1, true); 8 },
-            0xCD => { self.reg.b = self.set(self.reg.l, 1, true); 8 },
-            0xCE => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.set(v, 0, true); self.mmu.write_byte_at(hl, v); 16 },
+            0xC8 => { self.reg.b = self.set(self.reg.b, 1, true); 8 },
+            0xC9 => { self.reg.c = self.set(self.reg.c, 1, true); 8 },
+            0xCA => { self.reg.d = self.set(self.reg.d, 1, true); 8 },
+            0xCB => { self.reg.e = self.set(self.reg.e, 1, true); 8 },
+            0xCC => { self.reg.h = self.set(self.reg.h, 1, true); 8 },
+            0xCD => { self.reg.l = self.set(self.reg.l, 1, true); 8 },
+            0xCE => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.set(v, 1, true); self.write_byte(hl, v); 16 },
             0xCF => { self.reg.a = self.set(self.reg.a, 1, true); 8 },
             0xD0 => { self.reg.b = self.set(self.reg.b, 2, true); 8 },
             0xD1 => { self.reg.c = self.set(self.reg.c, 2, true); 8 },
@@ -521,7 +1065,7 @@ impl Cpu {
             0xD3 => { self.reg.e = self.set(self.reg.e, 2, true); 8 },
             0xD4 => { self.reg.h = self.set(self.reg.h, 2, true); 8 },
             0xD5 => { self.reg.l = self.set(self.reg.l, 2, true); 8 },
-            0xD6 => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.set(v, 0, true); self.mmu.write_byte_at(hl, v); 16 },
+            0xD6 => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.set(v, 2, true); self.write_byte(hl, v); 16 },
             0xD7 => { self.reg.a = self.set(self.reg.a, 2, true); 8 },
             0xD8 => { self.reg.b = self.set(self.reg.b, 3, true); 8 },
             0xD9 => { self.reg.c = self.set(self.reg.c, 3, true); 8 },
@@ -529,7 +1073,7 @@ impl Cpu {
             0xDB => { self.reg.e = self.set(self.reg.e, 3, true); 8 },
             0xDC => { self.reg.h = self.set(self.reg.h, 3, true); 8 },
             0xDD => { self.reg.l = self.set(self.reg.l, 3, true); 8 },
-            0xDE => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.set(v, 0, true); self.mmu.write_byte_at(hl, v); 16 },
+            0xDE => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.set(v, 3, true); self.write_byte(hl, v); 16 },
             0xDF => { self.reg.a = self.set(self.reg.a, 3, true); 8 },
             0xE0 => { self.reg.b = self.set(self.reg.b, 4, true); 8 },
             0xE1 => { self.reg.c = self.set(self.reg.c, 4, true); 8 },
@@ -537,7 +1081,7 @@ impl Cpu {
             0xE3 => { self.reg.e = self.set(self.reg.e, 4, true); 8 },
             0xE4 => { self.reg.h = self.set(self.reg.h, 4, true); 8 },
             0xE5 => { self.reg.l = self.set(self.reg.l, 4, true); 8 },
-            0xE6 => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.set(v, 0, true); self.mmu.write_byte_at(hl, v); 16 },
+            0xE6 => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.set(v, 4, true); self.write_byte(hl, v); 16 },
             0xE7 => { self.reg.a = self.set(self.reg.a, 4, true); 8 },
             0xE8 => { self.reg.b = self.set(self.reg.b, 5, true); 8 },
             0xE9 => { self.reg.c = self.set(self.reg.c, 5, true); 8 },
@@ -545,7 +1089,7 @@ impl Cpu {
             0xEB => { self.reg.e = self.set(self.reg.e, 5, true); 8 },
             0xEC => { self.reg.h = self.set(self.reg.h, 5, true); 8 },
             0xED => { self.reg.l = self.set(self.reg.l, 5, true); 8 },
-            0xEE => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.set(v, 0, true); self.mmu.write_byte_at(hl, v); 16 },
+            0xEE => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.set(v, 5, true); self.write_byte(hl, v); 16 },
             0xEF => { self.reg.a = self.set(self.reg.a, 5, true); 8 },
             0xF0 => { self.reg.b = self.set(self.reg.b, 6, true); 8 },
             0xF1 => { self.reg.c = self.set(self.reg.c, 6, true); 8 },
@@ -553,7 +1097,7 @@ impl Cpu {
             0xF3 => { self.reg.e = self.set(self.reg.e, 6, true); 8 },
             0xF4 => { self.reg.h = self.set(self.reg.h, 6, true); 8 },
             0xF5 => { self.reg.l = self.set(self.reg.l, 6, true); 8 },
-            0xF6 => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.set(v, 0, true); self.mmu.write_byte_at(hl, v); 16 },
+            0xF6 => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.set(v, 6, true); self.write_byte(hl, v); 16 },
             0xF7 => { self.reg.a = self.set(self.reg.a, 6, true); 8 },
             0xF8 => { self.reg.b = self.set(self.reg.b, 7, true); 8 },
             0xF9 => { self.reg.c = self.set(self.reg.c, 7, true); 8 },
@@ -561,7 +1105,7 @@ impl Cpu {
             0xFB => { self.reg.e = self.set(self.reg.e, 7, true); 8 },
             0xFC => { self.reg.h = self.set(self.reg.h, 7, true); 8 },
             0xFD => { self.reg.l = self.set(self.reg.l, 7, true); 8 },
-            0xFE => { let hl = self.reg.hl(); let mut v = self.mmu.read_byte_at(hl); v = self.set(v, 0, true); self.mmu.write_byte_at(hl, v); 16 },
+            0xFE => { let hl = self.reg.hl(); let mut v = self.read_byte(hl); v = self.set(v, 7, true); self.write_byte(hl, v); 16 },
             0xFF => { self.reg.a = self.set(self.reg.a, 7, true); 8 },
         }
     }
@@ -569,19 +1113,19 @@ impl Cpu {
     /// Push a word into the stack memory, first the MSB and then the LSB,
     /// and decrement the stack pointer twice
     fn push(&mut self, word: u16) {
-        self.reg.sp -= 1;
-        self.mmu.write_byte_at(self.reg.sp, msb(word));
-        self.reg.sp -= 1;
-        self.mmu.write_byte_at(self.reg.sp, lsb(word));
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.write_byte(self.reg.sp, msb(word));
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.write_byte(self.reg.sp, lsb(word));
     }
 
     /// Pop two bytes off the stack and return the resulting combined word,
     /// and increment the stack pointer twice
     fn pop(&mut self) -> u16 {
-        let b1 = self.mmu.read_byte_at(self.reg.sp);
-        self.reg.sp += 1;
-        let b2 = self.mmu.read_byte_at(self.reg.sp);
-        self.reg.sp += 1;
+        let b1 = self.read_byte(self.reg.sp);
+        self.reg.sp = self.reg.sp.wrapping_add(1);
+        let b2 = self.read_byte(self.reg.sp);
+        self.reg.sp = self.reg.sp.wrapping_add(1);
         make_word(b2, b1)
     }
 
@@ -610,6 +1154,7 @@ impl Cpu {
         self.reg.set_flag(N, true);
         self.reg.set_flag(H, (self.reg.a & 0x0F) < (val & 0x0F) + c);
         self.reg.set_flag(C, (self.reg.a as u16) < (val as u16) + (c as u16));
+        self.reg.a = res;
     }
 
     fn sub(&mut self, val: u8) {
@@ -654,23 +1199,63 @@ impl Cpu {
     /// Increment the value and return the results, also setting the corresponding flags
     /// as specified by the INC instruction
     fn inc(&mut self, val: u8) -> u8 {
-        let res = val + 1;
+        let res = val.wrapping_add(1);
         self.reg.set_flag(Z, res == 0);
         self.reg.set_flag(N, false);
-        self.reg.set_flag(H, (self.reg.a & 0x0F) + 1 > 0x0F);
+        self.reg.set_flag(H, (val & 0x0F) + 1 > 0x0F);
         res
     }
 
     /// Decrement the value and return the results, also setting the corresponding flags
     /// as specified by the DEC instruction
     fn dec(&mut self, val: u8) -> u8 {
-        let res = val - 1;
+        let res = val.wrapping_sub(1);
         self.reg.set_flag(Z, res == 0);
         self.reg.set_flag(N, true);
-        self.reg.set_flag(H, (self.reg.a & 0x0F) < 1);
+        self.reg.set_flag(H, (val & 0x0F) < 1);
         res
     }
 
+    fn add_hl(&mut self, val: u16) {
+        let hl = self.reg.hl();
+        self.reg.set_flag(N, false);
+        self.reg.set_flag(H, (hl & 0x0FFF) + (val & 0x0FFF) > 0x0FFF);
+        self.reg.set_flag(C, hl as u32 + val as u32 > 0xFFFF);
+        self.reg.set_hl(hl.wrapping_add(val));
+    }
+
+    /// Add a signed offset to SP and return the result, with the H and C
+    /// flags set from adding the offset's byte to the low byte of SP
+    fn add_sp(&mut self, val: u8) -> u16 {
+        let sp = self.reg.sp;
+        self.reg.set_flag(Z, false);
+        self.reg.set_flag(N, false);
+        self.reg.set_flag(H, (sp & 0x0F) + (val as u16 & 0x0F) > 0x0F);
+        self.reg.set_flag(C, (sp & 0xFF) + val as u16 > 0xFF);
+        sp.wrapping_add(val as i8 as u16)
+    }
+
+    /// Adjust A to be a valid BCD number after a BCD addition or subtraction
+    fn daa(&mut self) {
+        let mut correction = 0;
+        let mut carry = false;
+        if self.reg.get_flag(H) || (!self.reg.get_flag(N) && self.reg.a & 0x0F > 0x09) {
+            correction |= 0x06;
+        }
+        if self.reg.get_flag(C) || (!self.reg.get_flag(N) && self.reg.a > 0x99) {
+            correction |= 0x60;
+            carry = true;
+        }
+        self.reg.a = if self.reg.get_flag(N) {
+            self.reg.a.wrapping_sub(correction)
+        } else {
+            self.reg.a.wrapping_add(correction)
+        };
+        self.reg.set_flag(Z, self.reg.a == 0);
+        self.reg.set_flag(H, false);
+        self.reg.set_flag(C, carry);
+    }
+
     fn swap(&mut self, val: u8) -> u8 {
         let res = swap(val);
         self.reg.set_flag(Z, res == 0);
@@ -729,9 +1314,9 @@ impl Cpu {
     }
 
     fn rr(&mut self, val: u8) -> u8 {
-        let c = self.reg.get_flag(C) as u16;
+        let c = self.reg.get_flag(C) as u8;
         self.reg.set_flag(C, val & 1 != 0);
-        let res = (((val as u16) >> 1) | (c << 8)) as u8;
+        let res = (val >> 1) | (c << 7);
         self._rotate_shift_flag_update(res);
         res
     }
@@ -772,10 +1357,292 @@ impl Cpu {
         }
     }
 
-    /// Jump to the given address if the flag has the state passed as argument
-    fn jump_conditional(&mut self, addr: u16, flag: Flag, state: bool) {
+    /// Either switch the CPU speed if one was requested through KEY1,
+    /// or enter the low power mode
+    fn stop(&mut self) {
+        if !self.mmu.stop() {
+            self.stopped = true;
+        }
+        self.stall();
+    }
+
+    /// Wait for an interrupt to be pending. With IME off and one already
+    /// pending, the CPU does not halt and hits the HALT bug instead.
+    fn halt(&mut self) {
+        if !self.ime && self.mmu.pending_interrupts() != 0 {
+            self.halt_bug = true;
+        } else {
+            self.halted = true;
+        }
+    }
+
+    /// Call the handler of the highest priority pending interrupt, which
+    /// takes five machine cycles
+    fn service_interrupt(&mut self) {
+        self.ime = false;
+        self.tick(8);
+        self.push(self.reg.pc);
+        // The interrupt is only picked after pushing PC, so a push that
+        // overwrites IE can cancel it and jump to 0x0000 instead
+        let pending = self.mmu.pending_interrupts();
+        self.reg.pc = if pending == 0 {
+            0x0000
+        } else {
+            let interrupt = pending & pending.wrapping_neg();
+            self.mmu.acknowledge_interrupt(interrupt);
+            0x40 + 8 * interrupt.trailing_zeros() as u16
+        };
+        self.tick(4);
+    }
+
+    /// Jump to the given address if the flag has the state passed as argument,
+    /// returning the cycles taken
+    fn jump_conditional(&mut self, addr: u16, flag: Flag, state: bool) -> u32 {
         if self.reg.get_flag(flag) == state {
             self.reg.pc = addr;
+            return 16;
         }
+        12
+    }
+
+    fn jump_relative(&mut self, offset: i8) {
+        self.reg.pc = self.reg.pc.wrapping_add(offset as u16);
+    }
+
+    /// Jump by the given offset if the flag has the state passed as argument,
+    /// returning the cycles taken
+    fn jump_relative_conditional(&mut self, offset: i8, flag: Flag, state: bool) -> u32 {
+        if self.reg.get_flag(flag) == state {
+            self.jump_relative(offset);
+            return 12;
+        }
+        8
+    }
+
+    fn call(&mut self, addr: u16) {
+        self.push(self.reg.pc);
+        self.reg.pc = addr;
+    }
+
+    /// Call the given address if the flag has the state passed as argument,
+    /// returning the cycles taken
+    fn call_conditional(&mut self, addr: u16, flag: Flag, state: bool) -> u32 {
+        if self.reg.get_flag(flag) == state {
+            self.call(addr);
+            return 24;
+        }
+        12
+    }
+
+    /// Return if the flag has the state passed as argument, returning the
+    /// cycles taken
+    fn return_conditional(&mut self, flag: Flag, state: bool) -> u32 {
+        if self.reg.get_flag(flag) == state {
+            self.reg.pc = self.pop();
+            return 20;
+        }
+        8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::joypad::MAX_PLAYERS;
+
+    fn run(cpu: &mut Cpu, instructions: usize) {
+        for _ in 0..instructions {
+            cpu.step();
+        }
+    }
+
+    #[test]
+    fn register_pairs() {
+        let mut reg = Register::new();
+        reg.set_hl(0x1234);
+        assert_eq!(reg.hl(), 0x1234);
+        reg.set_af(0x12FF);
+        assert_eq!(reg.af(), 0x12F0);
+    }
+
+    #[test]
+    fn relative_jumps_are_signed() {
+        // LD A,3; loop: DEC A; JR NZ,loop
+        let mut cpu = Cpu::with_code(&[0x3E, 0x03, 0x3D, 0x20, 0xFD]);
+        run(&mut cpu, 7);
+        assert_eq!(cpu.reg.a, 0);
+        assert_eq!(cpu.reg.pc, 0x105);
+    }
+
+    #[test]
+    fn call_and_return() {
+        // CALL 0x0110; NOP ... 0x0110: LD A,0x42; RET
+        let mut code = vec![0xCD, 0x10, 0x01, 0x00];
+        code.resize(0x10, 0);
+        code.extend_from_slice(&[0x3E, 0x42, 0xC9]);
+        let mut cpu = Cpu::with_code(&code);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.reg.pc, 0x110);
+        assert_eq!(cpu.reg.sp, 0xFFFC);
+        run(&mut cpu, 2);
+        assert_eq!(cpu.reg.a, 0x42);
+        assert_eq!(cpu.reg.pc, 0x103);
+        assert_eq!(cpu.reg.sp, 0xFFFE);
+    }
+
+    #[test]
+    fn restart_pushes_the_return_address() {
+        // RST 0x38
+        let mut cpu = Cpu::with_code(&[0xFF]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.reg.pc, 0x38);
+        assert_eq!(cpu.mmu.read_byte_at(0xFFFC), 0x01);
+        assert_eq!(cpu.mmu.read_byte_at(0xFFFD), 0x01);
+    }
+
+    #[test]
+    fn subtractions() {
+        // LD A,0x10; SUB 1; SCF; SBC 0x0F
+        let mut cpu = Cpu::with_code(&[0x3E, 0x10, 0xD6, 0x01, 0x37, 0xDE, 0x0F]);
+        run(&mut cpu, 2);
+        assert_eq!(cpu.reg.a, 0x0F);
+        assert!(cpu.reg.get_flag(N) && cpu.reg.get_flag(H) && !cpu.reg.get_flag(C));
+        run(&mut cpu, 2);
+        assert_eq!(cpu.reg.a, 0xFF);
+        assert!(cpu.reg.get_flag(C));
+    }
+
+    #[test]
+    fn increments_and_decrements() {
+        // LD A,0; LD B,0x0F; INC B; DEC B; LD C,0; DEC C
+        let mut cpu = Cpu::with_code(&[0x3E, 0x00, 0x06, 0x0F, 0x04, 0x05, 0x0E, 0x00, 0x0D]);
+        run(&mut cpu, 3);
+        assert_eq!(cpu.reg.b, 0x10);
+        assert!(cpu.reg.get_flag(H));
+        run(&mut cpu, 1);
+        assert_eq!(cpu.reg.b, 0x0F);
+        assert!(cpu.reg.get_flag(H) && cpu.reg.get_flag(N));
+        run(&mut cpu, 2);
+        assert_eq!(cpu.reg.c, 0xFF);
+        assert!(!cpu.reg.get_flag(Z));
+    }
+
+    #[test]
+    fn rotate_right_through_carry() {
+        // SCF; LD A,2; RRA
+        let mut cpu = Cpu::with_code(&[0x37, 0x3E, 0x02, 0x1F]);
+        run(&mut cpu, 3);
+        assert_eq!(cpu.reg.a, 0x81);
+        assert!(!cpu.reg.get_flag(C) && !cpu.reg.get_flag(Z));
+    }
+
+    #[test]
+    fn swap_res_and_set_write_their_operand() {
+        // LD B,0x12; SWAP B; LD C,0xFF; RES 1,C; SET 1,B
+        let mut cpu = Cpu::with_code(&[0x06, 0x12, 0xCB, 0x30, 0x0E, 0xFF, 0xCB, 0x89, 0xCB, 0xC8]);
+        run(&mut cpu, 2);
+        assert_eq!(cpu.reg.b, 0x21);
+        run(&mut cpu, 2);
+        assert_eq!(cpu.reg.c, 0xFD);
+        assert_eq!(cpu.reg.b, 0x21);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.reg.b, 0x23);
+    }
+
+    #[test]
+    fn decimal_adjust() {
+        // LD A,0x19; ADD 0x28; DAA
+        let mut cpu = Cpu::with_code(&[0x3E, 0x19, 0xC6, 0x28, 0x27]);
+        run(&mut cpu, 3);
+        assert_eq!(cpu.reg.a, 0x47);
+        assert!(!cpu.reg.get_flag(C));
+    }
+
+    #[test]
+    fn sixteen_bit_arithmetic() {
+        // LD HL,0x0FFF; LD BC,1; ADD HL,BC; LD SP,0xFFF8; ADD SP,-2; LD HL,SP+2
+        let code = [
+            0x21, 0xFF, 0x0F, 0x01, 0x01, 0x00, 0x09, 0x31, 0xF8, 0xFF, 0xE8, 0xFE, 0xF8, 0x02,
+        ];
+        let mut cpu = Cpu::with_code(&code);
+        run(&mut cpu, 3);
+        assert_eq!(cpu.reg.hl(), 0x1000);
+        assert!(cpu.reg.get_flag(H) && !cpu.reg.get_flag(C));
+        run(&mut cpu, 2);
+        assert_eq!(cpu.reg.sp, 0xFFF6);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.reg.hl(), 0xFFF8);
+    }
+
+    #[test]
+    fn interrupts_are_enabled_after_the_instruction_following_ei() {
+        // LD A,4; LDH (IE),A; LDH (IF),A; EI; NOP
+        let mut cpu = Cpu::with_code(&[0x3E, 0x04, 0xE0, 0xFF, 0xE0, 0x0F, 0xFB, 0x00]);
+        run(&mut cpu, 5);
+        assert_eq!(cpu.reg.pc, 0x108);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.reg.pc, 0x50);
+        assert!(!cpu.ime);
+        assert_eq!(cpu.mmu.pending_interrupts(), 0);
+    }
+
+    #[test]
+    fn halt_bug_runs_the_next_instruction_twice() {
+        // LD A,4; LDH (IE),A; LDH (IF),A; XOR A; HALT; INC A
+        let mut cpu = Cpu::with_code(&[0x3E, 0x04, 0xE0, 0xFF, 0xE0, 0x0F, 0xAF, 0x76, 0x3C]);
+        run(&mut cpu, 7);
+        assert_eq!(cpu.reg.a, 2);
+        assert!(!cpu.halted);
+    }
+
+    #[test]
+    fn illegal_opcodes_lock_the_cpu_up() {
+        // LD A,4; LDH (IE),A; EI; illegal; INC A
+        let mut cpu = Cpu::with_code(&[0x3E, 0x04, 0xE0, 0xFF, 0xFB, 0xD3, 0x3C]);
+        run(&mut cpu, 4);
+        assert!(cpu.locked);
+        cpu.mmu.write_byte_at(0xFF0F, 0x04);
+        for _ in 0..100 {
+            assert_eq!(cpu.step(), 4);
+        }
+        assert_eq!(cpu.reg.pc, 0x106);
+        assert_eq!(cpu.reg.a, 4);
+        assert_eq!(cpu.mmu.pending_interrupts(), 0x04);
+    }
+
+    #[test]
+    fn players_past_the_last_joypad_are_ignored() {
+        let mut cpu = Cpu::with_code(&[]);
+        cpu.press_player(MAX_PLAYERS, Button::A);
+        cpu.release_player(usize::MAX, Button::A);
+        // Select both groups of buttons
+        cpu.mmu.joypad.write_register(0x00);
+        assert!(!cpu.mmu.joypad.any_line_low());
+    }
+
+    #[test]
+    fn bess_states_keep_the_banks_and_the_clock() {
+        // MBC3 with a clock and 32 KiB of RAM, with a marker in ROM bank 5
+        let mut rom = vec![0; 0x40000];
+        rom[0x147] = 0x10;
+        rom[0x149] = 0x03;
+        rom[5 * 0x4000] = 0x55;
+        let mut cpu = Cpu::from_rom(rom.clone());
+        // Select bank 5, set the clock's hours to 12, latch it and map them
+        for &(address, value) in &[
+            (0x0000, 0x0A),
+            (0x2000, 0x05),
+            (0x4000, 0x0A),
+            (0xA000, 12),
+            (0x6000, 0x00),
+            (0x6000, 0x01),
+        ] {
+            cpu.mmu.write_byte_at(address, value);
+        }
+
+        let mut loaded = Cpu::from_rom(rom);
+        loaded.load_bess(&cpu.save_bess()).unwrap();
+        assert_eq!(loaded.peek(0x4000), 0x55);
+        assert_eq!(loaded.peek(0xA000), 12);
     }
 }
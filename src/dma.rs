@@ -0,0 +1,340 @@
+use std::io;
+
+use crate::state::{StateReader, StateWriter};
+
+pub const OAM_DMA_LENGTH: u16 = 0xA0;
+
+// Machine cycles between writing to the DMA register and the first byte
+// being copied, during which a previous transfer keeps running
+const OAM_DMA_START_DELAY: u8 = 2;
+
+/// The buses the CPU and the DMA controller can access memory through
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    /// Cartridge ROM and RAM, and work RAM
+    External,
+    /// Video RAM
+    Video,
+    /// OAM, I/O registers and high RAM, which are never in conflict
+    Internal,
+}
+
+impl Bus {
+    pub fn of(address: u16) -> Self {
+        match address {
+            0x8000..=0x9FFF => Bus::Video,
+            0xFE00..=0xFFFF => Bus::Internal,
+            _ => Bus::External,
+        }
+    }
+}
+
+/// OAM DMA controller, which copies 160 bytes into OAM at one byte per
+/// machine cycle after a write to the DMA register (0xFF46)
+pub struct OamDma {
+    register: u8,
+    // Source address of a transfer waiting to start, and the machine cycles
+    // left until it does
+    pending: Option<(u16, u8)>,
+    // Source address of the transfer in progress, and the next byte to copy
+    active: Option<(u16, u16)>,
+    // Last byte copied, which is what the CPU sees when it reads from the
+    // bus the transfer is using
+    bus_value: u8,
+    // Cycles left over from the last tick that did not make a machine cycle
+    cycles: u32,
+}
+
+impl Default for OamDma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OamDma {
+    pub fn new() -> Self {
+        Self {
+            register: 0xFF,
+            pending: None,
+            active: None,
+            bus_value: 0xFF,
+            cycles: 0,
+        }
+    }
+
+    pub fn read_register(&self) -> u8 {
+        self.register
+    }
+
+    /// Start a transfer from the given page. If a transfer is already in
+    /// progress, it carries on until the new one replaces it.
+    pub fn write_register(&mut self, value: u8) {
+        self.register = value;
+        // Sources past work RAM read from its mirror
+        let page = if value >= 0xE0 { value - 0x20 } else { value };
+        self.pending = Some(((page as u16) << 8, OAM_DMA_START_DELAY));
+    }
+
+    /// Whether a transfer is copying bytes, which locks OAM for the CPU
+    pub fn is_active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    /// Whether a CPU access to the given address conflicts with the
+    /// transfer in progress, because both are trying to use the same bus
+    pub fn conflicts_with(&self, address: u16) -> bool {
+        match self.active {
+            Some((source, _)) => {
+                let bus = Bus::of(address);
+                bus != Bus::Internal && bus == Bus::of(source)
+            }
+            None => false,
+        }
+    }
+
+    /// Return the byte currently on the bus used by the transfer
+    pub fn bus_value(&self) -> u8 {
+        self.bus_value
+    }
+
+    /// Account for the given number of cycles, returning how many whole
+    /// machine cycles the controller should now be stepped by
+    pub fn machine_cycles(&mut self, cycles: u32) -> u32 {
+        self.cycles += cycles;
+        let machine_cycles = self.cycles / 4;
+        self.cycles %= 4;
+        machine_cycles
+    }
+
+    /// Advance the controller by one machine cycle, returning the source
+    /// address and OAM offset of the byte to copy in it, if any
+    pub fn step(&mut self) -> Option<(u16, u16)> {
+        if let Some((source, delay)) = self.pending {
+            if delay == 1 {
+                self.pending = None;
+                self.active = Some((source, 0));
+            } else {
+                self.pending = Some((source, delay - 1));
+            }
+        }
+        let (source, offset) = self.active?;
+        self.active = if offset + 1 < OAM_DMA_LENGTH {
+            Some((source, offset + 1))
+        } else {
+            None
+        };
+        Some((source + offset, offset))
+    }
+
+    /// Record the byte that was just copied as the one on the bus
+    pub fn set_bus_value(&mut self, value: u8) {
+        self.bus_value = value;
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.register);
+        writer.write_option(self.pending, |writer, (source, delay)| {
+            writer.write_u16(source);
+            writer.write_u8(delay);
+        });
+        writer.write_option(self.active, |writer, (source, offset)| {
+            writer.write_u16(source);
+            writer.write_u16(offset);
+        });
+        writer.write_u8(self.bus_value);
+        writer.write_u32(self.cycles);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.register = reader.read_u8()?;
+        self.pending = reader.read_option(|reader| {
+            let source = read_oam_dma_source(reader)?;
+            Ok((source, reader.read_u8_within(1..=OAM_DMA_START_DELAY)?))
+        })?;
+        self.active = reader.read_option(|reader| {
+            let source = read_oam_dma_source(reader)?;
+            Ok((source, reader.read_below(OAM_DMA_LENGTH as u32)? as u16))
+        })?;
+        self.bus_value = reader.read_u8()?;
+        self.cycles = reader.read_u32()? % 4;
+        Ok(())
+    }
+}
+
+/// Read the source address of an OAM DMA transfer, which is the start of a
+/// page below echo RAM
+fn read_oam_dma_source(reader: &mut StateReader) -> Result<u16, io::Error> {
+    let source = reader.read_u16()?;
+    if source & 0xFF != 0 || source >= 0xE000 {
+        let message = format!("{:04X} is not the source of an OAM DMA transfer", source);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    Ok(source)
+}
+
+// Bytes copied by the HDMA on each HBlank, and the size its length counts in
+pub const HDMA_BLOCK_SIZE: u16 = 0x10;
+// Dots the CPU is halted for while the HDMA copies one block, which is the
+// same amount of real time in either CPU speed
+pub const HDMA_BLOCK_DOTS: u32 = 32;
+
+/// The kind of transfer started by a write to HDMA5
+pub enum HdmaTransfer {
+    /// Copy all the blocks right away
+    GeneralPurpose,
+    /// Copy one block at the start of every HBlank
+    HBlank,
+}
+
+/// CGB VRAM DMA controller (HDMA1-HDMA5, 0xFF51-0xFF55)
+pub struct Hdma {
+    source: u16,
+    destination: u16,
+    // Blocks left to copy
+    remaining: u8,
+    hblank_active: bool,
+}
+
+impl Default for Hdma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hdma {
+    pub fn new() -> Self {
+        Self {
+            source: 0,
+            destination: 0x8000,
+            remaining: 0,
+            hblank_active: false,
+        }
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            // Bit 7 is clear while an HBlank transfer is running, and the
+            // rest of the bits are the number of blocks left minus one, which
+            // makes a finished transfer read as 0xFF
+            0xFF55 => {
+                let length = self.remaining.wrapping_sub(1) & 0x7F;
+                if self.hblank_active {
+                    length
+                } else {
+                    0x80 | length
+                }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    /// Write to one of the HDMA registers, returning the transfer to perform
+    /// if the write was to HDMA5 and started one
+    pub fn write_register(&mut self, address: u16, value: u8) -> Option<HdmaTransfer> {
+        match address {
+            0xFF51 => self.source = (self.source & 0x00FF) | ((value as u16) << 8),
+            0xFF52 => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+            0xFF53 => {
+                self.destination =
+                    0x8000 | (self.destination & 0x00FF) | (((value & 0x1F) as u16) << 8)
+            }
+            0xFF54 => self.destination = (self.destination & 0xFF00) | (value & 0xF0) as u16,
+            0xFF55 => {
+                if self.hblank_active && value & 0x80 == 0 {
+                    // Clearing bit 7 during an HBlank transfer cancels it
+                    self.hblank_active = false;
+                    return None;
+                }
+                self.remaining = (value & 0x7F) + 1;
+                if value & 0x80 != 0 {
+                    self.hblank_active = true;
+                    return Some(HdmaTransfer::HBlank);
+                }
+                return Some(HdmaTransfer::GeneralPurpose);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Whether an HBlank transfer is waiting for the next HBlank
+    pub fn hblank_active(&self) -> bool {
+        self.hblank_active
+    }
+
+    /// Whether the transfer has blocks left to copy
+    pub fn is_active(&self) -> bool {
+        self.remaining > 0
+    }
+
+    /// Return the source and destination addresses of the next block to
+    /// copy, advancing the transfer past it, or None if it is over
+    pub fn next_block(&mut self) -> Option<(u16, u16)> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let block = (self.source, self.destination);
+        self.source = self.source.wrapping_add(HDMA_BLOCK_SIZE);
+        self.destination = 0x8000 | (self.destination.wrapping_add(HDMA_BLOCK_SIZE) & 0x1FFF);
+        self.remaining -= 1;
+        if self.remaining == 0 || self.destination == 0x8000 {
+            // Running past the end of VRAM also stops the transfer
+            self.remaining = 0;
+            self.hblank_active = false;
+        }
+        Some(block)
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.source);
+        writer.write_u16(self.destination);
+        writer.write_u8(self.remaining);
+        writer.write_bool(self.hblank_active);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.source = reader.read_u16()?;
+        self.destination = 0x8000 | (reader.read_u16()? & 0x1FF0);
+        self.remaining = reader.read_u8()?;
+        self.hblank_active = reader.read_bool()? && self.remaining > 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::State;
+
+    #[test]
+    fn transfer_stops_at_the_end_of_vram() {
+        let mut hdma = Hdma::new();
+        hdma.write_register(0xFF53, 0x1F);
+        hdma.write_register(0xFF54, 0xE0);
+        assert!(matches!(
+            hdma.write_register(0xFF55, 0x07),
+            Some(HdmaTransfer::GeneralPurpose)
+        ));
+        assert_eq!(hdma.next_block(), Some((0x0000, 0x9FE0)));
+        assert_eq!(hdma.next_block(), Some((0x0010, 0x9FF0)));
+        assert!(!hdma.is_active());
+        assert_eq!(hdma.next_block(), None);
+        assert_eq!(hdma.read_register(0xFF55), 0xFF);
+    }
+
+    #[test]
+    fn states_with_impossible_transfers_are_rejected() {
+        let load = |pending| {
+            let mut dma = OamDma::new();
+            dma.pending = Some(pending);
+            let mut writer = StateWriter::new();
+            writer.section(b"DMA ", |writer| dma.save_state(writer));
+            let data = writer.finish();
+            let state = State::parse(&data).unwrap();
+            state.read_section(b"DMA ", |reader| OamDma::new().load_state(reader))
+        };
+        assert!(load((0xDF00, OAM_DMA_START_DELAY)).is_ok());
+        assert!(load((0xC000, 0)).is_err());
+        assert!(load((0xFFF0, 1)).is_err());
+    }
+}
@@ -0,0 +1,421 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::apu::CLOCK_RATE;
+use crate::cpu::Cpu;
+use crate::link::LinkCable;
+use crate::ppu::DOTS_PER_FRAME;
+use crate::serial::SerialDevice;
+
+pub const MAX_PLAYERS: usize = 4;
+
+// Bytes of the protocol
+const PING_HEADER: u8 = 0xFE;
+const PING_ACK: u8 = 0x88;
+const START_REQUEST: u8 = 0xAA;
+const START_ACK: u8 = 0xCC;
+const RESET_REQUEST: u8 = 0xFF;
+const PING_PACKET_SIZE: usize = 4;
+
+// Dots between the bytes of the ping phase, roughly 1.2 ms
+const PING_BYTE_DOTS: u32 = 0x1400;
+// Dots the adapter takes to clock a byte out at 8192 Hz, and the unit of
+// the extra delay between bytes set by the RATE byte, roughly 1 µs
+const BYTE_TRANSFER_DOTS: u32 = 8 * 512;
+const RATE_UNIT_DOTS: u32 = 4;
+
+// How long the hub waits for a player to answer a byte before treating it
+// as not plugged in for that byte
+const HUB_REPLY_TIMEOUT: Duration = Duration::from_millis(50);
+const HUB_POLL_INTERVAL: Duration = Duration::from_micros(50);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Ping,
+    // Answering player 1's request to start with a packet of START_ACK
+    Starting,
+    Transmission,
+}
+
+/// Four Player Adapter (DMG-07), which drives the clock of up to four Game
+/// Boys and relays their data between them
+///
+/// The adapter starts in the ping phase, where it sends each player packets
+/// of `PING_HEADER` followed by three status bytes, holding the player's
+/// number in bits 0-2 and which players are connected in bits 4-7. Players
+/// answer with two `PING_ACK`s to count as connected, then with the RATE and
+/// SIZE of the transmission phase, which only player 1's count. Once player
+/// 1 answers a whole packet with `START_REQUEST`, the adapter acknowledges
+/// with a packet of `START_ACK` and starts the transmission phase.
+///
+/// In the transmission phase, the adapter sends every player rounds of
+/// 4 × SIZE bytes, holding the SIZE bytes each player sent at the start of
+/// the previous round, in player order. Players that are not connected
+/// count as having sent zeros. Player 1 sending a whole packet of
+/// `RESET_REQUEST` goes back to the ping phase.
+pub struct FourPlayerAdapter {
+    phase: Phase,
+    // Index of the next byte in the packet or round
+    index: usize,
+    // Players that answered the last ping packet, and those that answered
+    // the one in progress so far, as masks with player 1 in bit 0
+    connected: u8,
+    answering: u8,
+    start_requested: bool,
+    reset_requested: bool,
+    rate: u8,
+    size: usize,
+    // Data being sent in the round in progress, and that received in it
+    sending: Vec<u8>,
+    receiving: Vec<u8>,
+}
+
+impl Default for FourPlayerAdapter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FourPlayerAdapter {
+    pub fn new() -> Self {
+        Self {
+            phase: Phase::Ping,
+            index: 0,
+            connected: 0,
+            answering: 0,
+            start_requested: false,
+            reset_requested: false,
+            rate: 0,
+            size: 1,
+            sending: Vec::new(),
+            receiving: Vec::new(),
+        }
+    }
+
+    /// Return the bytes the adapter shifts out to each player in the next
+    /// transfer
+    pub fn outgoing(&self) -> [u8; MAX_PLAYERS] {
+        let mut bytes = [0; MAX_PLAYERS];
+        for (player, byte) in bytes.iter_mut().enumerate() {
+            *byte = match self.phase {
+                Phase::Ping if self.index == 0 => PING_HEADER,
+                Phase::Ping => (self.connected << 4) | (player as u8 + 1),
+                Phase::Starting => START_ACK,
+                Phase::Transmission => self.sending[self.index],
+            };
+        }
+        bytes
+    }
+
+    /// Complete the transfer with the bytes shifted in from each player,
+    /// 0xFF for those not plugged in or not waiting for it
+    pub fn receive(&mut self, incoming: [u8; MAX_PLAYERS]) {
+        match self.phase {
+            Phase::Ping => self.receive_ping(incoming),
+            Phase::Starting => {
+                self.index += 1;
+                if self.index == PING_PACKET_SIZE {
+                    self.start_transmission();
+                }
+            }
+            Phase::Transmission => self.receive_data(incoming),
+        }
+    }
+
+    fn receive_ping(&mut self, incoming: [u8; MAX_PLAYERS]) {
+        if self.index == 0 {
+            self.answering = 0x0F;
+            self.start_requested = true;
+        }
+        match self.index {
+            0 | 1 => {
+                for (player, &byte) in incoming.iter().enumerate() {
+                    if byte != PING_ACK && !(player == 0 && byte == START_REQUEST) {
+                        self.answering &= !(1 << player);
+                    }
+                }
+            }
+            2 => self.rate = incoming[0],
+            _ => self.size = (incoming[0] as usize).max(1),
+        }
+        self.start_requested &= incoming[0] == START_REQUEST;
+        self.index += 1;
+        if self.index == PING_PACKET_SIZE {
+            self.index = 0;
+            self.connected = self.answering;
+            if self.start_requested && self.connected & 1 != 0 {
+                self.phase = Phase::Starting;
+            }
+        }
+    }
+
+    fn start_transmission(&mut self) {
+        self.phase = Phase::Transmission;
+        self.index = 0;
+        self.sending = vec![0; MAX_PLAYERS * self.size];
+        self.receiving = vec![0; MAX_PLAYERS * self.size];
+        self.reset_requested = true;
+    }
+
+    fn receive_data(&mut self, incoming: [u8; MAX_PLAYERS]) {
+        if self.index < self.size {
+            for (player, &byte) in incoming.iter().enumerate() {
+                if self.connected & (1 << player) != 0 {
+                    self.receiving[player * self.size + self.index] = byte;
+                }
+            }
+            self.reset_requested &= incoming[0] == RESET_REQUEST;
+        }
+        self.index += 1;
+        if self.index == self.sending.len() {
+            self.index = 0;
+            std::mem::swap(&mut self.sending, &mut self.receiving);
+            self.receiving.iter_mut().for_each(|byte| *byte = 0);
+            if self.reset_requested {
+                self.phase = Phase::Ping;
+                self.connected = 0;
+            }
+            self.reset_requested = true;
+        }
+    }
+
+    /// Return the dots from the start of a transfer to the start of the
+    /// next one
+    pub fn byte_dots(&self) -> u32 {
+        match self.phase {
+            Phase::Ping | Phase::Starting => PING_BYTE_DOTS,
+            Phase::Transmission => {
+                let delay = (self.rate & 0x0F) as u32 * 6 + 0x28;
+                BYTE_TRANSFER_DOTS + delay * RATE_UNIT_DOTS
+            }
+        }
+    }
+}
+
+/// Ports of an adapter shared with the players in the same process
+struct Ports {
+    // Byte each player is ready to shift out while waiting on the external
+    // clock, and the byte delivered to it once the adapter clocks it
+    offers: [Option<u8>; MAX_PLAYERS],
+    deliveries: [Option<u8>; MAX_PLAYERS],
+}
+
+/// Port of the adapter plugged into the serial port of one of the players
+struct Port {
+    ports: Rc<RefCell<Ports>>,
+    player: usize,
+}
+
+impl SerialDevice for Port {
+    /// The adapter ignores players that try to drive the clock themselves
+    fn transfer(&mut self, _outgoing: u8) -> Option<u8> {
+        Some(0xFF)
+    }
+
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        let mut ports = self.ports.borrow_mut();
+        let delivery = ports.deliveries[self.player].take();
+        ports.offers[self.player] = match delivery {
+            Some(_) => None,
+            None => Some(outgoing),
+        };
+        delivery
+    }
+}
+
+/// Up to four Game Boys plugged into a Four Player Adapter, run in
+/// instruction lockstep on one thread along with it. Missing players are ports with nothing
+/// plugged in.
+pub struct FourPlayerSession {
+    pub players: Vec<Cpu>,
+    pub adapter: FourPlayerAdapter,
+    ports: Rc<RefCell<Ports>>,
+    // Dots of real time at which the adapter starts its next transfer
+    next_transfer: u64,
+}
+
+impl FourPlayerSession {
+    pub fn new(mut players: Vec<Cpu>) -> Self {
+        assert!(
+            (1..=MAX_PLAYERS).contains(&players.len()),
+            "The adapter takes 1 to 4 players"
+        );
+        let ports = Rc::new(RefCell::new(Ports {
+            offers: [None; MAX_PLAYERS],
+            deliveries: [None; MAX_PLAYERS],
+        }));
+        for (player, cpu) in players.iter_mut().enumerate() {
+            let port = Port {
+                ports: Rc::clone(&ports),
+                player,
+            };
+            cpu.set_serial_device(Some(Box::new(port)));
+        }
+        let adapter = FourPlayerAdapter::new();
+        let next_transfer = adapter.byte_dots() as u64;
+        Self {
+            players,
+            adapter,
+            ports,
+            next_transfer,
+        }
+    }
+
+    /// Execute the next instruction of the player that is furthest behind,
+    /// or the adapter's next transfer once all players caught up with it
+    pub fn step(&mut self) {
+        let (player, dots) = self.behind();
+        if dots >= self.next_transfer {
+            self.transfer();
+            return;
+        }
+        // A player only offers a byte for as long as it keeps waiting on the
+        // external clock, which it renews on every cycle it does
+        self.ports.borrow_mut().offers[player] = None;
+        self.players[player].step();
+    }
+
+    /// Run all players for the given number of frames worth of real time
+    pub fn run_frames(&mut self, frames: u32) {
+        let end = self.behind().1 + frames as u64 * DOTS_PER_FRAME as u64;
+        while self.behind().1 < end {
+            self.step();
+        }
+    }
+
+    /// Return the player furthest behind in real time and its dots
+    fn behind(&self) -> (usize, u64) {
+        self.players
+            .iter()
+            .map(Cpu::dots)
+            .enumerate()
+            .min_by_key(|&(_, dots)| dots)
+            .unwrap()
+    }
+
+    fn transfer(&mut self) {
+        let outgoing = self.adapter.outgoing();
+        let mut incoming = [0xFF; MAX_PLAYERS];
+        let mut ports = self.ports.borrow_mut();
+        for player in 0..self.players.len() {
+            if let Some(byte) = ports.offers[player].take() {
+                incoming[player] = byte;
+                ports.deliveries[player] = Some(outgoing[player]);
+            }
+        }
+        self.adapter.receive(incoming);
+        self.next_transfer += self.adapter.byte_dots() as u64;
+    }
+}
+
+/// Four Player Adapter linking up to four emulator instances over local
+/// sockets, each connected with a `LinkCable` of its own
+///
+/// The adapter runs in real time, and waits for the players that are
+/// plugged in to answer each byte, so that they all keep up with it.
+pub struct FourPlayerHub {
+    cables: Vec<LinkCable>,
+    adapter: FourPlayerAdapter,
+}
+
+impl FourPlayerHub {
+    pub fn new(cables: Vec<LinkCable>) -> Self {
+        assert!(
+            (1..=MAX_PLAYERS).contains(&cables.len()),
+            "The adapter takes 1 to 4 players"
+        );
+        Self {
+            cables,
+            adapter: FourPlayerAdapter::new(),
+        }
+    }
+
+    /// Run the adapter until all players disconnected
+    pub fn run(&mut self) {
+        while self.cables.iter().any(LinkCable::is_connected) {
+            let start = Instant::now();
+            self.transfer();
+            let period = Duration::from_nanos(
+                self.adapter.byte_dots() as u64 * 1_000_000_000 / CLOCK_RATE as u64,
+            );
+            if let Some(remaining) = period.checked_sub(start.elapsed()) {
+                thread::sleep(remaining);
+            }
+        }
+    }
+
+    fn transfer(&mut self) {
+        let outgoing = self.adapter.outgoing();
+        for (cable, &byte) in self.cables.iter_mut().zip(&outgoing) {
+            cable.send_transfer(byte);
+        }
+        let mut incoming: [Option<u8>; MAX_PLAYERS] = [None; MAX_PLAYERS];
+        let deadline = Instant::now() + HUB_REPLY_TIMEOUT;
+        loop {
+            let mut waiting = false;
+            for (cable, byte) in self.cables.iter_mut().zip(incoming.iter_mut()) {
+                if byte.is_none() && cable.is_connected() {
+                    *byte = cable.poll_reply();
+                    waiting |= byte.is_none();
+                }
+            }
+            if !waiting {
+                break;
+            }
+            if Instant::now() >= deadline {
+                for (cable, byte) in self.cables.iter_mut().zip(&incoming) {
+                    if byte.is_none() && cable.is_connected() {
+                        cable.abandon_reply();
+                    }
+                }
+                break;
+            }
+            thread::sleep(HUB_POLL_INTERVAL);
+        }
+        self.adapter
+            .receive(incoming.map(|byte| byte.unwrap_or(0xFF)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run a whole ping packet through the adapter, returning the status
+    /// bytes it sent
+    fn ping(
+        adapter: &mut FourPlayerAdapter,
+        answers: [[u8; MAX_PLAYERS]; PING_PACKET_SIZE],
+    ) -> Vec<[u8; MAX_PLAYERS]> {
+        let mut sent = Vec::new();
+        for &incoming in &answers {
+            sent.push(adapter.outgoing());
+            adapter.receive(incoming);
+        }
+        assert_eq!(sent[0], [PING_HEADER; MAX_PLAYERS]);
+        sent.split_off(1)
+    }
+
+    #[test]
+    fn missing_players_are_not_connected() {
+        let mut adapter = FourPlayerAdapter::new();
+        // Players 2 and 4 are not plugged in, so their bytes read as 0xFF
+        let ack = [PING_ACK, 0xFF, PING_ACK, 0xFF];
+        let answers = [ack, ack, [0x00, 0xFF, 0x00, 0xFF], [0x01, 0xFF, 0x01, 0xFF]];
+        for status in ping(&mut adapter, answers) {
+            assert_eq!(status, [0x01, 0x02, 0x03, 0x04]);
+        }
+        for status in ping(&mut adapter, answers) {
+            assert_eq!(status, [0x51, 0x52, 0x53, 0x54]);
+        }
+        // Player 3 stops answering
+        let ack = [PING_ACK, 0xFF, 0xFF, 0xFF];
+        ping(&mut adapter, [ack, ack, [0x00; 4], [0x01; 4]]);
+        for status in ping(&mut adapter, answers) {
+            assert_eq!(status, [0x11, 0x12, 0x13, 0x14]);
+        }
+        assert!(adapter.phase == Phase::Ping);
+    }
+}
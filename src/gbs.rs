@@ -0,0 +1,161 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const HEADER_SIZE: usize = 0x70;
+const ROM_BANK_SIZE: usize = 0x4000;
+// Start of the code that sets up the registers and calls init
+const MAIN_ADDRESS: u16 = 0x0150;
+
+// TAC bits with special meaning in a GBS header
+const TAC_ENABLE: u8 = 0x04;
+const TAC_DOUBLE_SPEED: u8 = 0x80;
+
+/// GBS music file, which holds the sound driver and music data ripped out of
+/// a game, to be loaded at a fixed address along with the addresses of its
+/// init and play routines
+pub struct Gbs {
+    pub song_count: u8,
+    /// First song to play, counting from 1
+    pub first_song: u8,
+    load_address: u16,
+    init_address: u16,
+    play_address: u16,
+    stack_pointer: u16,
+    timer_modulo: u8,
+    timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+    data: Vec<u8>,
+}
+
+impl Gbs {
+    pub fn load(path: &Path) -> Result<Self, io::Error> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, io::Error> {
+        if bytes.len() < HEADER_SIZE || &bytes[0..3] != b"GBS" || bytes[3] != 1 {
+            return Err(invalid_data("Not a version 1 GBS file"));
+        }
+        let word = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let text = |offset: usize| {
+            let field = &bytes[offset..offset + 0x20];
+            let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+            String::from_utf8_lossy(&field[..end]).into_owned()
+        };
+        let gbs = Self {
+            song_count: bytes[0x04],
+            first_song: bytes[0x05],
+            load_address: word(0x06),
+            init_address: word(0x08),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: bytes[0x0E],
+            timer_control: bytes[0x0F],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+            data: bytes[HEADER_SIZE..].to_vec(),
+        };
+        // The space below the load address is where the RST and interrupt
+        // vectors go, which the player needs for itself
+        if gbs.load_address < 0x0400 || gbs.load_address >= 0x8000 {
+            return Err(invalid_data("Invalid GBS load address"));
+        }
+        Ok(gbs)
+    }
+
+    /// Whether play is called from the timer interrupt rather than VBlank
+    pub fn uses_timer(&self) -> bool {
+        self.timer_control & TAC_ENABLE != 0
+    }
+
+    /// Build a ROM image around the GBS data that calls init with the given
+    /// track, counting from 0, and then calls play on every VBlank or timer
+    /// interrupt. The data goes at its load address, with any of it past
+    /// 0x8000 in the banks that follow.
+    pub fn rom_image(&self, track: u8) -> Vec<u8> {
+        let end = self.load_address as usize + self.data.len();
+        let size = end.max(2 * ROM_BANK_SIZE).div_ceil(ROM_BANK_SIZE) * ROM_BANK_SIZE;
+        let mut rom = vec![0xFF; size];
+        rom[self.load_address as usize..end].copy_from_slice(&self.data);
+
+        // RST instructions jump to the same offset from the load address
+        for vector in (0x00..0x40).step_by(8) {
+            let target = self.load_address + vector as u16;
+            put(&mut rom, vector, &jump(0xC3, target));
+        }
+        // Interrupt vectors, with the one of the interrupt driving play
+        // calling it and the rest returning right away
+        let play_vector = if self.uses_timer() { 0x50 } else { 0x40 };
+        for vector in (0x40..=0x60).step_by(8) {
+            if vector == play_vector {
+                put(&mut rom, vector, &jump(0xCD, self.play_address));
+                put(&mut rom, vector + 3, &[0xD9]);
+            } else {
+                put(&mut rom, vector, &[0xD9]);
+            }
+        }
+
+        // Entry point and header, which only needs the title, whether it is
+        // a CGB game, for double speed, and an MBC3 with 8 KiB of RAM, which
+        // switches banks the way GBS files expect, with bank 0 selecting 1
+        put(&mut rom, 0x0100, &[0x00]);
+        put(&mut rom, 0x0101, &jump(0xC3, MAIN_ADDRESS));
+        let title: Vec<u8> = self.title.bytes().filter(u8::is_ascii).take(15).collect();
+        put(&mut rom, 0x0134, &[0; 15]);
+        put(&mut rom, 0x0134, &title);
+        let double_speed = self.timer_control & TAC_DOUBLE_SPEED != 0;
+        put(&mut rom, 0x0143, &[if double_speed { 0xC0 } else { 0x00 }]);
+        put(&mut rom, 0x0147, &[0x12]);
+        put(&mut rom, 0x0149, &[0x02]);
+
+        let mut code = vec![0xF3];
+        code.extend_from_slice(&jump(0x31, self.stack_pointer));
+        // Enable the RAM at 0xA000-0xBFFF, which GBS files can use
+        code.extend_from_slice(&[0x3E, 0x0A, 0xEA, 0x00, 0x00]);
+        if double_speed {
+            // Switch speeds through KEY1 and STOP
+            code.extend_from_slice(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00]);
+        }
+        let interrupt = if self.uses_timer() { 0x04 } else { 0x01 };
+        code.extend_from_slice(&[
+            0x3E,
+            self.timer_modulo,
+            0xE0,
+            0x06,
+            0x3E,
+            self.timer_control & 0x07,
+            0xE0,
+            0x07,
+            0x3E,
+            interrupt,
+            0xE0,
+            0xFF,
+            0x3E,
+            track,
+        ]);
+        code.extend_from_slice(&jump(0xCD, self.init_address));
+        // Clear whatever init requested, enable interrupts, and halt in a
+        // loop while they call play
+        code.extend_from_slice(&[0xAF, 0xE0, 0x0F, 0xFB, 0x76, 0x18, 0xFD]);
+        put(&mut rom, MAIN_ADDRESS as usize, &code);
+        rom
+    }
+}
+
+/// Encode an instruction taking a 16-bit address
+fn jump(opcode: u8, address: u16) -> [u8; 3] {
+    let [low, high] = address.to_le_bytes();
+    [opcode, low, high]
+}
+
+fn put(rom: &mut [u8], address: usize, bytes: &[u8]) {
+    rom[address..address + bytes.len()].copy_from_slice(bytes);
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
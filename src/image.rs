@@ -0,0 +1,57 @@
+use std::io;
+use std::io::Write;
+
+/// Expand an RGB555 color into 8-bit RGB components
+pub fn rgb888(color: u16) -> [u8; 3] {
+    let expand = |component: u16| {
+        let component = (component & 0x1F) as u8;
+        (component << 3) | (component >> 2)
+    };
+    [expand(color), expand(color >> 5), expand(color >> 10)]
+}
+
+/// Write RGB555 pixels as a binary PPM image. PPM images can be written
+/// one after another into the same stream to make a video.
+pub fn write_ppm(
+    writer: &mut dyn Write,
+    width: usize,
+    height: usize,
+    pixels: &[u16],
+) -> Result<(), io::Error> {
+    write!(writer, "P6\n{} {}\n255\n", width, height)?;
+    let data: Vec<u8> = pixels.iter().flat_map(|&color| rgb888(color)).collect();
+    writer.write_all(&data)
+}
+
+/// Write 8-bit grayscale pixels as a binary PGM image
+pub fn write_pgm(
+    writer: &mut dyn Write,
+    width: usize,
+    height: usize,
+    pixels: &[u8],
+) -> Result<(), io::Error> {
+    write!(writer, "P5\n{} {}\n255\n", width, height)?;
+    writer.write_all(pixels)
+}
+
+/// Shrink RGB555 pixels to half their width and height by averaging each
+/// block of 2x2 pixels, to make a thumbnail
+pub fn half_size(width: usize, height: usize, pixels: &[u16]) -> Vec<u16> {
+    let mut thumbnail = Vec::with_capacity(width / 2 * height / 2);
+    for y in (0..height - height % 2).step_by(2) {
+        for x in (0..width - width % 2).step_by(2) {
+            let block = [
+                pixels[y * width + x],
+                pixels[y * width + x + 1],
+                pixels[(y + 1) * width + x],
+                pixels[(y + 1) * width + x + 1],
+            ];
+            let average = |shift: u16| {
+                let sum: u16 = block.iter().map(|&color| (color >> shift) & 0x1F).sum();
+                (sum / 4) << shift
+            };
+            thumbnail.push(average(0) | average(5) | average(10));
+        }
+    }
+    thumbnail
+}
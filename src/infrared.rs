@@ -0,0 +1,129 @@
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+use crate::state::{StateReader, StateWriter};
+
+const RP_LED: u8 = 0x01;
+const RP_NO_LIGHT: u8 = 0x02;
+const RP_READ_ENABLE: u8 = 0xC0;
+
+// Dots the photodiode's output takes to decay below the detection level
+// once the light goes away, roughly 60 µs
+const DECAY_DOTS: u64 = 0x100;
+
+/// Whatever faces the infrared port, such as another Game Boy Color
+pub trait InfraredDevice {
+    /// Called when the Game Boy turns its LED on or off, with the dots
+    /// elapsed since power on
+    fn set_led(&mut self, on: bool, dots: u64);
+
+    /// Return whether light from the device reaches the Game Boy's
+    /// photodiode at the given dots since power on
+    fn light(&mut self, dots: u64) -> bool;
+}
+
+impl<T: InfraredDevice> InfraredDevice for Rc<RefCell<T>> {
+    fn set_led(&mut self, on: bool, dots: u64) {
+        self.borrow_mut().set_led(on, dots);
+    }
+
+    fn light(&mut self, dots: u64) -> bool {
+        self.borrow_mut().light(dots)
+    }
+}
+
+/// Infrared port of the CGB (RP)
+///
+/// Bit 0 turns the LED on. Bit 1 reads 0 while the photodiode sees light,
+/// as long as reading is enabled by setting bits 6 and 7. The photodiode's
+/// output fades out rather than stopping when the light goes away, so short
+/// gaps between pulses still read as light.
+pub struct Infrared {
+    control: u8,
+    device: Option<Box<dyn InfraredDevice>>,
+    dots: u64,
+    // Dots at which the photodiode last saw light
+    last_light: Option<u64>,
+}
+
+impl Default for Infrared {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Infrared {
+    pub fn new() -> Self {
+        Self {
+            control: 0,
+            device: None,
+            dots: 0,
+            last_light: None,
+        }
+    }
+
+    /// Point the port at a device, or at nothing with `None`
+    pub fn set_device(&mut self, device: Option<Box<dyn InfraredDevice>>) {
+        self.device = device;
+        self.last_light = None;
+    }
+
+    /// Swap the device for another, or for none, returning the previous
+    /// one. Unlike `set_device`, the light last seen is kept.
+    pub fn replace_device(
+        &mut self,
+        device: Option<Box<dyn InfraredDevice>>,
+    ) -> Option<Box<dyn InfraredDevice>> {
+        std::mem::replace(&mut self.device, device)
+    }
+
+    pub fn read_register(&self) -> u8 {
+        let sees_light = self.control & RP_READ_ENABLE == RP_READ_ENABLE
+            && self
+                .last_light
+                .is_some_and(|dots| self.dots.saturating_sub(dots) < DECAY_DOTS);
+        let signal = if sees_light { 0 } else { RP_NO_LIGHT };
+        0x3C | self.control | signal
+    }
+
+    pub fn write_register(&mut self, value: u8) {
+        let led_changed = (self.control ^ value) & RP_LED != 0;
+        self.control = value & (RP_READ_ENABLE | RP_LED);
+        if let (true, Some(device)) = (led_changed, self.device.as_mut()) {
+            device.set_led(value & RP_LED != 0, self.dots);
+        }
+    }
+
+    /// Advance the port to the given dots since power on, sampling the
+    /// light while reading is enabled
+    pub fn tick(&mut self, dots: u64) {
+        self.dots = dots;
+        if self.control & RP_READ_ENABLE != RP_READ_ENABLE {
+            return;
+        }
+        if let Some(device) = self.device.as_mut() {
+            if device.light(dots) {
+                self.last_light = Some(dots);
+            }
+        }
+    }
+
+    /// Save the port itself, without whatever it is pointed at
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.control);
+        writer.write_u64(self.dots);
+        writer.write_option(self.last_light, StateWriter::write_u64);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.control = reader.read_u8()? & (RP_READ_ENABLE | RP_LED);
+        self.dots = reader.read_u64()?;
+        self.last_light = reader.read_option(StateReader::read_u64)?;
+        if self.last_light.is_some_and(|dots| dots > self.dots) {
+            let message = "the light was seen after the time of the state".to_string();
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        Ok(())
+    }
+}
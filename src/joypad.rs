@@ -0,0 +1,202 @@
+use std::io;
+
+use crate::memory::Interrupt;
+use crate::state::{StateReader, StateWriter};
+
+/// Buttons of the Game Boy, with the value of their bit in the pressed mask.
+/// The D-pad is in the lower nibble and the rest of the buttons in the upper
+/// one, in the same order as they appear in P1.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Button {
+    Right = 0x01,
+    Left = 0x02,
+    Up = 0x04,
+    Down = 0x08,
+    A = 0x10,
+    B = 0x20,
+    Select = 0x40,
+    Start = 0x80,
+}
+
+pub const MAX_PLAYERS: usize = 4;
+
+/// Joypad register (P1)
+///
+/// Bits 4 and 5 select the D-pad and the rest of the buttons respectively,
+/// and the lower nibble reads the buttons of the selected groups. All of
+/// them are active low.
+///
+/// The Super Game Boy can read several joypads, one at a time. It moves on
+/// to the next one whenever P15 goes high, and with no group selected, the
+/// lower nibble reads 0xF minus the number of the current one.
+pub struct Joypad {
+    // Group select lines as written, in bits 4 and 5
+    select: u8,
+    // Mask of the buttons currently held on each joypad
+    pressed: [u8; MAX_PLAYERS],
+    // Number of joypads read, and the one being read
+    players: usize,
+    player: usize,
+    // Whether Left+Right and Up+Down can be held at the same time, which a
+    // real D-pad does not allow but some glitches rely on
+    allow_opposite_directions: bool,
+    // Input lines as last read, to detect high to low transitions
+    lines: u8,
+    interrupts: u8,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self {
+            select: 0x30,
+            pressed: [0; MAX_PLAYERS],
+            players: 1,
+            player: 0,
+            allow_opposite_directions: false,
+            lines: 0x0F,
+            interrupts: 0,
+        }
+    }
+
+    pub fn read_register(&self) -> u8 {
+        0xC0 | self.select | self.input_lines()
+    }
+
+    pub fn write_register(&mut self, value: u8) {
+        if self.select & 0x20 == 0 && value & 0x20 != 0 {
+            self.player = (self.player + 1) % self.players;
+        }
+        self.select = value & 0x30;
+        self.update_lines();
+    }
+
+    /// Press a button on the joypad of the given player, counting from 0,
+    /// ignoring players past the last joypad
+    pub fn press(&mut self, player: usize, button: Button) {
+        if let Some(pressed) = self.pressed.get_mut(player) {
+            *pressed |= button as u8;
+            self.update_lines();
+        }
+    }
+
+    pub fn release(&mut self, player: usize, button: Button) {
+        if let Some(pressed) = self.pressed.get_mut(player) {
+            *pressed &= !(button as u8);
+            self.update_lines();
+        }
+    }
+
+    /// Return the mask of the buttons held on each joypad
+    pub fn pressed(&self) -> [u8; MAX_PLAYERS] {
+        self.pressed
+    }
+
+    pub fn set_pressed(&mut self, pressed: [u8; MAX_PLAYERS]) {
+        self.pressed = pressed;
+        self.update_lines();
+    }
+
+    /// Set the number of joypads read, from 1 to 4, starting over from the
+    /// first one
+    pub fn set_players(&mut self, players: usize) {
+        self.players = players.clamp(1, MAX_PLAYERS);
+        self.player = 0;
+        self.update_lines();
+    }
+
+    /// Make the given joypad, counting from 0, the one being read
+    pub fn set_player(&mut self, player: usize) {
+        self.player = player % self.players;
+        self.update_lines();
+    }
+
+    /// Return the number of joypads read, and the one being read
+    pub fn players(&self) -> (usize, usize) {
+        (self.players, self.player)
+    }
+
+    pub fn allow_opposite_directions(&self) -> bool {
+        self.allow_opposite_directions
+    }
+
+    pub fn set_allow_opposite_directions(&mut self, allow: bool) {
+        self.allow_opposite_directions = allow;
+        self.update_lines();
+    }
+
+    /// Whether any of the input lines is low, which wakes the CPU from STOP
+    pub fn any_line_low(&self) -> bool {
+        self.input_lines() != 0x0F
+    }
+
+    /// Return the interrupts requested since the last call, as a mask of IF bits
+    pub fn take_interrupts(&mut self) -> u8 {
+        std::mem::take(&mut self.interrupts)
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.select);
+        writer.write_bytes(&self.pressed);
+        writer.write_u32(self.players as u32);
+        writer.write_u32(self.player as u32);
+        writer.write_u8(self.lines);
+        writer.write_u8(self.interrupts);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.select = reader.read_u8()? & 0x30;
+        reader.read_bytes(&mut self.pressed)?;
+        self.players = reader.read_below(MAX_PLAYERS as u32 + 1)?.max(1) as usize;
+        self.player = reader.read_below(self.players as u32)? as usize;
+        self.lines = reader.read_u8()?;
+        self.interrupts = reader.read_u8()?;
+        Ok(())
+    }
+
+    /// Return the pressed buttons as the hardware sees them. Unless allowed,
+    /// holding two opposite directions reads as holding neither.
+    fn effective_pressed(&self) -> u8 {
+        let mut pressed = self.pressed[self.player];
+        if !self.allow_opposite_directions {
+            for &(a, b) in &[(Button::Left, Button::Right), (Button::Up, Button::Down)] {
+                let both = a as u8 | b as u8;
+                if pressed & both == both {
+                    pressed &= !both;
+                }
+            }
+        }
+        pressed
+    }
+
+    /// Return the lower nibble of P1, with a 0 for each pressed button of
+    /// the selected groups, or the number of the current joypad
+    fn input_lines(&self) -> u8 {
+        if self.players > 1 && self.select == 0x30 {
+            return 0x0F - self.player as u8;
+        }
+        let pressed = self.effective_pressed();
+        let mut low = 0;
+        if self.select & 0x10 == 0 {
+            low |= pressed & 0x0F;
+        }
+        if self.select & 0x20 == 0 {
+            low |= pressed >> 4;
+        }
+        !low & 0x0F
+    }
+
+    /// Request the joypad interrupt if any input line went from high to low
+    fn update_lines(&mut self) {
+        let lines = self.input_lines();
+        if self.lines & !lines != 0 {
+            self.interrupts |= Interrupt::Joypad as u8;
+        }
+        self.lines = lines;
+    }
+}
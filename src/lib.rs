@@ -0,0 +1,27 @@
+pub mod apu;
+pub mod bess;
+pub mod blip;
+pub mod cpu;
+pub mod dma;
+pub mod four_player;
+pub mod gbs;
+pub mod image;
+pub mod infrared;
+pub mod joypad;
+pub mod link;
+pub mod mbc;
+pub mod memory;
+pub mod movie;
+pub mod ppu;
+pub mod printer;
+pub mod register;
+pub mod rewind;
+pub mod serial;
+pub mod serial_log;
+pub mod session;
+pub mod sgb;
+pub mod state;
+pub mod timer;
+pub mod util;
+pub mod vgm;
+pub mod wav;
@@ -0,0 +1,325 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::infrared::InfraredDevice;
+use crate::serial::SerialDevice;
+
+// Messages exchanged over the link, each a kind byte followed by a data
+// byte, and for the infrared port by the dots since power on as well
+const MESSAGE_TRANSFER: u8 = 0x01;
+const MESSAGE_REPLY: u8 = 0x02;
+const MESSAGE_INFRARED: u8 = 0x03;
+const INFRARED_MESSAGE_SIZE: usize = 10;
+
+// How long a transfer waits for the partner to answer before giving up on
+// the byte
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+// Calls to `poll_transfer`, `poll_external` or `light` between actual reads
+// from the socket, so that waiting on the partner or for light does not
+// make a system call every cycle
+const EXTERNAL_POLL_INTERVAL: u32 = 64;
+// Gap between the partner's LED changes that starts a new burst of pulses,
+// whose timing is replayed from when its first change arrives
+const INFRARED_BURST_GAP: u64 = 0x10000;
+
+// Prefix of an address that names a Unix socket rather than a TCP one
+#[cfg(unix)]
+const UNIX_PREFIX: &str = "unix:";
+
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+/// Link cable to another emulator instance over a local socket
+///
+/// The side whose Game Boy drives the clock sends its byte and holds the
+/// transfer until the other side answers with its own, which it only does
+/// once its Game Boy is waiting on the external clock. This keeps both
+/// instances in lockstep at every transfer, while the rest of the machine
+/// keeps running. An answer that does not come in time reads as 0xFF, and
+/// if the partner goes away, the cable behaves as if nothing was plugged
+/// in.
+///
+/// The cable also carries the infrared port, for which each side sends the
+/// changes of its LED along with when they happened. Those are replayed on
+/// the other side with the same timing, so that the pulses keep their
+/// widths however the socket delays them. To carry both, the cable is
+/// shared through an `Rc<RefCell<_>>`.
+pub struct LinkCable {
+    stream: Option<Box<dyn Stream>>,
+    // Bytes received that do not make a whole message yet, and messages
+    // about the serial port received but not handled yet
+    received: Vec<u8>,
+    messages: VecDeque<(u8, u8)>,
+    // Replies to transfers that timed out, to drop when they arrive
+    stale_replies: u32,
+    // When the transfer clocked by this side gives up on its answer
+    reply_deadline: Option<Instant>,
+    external_polls: u32,
+    // Changes of the partner's LED still to replay, in the partner's dots,
+    // and the state of the LED as replayed so far
+    infrared_changes: VecDeque<(u64, bool)>,
+    partner_led: bool,
+    // Dots here and at the partner's at the start of the burst of pulses
+    // being replayed, and of the last change replayed
+    burst_start: (u64, u64),
+    last_change: Option<u64>,
+    infrared_polls: u32,
+}
+
+impl LinkCable {
+    /// Wait for the partner to connect to the given address, which is
+    /// either a TCP address or a Unix socket path prefixed with `unix:`
+    pub fn listen(address: &str) -> Result<Self, io::Error> {
+        Ok(Self::listen_many(address, 1)?.remove(0))
+    }
+
+    /// Wait for the given number of partners to connect to the address, for
+    /// a hub linking several Game Boys
+    pub fn listen_many(address: &str, count: usize) -> Result<Vec<Self>, io::Error> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+                let listener = UnixListener::bind(path)?;
+                return (0..count)
+                    .map(|_| {
+                        let (stream, _) = listener.accept()?;
+                        stream.set_nonblocking(true)?;
+                        Ok(Self::new(Box::new(stream)))
+                    })
+                    .collect();
+            }
+        }
+        let listener = TcpListener::bind(address)?;
+        (0..count)
+            .map(|_| Self::from_tcp(listener.accept()?.0))
+            .collect()
+    }
+
+    /// Connect to a partner listening at the given address
+    pub fn connect(address: &str) -> Result<Self, io::Error> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+                let stream = UnixStream::connect(path)?;
+                stream.set_nonblocking(true)?;
+                return Ok(Self::new(Box::new(stream)));
+            }
+        }
+        Self::from_tcp(TcpStream::connect(address)?)
+    }
+
+    fn from_tcp(stream: TcpStream) -> Result<Self, io::Error> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        Ok(Self::new(Box::new(stream)))
+    }
+
+    fn new(stream: Box<dyn Stream>) -> Self {
+        Self {
+            stream: Some(stream),
+            received: Vec::new(),
+            messages: VecDeque::new(),
+            stale_replies: 0,
+            reply_deadline: None,
+            external_polls: 0,
+            infrared_changes: VecDeque::new(),
+            partner_led: false,
+            burst_start: (0, 0),
+            last_change: None,
+            infrared_polls: 0,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn send(&mut self, mut message: &[u8]) {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => return,
+        };
+        while !message.is_empty() {
+            match stream.write(message) {
+                Ok(0) => return self.disconnect(),
+                Ok(written) => message = &message[written..],
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return self.disconnect(),
+            }
+        }
+    }
+
+    /// Return the next message about the serial port from the partner, if
+    /// a whole one arrived
+    fn receive(&mut self) -> Option<(u8, u8)> {
+        if self.messages.is_empty() {
+            self.read_messages();
+        }
+        self.messages.pop_front()
+    }
+
+    /// Read whatever the partner sent so far, queueing the messages about
+    /// the serial port for `receive` and the changes of the LED for `light`
+    fn read_messages(&mut self) {
+        while let Some(stream) = self.stream.as_mut() {
+            let mut buffer = [0; 64];
+            match stream.read(&mut buffer) {
+                Ok(0) => return self.disconnect(),
+                Ok(read) => {
+                    self.received.extend_from_slice(&buffer[..read]);
+                    break;
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return self.disconnect(),
+            }
+        }
+        let mut offset = 0;
+        while let Some(&kind) = self.received.get(offset) {
+            let size = match kind {
+                MESSAGE_INFRARED => INFRARED_MESSAGE_SIZE,
+                _ => 2,
+            };
+            if self.received.len() < offset + size {
+                break;
+            }
+            let message = &self.received[offset..offset + size];
+            match kind {
+                MESSAGE_INFRARED => {
+                    let mut dots = [0; 8];
+                    dots.copy_from_slice(&message[2..]);
+                    let change = (u64::from_le_bytes(dots), message[1] != 0);
+                    self.infrared_changes.push_back(change);
+                }
+                MESSAGE_REPLY if self.stale_replies > 0 => self.stale_replies -= 1,
+                _ => self.messages.push_back((kind, message[1])),
+            }
+            offset += size;
+        }
+        self.received.drain(..offset);
+    }
+
+    /// Start a transfer clocked by this side, with the partner's answer to
+    /// come from `poll_reply`
+    pub(crate) fn send_transfer(&mut self, outgoing: u8) {
+        self.send(&[MESSAGE_TRANSFER, outgoing]);
+    }
+
+    /// Return the partner's answer to the transfer in progress, once it
+    /// arrived
+    pub(crate) fn poll_reply(&mut self) -> Option<u8> {
+        loop {
+            match self.receive()? {
+                (MESSAGE_REPLY, incoming) => return Some(incoming),
+                // Both sides started a transfer at the same time, so each
+                // takes the other's byte as the answer
+                (MESSAGE_TRANSFER, incoming) => return Some(incoming),
+                _ => {}
+            }
+        }
+    }
+
+    /// Give up on the answer to the transfer in progress, dropping it if it
+    /// arrives later
+    pub(crate) fn abandon_reply(&mut self) {
+        self.stale_replies += 1;
+    }
+
+    fn disconnect(&mut self) {
+        self.stream = None;
+        self.received.clear();
+        self.messages.clear();
+        self.infrared_changes.clear();
+        self.partner_led = false;
+    }
+}
+
+impl SerialDevice for LinkCable {
+    fn transfer(&mut self, outgoing: u8) -> Option<u8> {
+        self.send_transfer(outgoing);
+        self.reply_deadline = Some(Instant::now() + REPLY_TIMEOUT);
+        self.external_polls = EXTERNAL_POLL_INTERVAL;
+        self.poll_transfer()
+    }
+
+    fn poll_transfer(&mut self) -> Option<u8> {
+        self.external_polls += 1;
+        if self.external_polls < EXTERNAL_POLL_INTERVAL {
+            return None;
+        }
+        self.external_polls = 0;
+        let incoming = match self.poll_reply() {
+            Some(incoming) => incoming,
+            None if !self.is_connected() => 0xFF,
+            None if self
+                .reply_deadline
+                .is_some_and(|deadline| Instant::now() < deadline) =>
+            {
+                return None
+            }
+            None => {
+                self.abandon_reply();
+                0xFF
+            }
+        };
+        self.reply_deadline = None;
+        Some(incoming)
+    }
+
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        self.external_polls += 1;
+        if self.external_polls < EXTERNAL_POLL_INTERVAL {
+            return None;
+        }
+        self.external_polls = 0;
+        match self.receive()? {
+            (MESSAGE_TRANSFER, incoming) => {
+                self.send(&[MESSAGE_REPLY, outgoing]);
+                Some(incoming)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl InfraredDevice for LinkCable {
+    fn set_led(&mut self, on: bool, dots: u64) {
+        let mut message = [0; INFRARED_MESSAGE_SIZE];
+        message[0] = MESSAGE_INFRARED;
+        message[1] = on as u8;
+        message[2..].copy_from_slice(&dots.to_le_bytes());
+        self.send(&message);
+    }
+
+    fn light(&mut self, dots: u64) -> bool {
+        self.infrared_polls += 1;
+        if self.infrared_polls >= EXTERNAL_POLL_INTERVAL {
+            self.infrared_polls = 0;
+            self.read_messages();
+        }
+        while let Some(&(partner_dots, on)) = self.infrared_changes.front() {
+            let new_burst = self
+                .last_change
+                .is_none_or(|last| partner_dots.wrapping_sub(last) > INFRARED_BURST_GAP);
+            if new_burst {
+                self.burst_start = (dots, partner_dots);
+            }
+            let (start, partner_start) = self.burst_start;
+            if start + (partner_dots - partner_start) > dots {
+                break;
+            }
+            self.infrared_changes.pop_front();
+            self.last_change = Some(partner_dots);
+            self.partner_led = on;
+        }
+        self.partner_led
+    }
+}
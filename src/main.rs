@@ -1,15 +1,37 @@
-mod cpu;
-mod memory;
-mod register;
-mod util;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::cell::RefCell;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use clap::{App, Arg};
-use std::path::Path;
+use tonzoboy::apu::Channel;
+use tonzoboy::cpu::Cpu;
+use tonzoboy::four_player::{FourPlayerHub, MAX_PLAYERS};
+use tonzoboy::gbs::Gbs;
+use tonzoboy::image::{half_size, write_ppm};
+use tonzoboy::link::LinkCable;
+use tonzoboy::memory::Model;
+use tonzoboy::movie::{InputScript, Mode, Movie, MoviePlayer, Start};
+use tonzoboy::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use tonzoboy::printer::Printer;
+use tonzoboy::serial_log::{SerialLog, SerialReplay};
+use tonzoboy::wav::WavWriter;
 
-use cpu::Cpu;
+/// Sample rates the audio can be recorded at
+const SAMPLE_RATES: RangeInclusive<u32> = 8000..=192_000;
+
+// Number of frames between the hashes of a recorded movie, one a second
+const MOVIE_HASH_INTERVAL: u32 = 60;
 
 fn main() {
     let matches = App::new("tonzoboy")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("file")
                 .short("f")
@@ -17,8 +39,542 @@ fn main() {
                 .index(1)
                 .help("Path of the ROM file to load"),
         )
+        .arg(
+            Arg::with_name("model")
+                .long("model")
+                .takes_value(true)
+                .possible_values(&["dmg", "cgb", "sgb"])
+                .help("Hardware to emulate [default: the one the game is for]"),
+        )
+        .args(&recording_args())
+        .arg(
+            Arg::with_name("load-slot")
+                .long("load-slot")
+                .takes_value(true)
+                .help("Start from the state saved in the given slot, from 0 to 9"),
+        )
+        .arg(
+            Arg::with_name("save-slot")
+                .long("save-slot")
+                .takes_value(true)
+                .conflicts_with_all(&["wav", "vgm"])
+                .help("Run headless, then save the state and a thumbnail to the given slot, from 0 to 9"),
+        )
+        .arg(
+            Arg::with_name("import-bess")
+                .long("import-bess")
+                .takes_value(true)
+                .conflicts_with("load-slot")
+                .help("Start from a BESS save state, such as one saved by another emulator"),
+        )
+        .arg(
+            Arg::with_name("export-bess")
+                .long("export-bess")
+                .takes_value(true)
+                .conflicts_with_all(&["wav", "vgm"])
+                .help("Run headless, then export the state to the given BESS file"),
+        )
+        .arg(
+            Arg::with_name("record-movie")
+                .long("record-movie")
+                .takes_value(true)
+                .conflicts_with_all(&["wav", "vgm", "save-slot", "export-bess"])
+                .help("Run headless and record a movie of the run to the given file"),
+        )
+        .arg(
+            Arg::with_name("movie-sram")
+                .long("movie-sram")
+                .takes_value(true)
+                .requires("record-movie")
+                .conflicts_with_all(&["load-slot", "import-bess"])
+                .help("Start the movie from power on with the cartridge RAM of the given battery save"),
+        )
+        .arg(
+            Arg::with_name("movie-input")
+                .long("movie-input")
+                .takes_value(true)
+                .help("Hold the buttons of the given input script while recording the movie"),
+        )
+        .arg(
+            Arg::with_name("play-movie")
+                .long("play-movie")
+                .takes_value(true)
+                .conflicts_with_all(&[
+                    "wav",
+                    "vgm",
+                    "save-slot",
+                    "export-bess",
+                    "record-movie",
+                    "load-slot",
+                    "import-bess",
+                ])
+                .help("Play the given movie back headless, checking that it stays in sync"),
+        )
+        .arg(
+            Arg::with_name("movie-mode")
+                .long("movie-mode")
+                .takes_value(true)
+                .possible_values(&["read-only", "read-write"])
+                .requires("play-movie")
+                .help("Play the movie back as is, or record over it past its end until --frames [default: read-only]"),
+        )
+        .arg(
+            Arg::with_name("link-listen")
+                .long("link-listen")
+                .takes_value(true)
+                .conflicts_with("link-connect")
+                .help("Wait for another instance to link up at the given TCP address or unix:<path>"),
+        )
+        .arg(
+            Arg::with_name("link-connect")
+                .long("link-connect")
+                .takes_value(true)
+                .help("Link up with another instance listening at the given TCP address or unix:<path>"),
+        )
+        .arg(
+            Arg::with_name("printer")
+                .long("printer")
+                .takes_value(true)
+                .conflicts_with_all(&["link-listen", "link-connect"])
+                .help("Plug in a Game Boy Printer, saving the paper to the given PGM file"),
+        )
+        .arg(
+            Arg::with_name("serial-replay")
+                .long("serial-replay")
+                .takes_value(true)
+                .conflicts_with_all(&["link-listen", "link-connect", "printer"])
+                .help("Plug in a fake link partner replaying the given serial log or script"),
+        )
+        .arg(
+            Arg::with_name("serial-log")
+                .long("serial-log")
+                .takes_value(true)
+                .help("Log every byte exchanged over the link port to the given file"),
+        )
+        .subcommand(
+            SubCommand::with_name("play-gbs")
+                .about("Play a track of a GBS music file, recording it headless")
+                .arg(
+                    Arg::with_name("file")
+                        .required(true)
+                        .index(1)
+                        .help("Path of the GBS file to play"),
+                )
+                .arg(
+                    Arg::with_name("track")
+                        .long("track")
+                        .takes_value(true)
+                        .help("Track to play, counting from 1 [default: the file's first track]"),
+                )
+                .args(&recording_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("four-player-hub")
+                .about("Act as a Four Player Adapter for instances linking up with --link-connect")
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .takes_value(true)
+                        .required(true)
+                        .help("TCP address or unix:<path> to wait for the players at"),
+                )
+                .arg(
+                    Arg::with_name("players")
+                        .long("players")
+                        .takes_value(true)
+                        .default_value("4")
+                        .help("Number of players to wait for, from 1 to 4"),
+                ),
+        )
         .get_matches();
+    if let Some(matches) = matches.subcommand_matches("play-gbs") {
+        play_gbs(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("four-player-hub") {
+        four_player_hub(matches);
+        return;
+    }
     let rom_path = Path::new(matches.value_of("file").unwrap());
-    let mut cpu = Cpu::new(&rom_path);
-    cpu.run()
+    let new_cpu = || {
+        let mut cpu = Cpu::new(rom_path);
+        match matches.value_of("model") {
+            Some("dmg") => cpu.set_model(Model::Dmg),
+            Some("cgb") => cpu.set_model(Model::Cgb),
+            Some("sgb") => cpu.set_model(Model::Sgb),
+            _ => {}
+        }
+        if let Some(slot) = matches.value_of("load-slot") {
+            let path = slot_path(rom_path, parse_slot(slot), "");
+            if let Err(error) = fs::read(&path).and_then(|state| cpu.load_state(&state)) {
+                eprintln!("Failed to load {}: {}", path.display(), error);
+                process::exit(1);
+            }
+        }
+        if let Some(path) = matches.value_of("import-bess").map(Path::new) {
+            if let Err(error) = fs::read(path).and_then(|state| cpu.load_bess(&state)) {
+                eprintln!("Failed to import {}: {}", path.display(), error);
+                process::exit(1);
+            }
+        }
+        cpu
+    };
+    if matches.is_present("save-slot") || matches.is_present("export-bess") {
+        let frames = parse_number(matches.value_of("frames").unwrap(), "frames");
+        let mut cpu = new_cpu();
+        cpu.run_frames(frames);
+        if let Some(slot) = matches.value_of("save-slot").map(parse_slot) {
+            if let Err(error) = save_slot(&cpu, rom_path, slot) {
+                eprintln!("Failed to save slot {}: {}", slot, error);
+                process::exit(1);
+            }
+        }
+        if let Some(path) = matches.value_of("export-bess").map(Path::new) {
+            if let Err(error) = fs::write(path, cpu.save_bess()) {
+                eprintln!("Failed to export {}: {}", path.display(), error);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    let recording_movie =
+        matches.is_present("record-movie") || matches.value_of("movie-mode") == Some("read-write");
+    if matches.is_present("movie-input") && !recording_movie {
+        eprintln!("An input script needs --record-movie, or --movie-mode read-write");
+        process::exit(1);
+    }
+    if let Some(path) = matches.value_of("record-movie").map(Path::new) {
+        let frames = parse_number(matches.value_of("frames").unwrap(), "frames");
+        let start = if matches.is_present("load-slot") || matches.is_present("import-bess") {
+            Start::State(new_cpu().save_state())
+        } else if let Some(sram_path) = matches.value_of("movie-sram") {
+            Start::SaveRam(fs::read(sram_path).unwrap_or_else(|error| {
+                eprintln!("Failed to load {}: {}", sram_path, error);
+                process::exit(1);
+            }))
+        } else {
+            Start::PowerOn
+        };
+        // The clock of cartridges that have one starts at the current time
+        let rtc_seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        let script = load_input_script(&matches);
+        let mut cpu = new_cpu();
+        let result = MoviePlayer::record(&mut cpu, start, rtc_seed, MOVIE_HASH_INTERVAL).and_then(
+            |mut player| {
+                for frame in 0..frames as usize {
+                    let pressed = script.as_ref().map(|script| script.pressed(frame));
+                    player.run_frame(&mut cpu, pressed)?;
+                }
+                fs::write(path, player.movie().to_bytes())
+            },
+        );
+        if let Err(error) = result {
+            eprintln!("Failed to record {}: {}", path.display(), error);
+            process::exit(1);
+        }
+        return;
+    }
+    if let Some(path) = matches.value_of("play-movie").map(Path::new) {
+        if matches.value_of("movie-mode") == Some("read-write") {
+            let frames = parse_number(matches.value_of("frames").unwrap(), "frames");
+            rerecord_movie(rom_path, path, frames, load_input_script(&matches));
+        } else {
+            play_movie(rom_path, path);
+        }
+        return;
+    }
+    if !record(&matches, &new_cpu) {
+        let mut cpu = new_cpu();
+        let link = match (
+            matches.value_of("link-listen"),
+            matches.value_of("link-connect"),
+        ) {
+            (Some(address), _) => Some((address, LinkCable::listen(address))),
+            (_, Some(address)) => Some((address, LinkCable::connect(address))),
+            _ => None,
+        };
+        if let Some((address, link)) = link {
+            match link {
+                Ok(link) => {
+                    // The cable carries the infrared port along with the
+                    // serial one
+                    let link = Rc::new(RefCell::new(link));
+                    cpu.set_serial_device(Some(Box::new(Rc::clone(&link))));
+                    cpu.set_infrared_device(Some(Box::new(link)));
+                }
+                Err(error) => {
+                    eprintln!("Failed to link up at {}: {}", address, error);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(path) = matches.value_of("printer").map(Path::new) {
+            match Printer::create(path) {
+                Ok(printer) => cpu.set_serial_device(Some(Box::new(printer))),
+                Err(error) => {
+                    eprintln!("Failed to create {}: {}", path.display(), error);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(path) = matches.value_of("serial-replay").map(Path::new) {
+            match SerialReplay::load(path) {
+                Ok(replay) => cpu.set_serial_device(Some(Box::new(replay))),
+                Err(error) => {
+                    eprintln!("Failed to load {}: {}", path.display(), error);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(path) = matches.value_of("serial-log").map(Path::new) {
+            match SerialLog::create(path) {
+                Ok(log) => cpu.set_serial_log(Some(log)),
+                Err(error) => {
+                    eprintln!("Failed to create {}: {}", path.display(), error);
+                    process::exit(1);
+                }
+            }
+        }
+        cpu.run()
+    }
+}
+
+/// Options of a headless run that records the audio
+fn recording_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("wav")
+            .long("wav")
+            .takes_value(true)
+            .help("Run headless and record the audio to the given WAV file"),
+        Arg::with_name("vgm")
+            .long("vgm")
+            .takes_value(true)
+            .help("Run headless and log the writes to the sound registers to the given VGM file"),
+        Arg::with_name("stems")
+            .long("stems")
+            .requires("wav")
+            .help("Also record each sound channel to a WAV file of its own"),
+        Arg::with_name("frames")
+            .long("frames")
+            .takes_value(true)
+            .default_value("3600")
+            .help("Number of frames to run for when headless"),
+        Arg::with_name("sample-rate")
+            .long("sample-rate")
+            .takes_value(true)
+            .default_value("44100")
+            .help("Sample rate of the recorded audio"),
+    ]
+}
+
+fn play_gbs(matches: &ArgMatches) {
+    let gbs_path = Path::new(matches.value_of("file").unwrap());
+    let gbs = Gbs::load(gbs_path).unwrap_or_else(|error| {
+        eprintln!("Failed to load {}: {}", gbs_path.display(), error);
+        process::exit(1);
+    });
+    let track = match matches.value_of("track") {
+        Some(track) => parse_number(track, "track"),
+        None => gbs.first_song as u32,
+    };
+    if track == 0 || track > gbs.song_count as u32 {
+        eprintln!("Invalid track {}, the file has {}", track, gbs.song_count);
+        process::exit(1);
+    }
+    let rom = gbs.rom_image(track as u8 - 1);
+    if !record(matches, &|| Cpu::from_rom(rom.clone())) {
+        // There is no audio output other than the recordings
+        eprintln!("Nothing to play to, pass --wav or --vgm");
+        process::exit(1);
+    }
+}
+
+/// Load the input script given to hold the buttons of a movie, if any
+fn load_input_script(matches: &ArgMatches) -> Option<InputScript> {
+    matches.value_of("movie-input").map(|script_path| {
+        InputScript::load(Path::new(script_path)).unwrap_or_else(|error| {
+            eprintln!("Failed to load {}: {}", script_path, error);
+            process::exit(1);
+        })
+    })
+}
+
+/// Start playing a movie back in the given mode, exiting with an error if
+/// it cannot be
+fn start_movie(cpu: &mut Cpu, movie_path: &Path, mode: Mode) -> MoviePlayer {
+    let result = fs::read(movie_path)
+        .and_then(|data| Movie::parse(&data))
+        .and_then(|movie| MoviePlayer::play(cpu, movie, mode));
+    result.unwrap_or_else(|error| {
+        eprintln!("Failed to play {}: {}", movie_path.display(), error);
+        process::exit(1);
+    })
+}
+
+/// Play a movie back read-only, exiting with an error if it desyncs
+fn play_movie(rom_path: &Path, movie_path: &Path) {
+    let mut cpu = Cpu::new(rom_path);
+    let mut player = start_movie(&mut cpu, movie_path, Mode::ReadOnly);
+    while !player.is_finished() {
+        if let Err(error) = player.run_frame(&mut cpu, None) {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+    }
+    println!("Played {} frames in sync", player.frame());
+}
+
+/// Play a movie back read-write, recording past its end up to the given
+/// number of frames with the buttons of the input script, and save it over
+/// the original
+fn rerecord_movie(rom_path: &Path, movie_path: &Path, frames: u32, script: Option<InputScript>) {
+    let mut cpu = Cpu::new(rom_path);
+    let mut player = start_movie(&mut cpu, movie_path, Mode::ReadWrite);
+    let result = (0..frames as usize)
+        .try_for_each(|frame| {
+            let pressed = match player.mode() {
+                Mode::Recording => script.as_ref().map(|script| script.pressed(frame)),
+                Mode::ReadOnly | Mode::ReadWrite => None,
+            };
+            player.run_frame(&mut cpu, pressed)
+        })
+        .and_then(|_| fs::write(movie_path, player.movie().to_bytes()));
+    if let Err(error) = result {
+        eprintln!("Failed to record {}: {}", movie_path.display(), error);
+        process::exit(1);
+    }
+    println!("Recorded {} frames", player.movie().frames.len());
+}
+
+fn four_player_hub(matches: &ArgMatches) {
+    let address = matches.value_of("listen").unwrap();
+    let players = parse_number(matches.value_of("players").unwrap(), "player count") as usize;
+    if players == 0 || players > MAX_PLAYERS {
+        eprintln!("Invalid player count {}, the adapter takes 1 to 4", players);
+        process::exit(1);
+    }
+    let cables = LinkCable::listen_many(address, players).unwrap_or_else(|error| {
+        eprintln!("Failed to link up at {}: {}", address, error);
+        process::exit(1);
+    });
+    FourPlayerHub::new(cables).run();
+}
+
+/// Make the headless recordings asked for, each on a CPU from the given
+/// function, returning whether there were any
+fn record(matches: &ArgMatches, new_cpu: &dyn Fn() -> Cpu) -> bool {
+    let wav_path = matches.value_of("wav").map(Path::new);
+    let vgm_path = matches.value_of("vgm").map(Path::new);
+    if wav_path.is_none() && vgm_path.is_none() {
+        return false;
+    }
+    let frames = parse_number(matches.value_of("frames").unwrap(), "frames");
+    let sample_rate = parse_number(matches.value_of("sample-rate").unwrap(), "sample rate");
+    if !SAMPLE_RATES.contains(&sample_rate) {
+        eprintln!(
+            "Invalid sample rate: {}, it must be between {} and {}",
+            sample_rate,
+            SAMPLE_RATES.start(),
+            SAMPLE_RATES.end()
+        );
+        process::exit(1);
+    }
+    let mut runs = vec![(wav_path.map(Path::to_path_buf), vgm_path, None)];
+    if let (Some(wav_path), true) = (wav_path, matches.is_present("stems")) {
+        // The emulation is deterministic, so each stem is recorded on a
+        // run of its own with its channel soloed
+        for &channel in &Channel::ALL {
+            runs.push((Some(stem_path(wav_path, channel)), None, Some(channel)));
+        }
+    }
+    for (wav_path, vgm_path, solo) in runs {
+        let recording = Recording {
+            frames,
+            sample_rate,
+            wav_path: wav_path.as_deref(),
+            vgm_path,
+            solo,
+        };
+        if let Err(error) = recording.run(new_cpu()) {
+            eprintln!("Failed to record the audio: {}", error);
+            process::exit(1);
+        }
+    }
+    true
+}
+
+fn parse_number(value: &str, name: &str) -> u32 {
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("Invalid {}: {}", name, value);
+        process::exit(1);
+    })
+}
+
+fn parse_slot(value: &str) -> u32 {
+    let slot = parse_number(value, "slot");
+    if slot > 9 {
+        eprintln!("Invalid slot {}, there are 10 from 0 to 9", slot);
+        process::exit(1);
+    }
+    slot
+}
+
+/// Return the path of a save state slot, next to the ROM, or of its
+/// thumbnail with a `.ppm` suffix
+fn slot_path(rom_path: &Path, slot: u32, suffix: &str) -> PathBuf {
+    rom_path.with_extension(format!("ss{}{}", slot, suffix))
+}
+
+/// Save the state to a slot, along with a thumbnail of the screen
+fn save_slot(cpu: &Cpu, rom_path: &Path, slot: u32) -> Result<(), io::Error> {
+    fs::write(slot_path(rom_path, slot, ""), cpu.save_state())?;
+    let thumbnail = half_size(SCREEN_WIDTH, SCREEN_HEIGHT, cpu.framebuffer());
+    let mut writer = BufWriter::new(File::create(slot_path(rom_path, slot, ".ppm"))?);
+    write_ppm(&mut writer, SCREEN_WIDTH / 2, SCREEN_HEIGHT / 2, &thumbnail)?;
+    writer.flush()
+}
+
+/// Return the path of a channel's stem, next to the mixed recording
+fn stem_path(wav_path: &Path, channel: Channel) -> PathBuf {
+    let stem = wav_path.file_stem().unwrap_or_default().to_string_lossy();
+    let suffix = match channel {
+        Channel::Pulse1 => "pulse1",
+        Channel::Pulse2 => "pulse2",
+        Channel::Wave => "wave",
+        Channel::Noise => "noise",
+    };
+    wav_path.with_file_name(format!("{}-{}.wav", stem, suffix))
+}
+
+/// Headless run for a number of frames, recording the audio
+struct Recording<'a> {
+    frames: u32,
+    sample_rate: u32,
+    wav_path: Option<&'a Path>,
+    vgm_path: Option<&'a Path>,
+    // Channel to record on its own
+    solo: Option<Channel>,
+}
+
+impl Recording<'_> {
+    fn run(&self, mut cpu: Cpu) -> Result<(), io::Error> {
+        if let Some(path) = self.wav_path {
+            let wav = WavWriter::create(path, self.sample_rate)?;
+            cpu.set_audio_sink(Box::new(wav), self.sample_rate);
+        }
+        if self.vgm_path.is_some() {
+            cpu.start_vgm_log();
+        }
+        if let Some(channel) = self.solo {
+            cpu.set_channel_soloed(channel, true);
+        }
+        cpu.run_frames(self.frames);
+        cpu.finish_audio()?;
+        match (self.vgm_path, cpu.take_vgm_log()) {
+            (Some(path), Some(mut vgm)) => vgm.save(path),
+            _ => Ok(()),
+        }
+    }
 }
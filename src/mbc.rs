@@ -0,0 +1,448 @@
+use std::io;
+
+use crate::state::{StateReader, StateWriter};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+// The real time clock counts emulated time, so that runs are reproducible
+const DOTS_PER_SECOND: u32 = 4_194_304;
+// Bits of the RTC registers that are stored, for seconds, minutes, hours,
+// the lower 8 bits of the day counter, and the upper register that holds
+// the 9th bit of the day counter along with the halt and carry flags
+const RTC_MASKS: [u8; 5] = [0x3F, 0x3F, 0x1F, 0xFF, 0xC1];
+const RTC_DAY_HIGH: u8 = 0x01;
+const RTC_HALT: u8 = 0x40;
+const RTC_DAY_CARRY: u8 = 0x80;
+
+/// Memory bank controller of a cartridge, as given by its type in the
+/// header at 0x147
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    /// No controller, for up to 32 KiB of ROM and 8 KiB of RAM. Types the
+    /// emulator has no controller for are also run this way.
+    RomOnly,
+    /// Up to 2 MiB of ROM and 32 KiB of RAM, with the 2 bit register
+    /// selecting either the RAM bank or the upper bits of the ROM bank
+    Mbc1,
+    /// Up to 2 MiB of ROM and 32 KiB of RAM, and a real time clock on some
+    Mbc3,
+    /// Up to 8 MiB of ROM and 128 KiB of RAM
+    Mbc5,
+}
+
+impl Kind {
+    /// Decode the cartridge type, returning whether it has a real time clock
+    fn from_header(cartridge_type: u8) -> (Self, bool) {
+        match cartridge_type {
+            0x01..=0x03 => (Kind::Mbc1, false),
+            0x0F | 0x10 => (Kind::Mbc3, true),
+            0x11..=0x13 => (Kind::Mbc3, false),
+            0x19..=0x1E => (Kind::Mbc5, false),
+            _ => (Kind::RomOnly, false),
+        }
+    }
+}
+
+/// Real time clock of MBC3 cartridges
+///
+/// The registers are mapped in place of the RAM by selecting 0x08-0x0C as
+/// the RAM bank. Reads go to a copy latched by writing 0 and then 1 to
+/// 0x6000-0x7FFF, while writes go to the running clock.
+struct Rtc {
+    registers: [u8; 5],
+    latched: [u8; 5],
+    // Dots into the current second
+    dots: u32,
+    // Whether 0 was written to the latch register, so that 1 latches next
+    latch_armed: bool,
+}
+
+impl Rtc {
+    fn new() -> Self {
+        Self {
+            registers: [0; 5],
+            latched: [0; 5],
+            dots: 0,
+            latch_armed: false,
+        }
+    }
+
+    fn tick(&mut self, dots: u32) {
+        if self.registers[4] & RTC_HALT != 0 {
+            return;
+        }
+        self.dots += dots;
+        while self.dots >= DOTS_PER_SECOND {
+            self.dots -= DOTS_PER_SECOND;
+            self.advance_second();
+        }
+    }
+
+    fn advance_second(&mut self) {
+        let registers = &mut self.registers;
+        if !increment(&mut registers[0], 60, RTC_MASKS[0])
+            || !increment(&mut registers[1], 60, RTC_MASKS[1])
+            || !increment(&mut registers[2], 24, RTC_MASKS[2])
+        {
+            return;
+        }
+        let day = (registers[4] as u16 & RTC_DAY_HIGH as u16) << 8 | registers[3] as u16;
+        let day = day + 1;
+        registers[3] = day as u8;
+        registers[4] = registers[4] & !RTC_DAY_HIGH | (day >> 8) as u8 & RTC_DAY_HIGH;
+        if day > 0x1FF {
+            registers[4] |= RTC_DAY_CARRY;
+        }
+    }
+
+    fn write_latch(&mut self, value: u8) {
+        if self.latch_armed && value == 0x01 {
+            self.latched = self.registers;
+        }
+        self.latch_armed = value == 0x00;
+    }
+
+    fn write_register(&mut self, index: usize, value: u8) {
+        self.registers[index] = value & RTC_MASKS[index];
+        // Writing the seconds restarts the second in progress
+        if index == 0 {
+            self.dots = 0;
+        }
+    }
+}
+
+/// Increment a counter of the clock, returning whether it carries into the
+/// next one. Values past the limit, which can be written, wrap around at
+/// the width of the register without carrying.
+fn increment(counter: &mut u8, limit: u8, mask: u8) -> bool {
+    *counter = counter.wrapping_add(1) & mask;
+    if *counter == limit {
+        *counter = 0;
+        true
+    } else {
+        false
+    }
+}
+
+/// Cartridge hardware besides the ROM: the memory bank controller, the RAM
+/// and the real time clock
+pub struct Mbc {
+    kind: Kind,
+    rom_banks: usize,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    // ROM bank register, of 5 bits on MBC1, 7 on MBC3 and 9 on MBC5
+    rom_bank: u16,
+    // RAM bank register, which also holds the upper bits of the ROM bank on
+    // MBC1 and selects the RTC registers on MBC3
+    ram_bank: u8,
+    // MBC1 mode in which the 2 bit register also banks 0x0000-0x3FFF and
+    // the RAM
+    advanced_banking: bool,
+    rtc: Option<Rtc>,
+}
+
+impl Mbc {
+    /// Create the controller described by the header of the given ROM
+    pub fn new(rom: &[u8]) -> Self {
+        let header = |address: usize| rom.get(address).copied().unwrap_or(0);
+        let (kind, has_rtc) = Kind::from_header(header(0x147));
+        let ram_size = match (kind, header(0x149)) {
+            (_, 0x01) => 0x800,
+            (Kind::RomOnly, 0x02..=0x05) => RAM_BANK_SIZE,
+            (_, 0x02) => RAM_BANK_SIZE,
+            (_, 0x03) => RAM_BANK_SIZE * 4,
+            (_, 0x04) => RAM_BANK_SIZE * 16,
+            (_, 0x05) => RAM_BANK_SIZE * 8,
+            _ => 0,
+        };
+        Self {
+            kind,
+            rom_banks: rom.len().div_ceil(ROM_BANK_SIZE).max(2),
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            advanced_banking: false,
+            rtc: if has_rtc { Some(Rtc::new()) } else { None },
+        }
+    }
+
+    /// Return the offset into the ROM of an address in 0x0000-0x7FFF
+    pub fn rom_offset(&self, address: u16) -> usize {
+        let bank = match (self.kind, address) {
+            (Kind::Mbc1, 0x0000..=0x3FFF) if self.advanced_banking => (self.ram_bank as usize) << 5,
+            (_, 0x0000..=0x3FFF) => 0,
+            (Kind::RomOnly, _) => 1,
+            (Kind::Mbc1, _) => (self.ram_bank as usize) << 5 | self.rom_bank.max(1) as usize,
+            (Kind::Mbc3, _) => self.rom_bank.max(1) as usize,
+            (Kind::Mbc5, _) => self.rom_bank as usize,
+        };
+        // Cartridges do not decode the bank bits past the size of the ROM
+        (bank % self.rom_banks) * ROM_BANK_SIZE + (address as usize & 0x3FFF)
+    }
+
+    /// Write to the registers of the controller at 0x0000-0x7FFF
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match (self.kind, address) {
+            (Kind::RomOnly, _) => {}
+            (_, 0x0000..=0x1FFF) => self.ram_enabled = value & 0x0F == 0x0A,
+            (Kind::Mbc1, 0x2000..=0x3FFF) => self.rom_bank = (value & 0x1F) as u16,
+            (Kind::Mbc3, 0x2000..=0x3FFF) => self.rom_bank = (value & 0x7F) as u16,
+            (Kind::Mbc5, 0x2000..=0x2FFF) => self.rom_bank = self.rom_bank & 0x100 | value as u16,
+            (Kind::Mbc5, 0x3000..=0x3FFF) => {
+                self.rom_bank = self.rom_bank & 0xFF | (value as u16 & 0x01) << 8
+            }
+            (Kind::Mbc1, 0x4000..=0x5FFF) => self.ram_bank = value & 0x03,
+            (_, 0x4000..=0x5FFF) => self.ram_bank = value & 0x0F,
+            (Kind::Mbc1, _) => self.advanced_banking = value & 0x01 != 0,
+            (Kind::Mbc3, _) => {
+                if let Some(rtc) = self.rtc.as_mut() {
+                    rtc.write_latch(value);
+                }
+            }
+            (Kind::Mbc5, _) => {}
+        }
+    }
+
+    /// Read from the RAM or the RTC registers mapped at 0xA000-0xBFFF
+    pub fn read_ram(&self, address: u16) -> u8 {
+        if let Some(index) = self.rtc_register() {
+            return self.rtc.as_ref().map_or(0xFF, |rtc| rtc.latched[index]);
+        }
+        match self.ram_offset(address) {
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        if let Some(index) = self.rtc_register() {
+            if let Some(rtc) = self.rtc.as_mut() {
+                rtc.write_register(index, value);
+            }
+        } else if let Some(offset) = self.ram_offset(address) {
+            self.ram[offset] = value;
+        }
+    }
+
+    /// Return the index of the RTC register selected in place of the RAM
+    fn rtc_register(&self) -> Option<usize> {
+        match (self.kind, self.ram_bank) {
+            (Kind::Mbc3, bank @ 0x08..=0x0C) if self.ram_enabled => Some(bank as usize - 0x08),
+            _ => None,
+        }
+    }
+
+    /// Return the offset into the RAM of an address in 0xA000-0xBFFF, if
+    /// there is RAM there and it is enabled
+    fn ram_offset(&self, address: u16) -> Option<usize> {
+        if self.ram.is_empty() || !self.ram_enabled && self.kind != Kind::RomOnly {
+            return None;
+        }
+        let bank = match self.kind {
+            Kind::RomOnly => 0,
+            Kind::Mbc1 if self.advanced_banking => self.ram_bank as usize,
+            Kind::Mbc1 => 0,
+            Kind::Mbc3 if self.ram_bank > 0x03 => return None,
+            Kind::Mbc3 | Kind::Mbc5 => self.ram_bank as usize,
+        };
+        Some((bank * RAM_BANK_SIZE + (address as usize & 0x1FFF)) % self.ram.len())
+    }
+
+    /// Advance the real time clock, if any, by the given number of dots
+    pub fn tick(&mut self, dots: u32) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.tick(dots);
+        }
+    }
+
+    /// Return the RAM of the cartridge, which is empty if it has none
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    /// Fill the RAM with the given data, the rest of it being zeros if it
+    /// is shorter
+    pub fn load_ram(&mut self, data: &[u8]) {
+        let length = data.len().min(self.ram.len());
+        self.ram.iter_mut().for_each(|byte| *byte = 0);
+        self.ram[..length].copy_from_slice(&data[..length]);
+    }
+
+    /// Return the running and latched registers of the real time clock, if
+    /// the cartridge has one
+    pub fn rtc_registers(&self) -> Option<([u8; 5], [u8; 5])> {
+        self.rtc.as_ref().map(|rtc| (rtc.registers, rtc.latched))
+    }
+
+    /// Set the running and latched registers of the real time clock, if
+    /// the cartridge has one, starting the second in progress over
+    pub fn set_rtc_registers(&mut self, registers: [u8; 5], latched: [u8; 5]) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            for (i, mask) in RTC_MASKS.iter().enumerate() {
+                rtc.registers[i] = registers[i] & mask;
+                rtc.latched[i] = latched[i] & mask;
+            }
+            rtc.dots = 0;
+        }
+    }
+
+    /// Set the real time clock, if the cartridge has one, to the given number
+    /// of seconds, the day counter wrapping around every 512 days
+    pub fn set_rtc_seconds(&mut self, seconds: u64) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            let days = seconds / 86400 % 0x200;
+            rtc.registers = [
+                (seconds % 60) as u8,
+                (seconds / 60 % 60) as u8,
+                (seconds / 3600 % 24) as u8,
+                days as u8,
+                (days >> 8) as u8,
+            ];
+            rtc.dots = 0;
+        }
+    }
+
+    /// Return the register writes that bring a controller that has just
+    /// been powered on to the current banks, as BESS stores them
+    pub fn register_writes(&self) -> Vec<(u16, u8)> {
+        let enable = if self.ram_enabled { 0x0A } else { 0x00 };
+        match self.kind {
+            Kind::RomOnly => Vec::new(),
+            Kind::Mbc1 => vec![
+                (0x0000, enable),
+                (0x2000, self.rom_bank as u8),
+                (0x4000, self.ram_bank),
+                (0x6000, self.advanced_banking as u8),
+            ],
+            Kind::Mbc3 => vec![
+                (0x0000, enable),
+                (0x2000, self.rom_bank as u8),
+                (0x4000, self.ram_bank),
+            ],
+            Kind::Mbc5 => vec![
+                (0x0000, enable),
+                (0x2000, self.rom_bank as u8),
+                (0x3000, (self.rom_bank >> 8) as u8),
+                (0x4000, self.ram_bank),
+            ],
+        }
+    }
+
+    /// Save the registers, the RAM and the clock, but not what the header
+    /// decides, which comes from the ROM the state is tied to
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enabled);
+        writer.write_u32(self.rom_bank as u32);
+        writer.write_u32(self.ram_bank as u32);
+        writer.write_bool(self.advanced_banking);
+        writer.write_bytes(&self.ram);
+        if let Some(rtc) = self.rtc.as_ref() {
+            writer.write_bytes(&rtc.registers);
+            writer.write_bytes(&rtc.latched);
+            writer.write_u32(rtc.dots);
+            writer.write_bool(rtc.latch_armed);
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_bank = reader.read_below(0x200)? as u16;
+        self.ram_bank = reader.read_below(0x10)? as u8;
+        self.advanced_banking = reader.read_bool()?;
+        reader.read_bytes(&mut self.ram)?;
+        if let Some(rtc) = self.rtc.as_mut() {
+            reader.read_bytes(&mut rtc.registers)?;
+            reader.read_bytes(&mut rtc.latched)?;
+            for (i, mask) in RTC_MASKS.iter().enumerate() {
+                rtc.registers[i] &= mask;
+                rtc.latched[i] &= mask;
+            }
+            rtc.dots = reader.read_below(DOTS_PER_SECOND)?;
+            rtc.latch_armed = reader.read_bool()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create the controller of a cartridge of the given type and sizes
+    fn mbc(cartridge_type: u8, rom_banks: usize, ram_size: u8) -> Mbc {
+        let mut rom = vec![0; rom_banks * ROM_BANK_SIZE];
+        rom[0x147] = cartridge_type;
+        rom[0x149] = ram_size;
+        Mbc::new(&rom)
+    }
+
+    #[test]
+    fn mbc1_upper_bits_bank_rom_and_ram() {
+        let mut mbc = mbc(0x03, 128, 0x03);
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mbc.rom_offset(0x4000), ROM_BANK_SIZE);
+        mbc.write_register(0x2000, 0x05);
+        mbc.write_register(0x4000, 0x02);
+        assert_eq!(mbc.rom_offset(0x4000), 0x45 * ROM_BANK_SIZE);
+        assert_eq!(mbc.rom_offset(0x0000), 0);
+
+        mbc.write_register(0x0000, 0x0A);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.rom_offset(0x0000), 0x40 * ROM_BANK_SIZE);
+        mbc.write_ram(0xA000, 0x42);
+        assert_eq!(mbc.ram()[2 * RAM_BANK_SIZE], 0x42);
+    }
+
+    #[test]
+    fn mbc5_has_9_bit_rom_banks() {
+        let mut mbc = mbc(0x19, 512, 0x00);
+        mbc.write_register(0x2000, 0x23);
+        mbc.write_register(0x3000, 0x01);
+        assert_eq!(mbc.rom_offset(0x4000), 0x123 * ROM_BANK_SIZE);
+        mbc.write_register(0x2000, 0x00);
+        mbc.write_register(0x3000, 0x00);
+        assert_eq!(mbc.rom_offset(0x4000), 0);
+    }
+
+    #[test]
+    fn ram_is_only_accessible_while_enabled() {
+        let mut mbc = mbc(0x1B, 2, 0x04);
+        mbc.write_ram(0xA000, 0x42);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+        mbc.write_register(0x0000, 0x0A);
+        mbc.write_register(0x4000, 0x0F);
+        mbc.write_ram(0xA000, 0x42);
+        assert_eq!(mbc.read_ram(0xA000), 0x42);
+        assert_eq!(mbc.ram()[15 * RAM_BANK_SIZE], 0x42);
+        mbc.write_register(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn rtc_counts_emulated_time_and_latches() {
+        let mut mbc = mbc(0x10, 2, 0x03);
+        mbc.write_register(0x0000, 0x0A);
+        // 23:59:59 on day 511
+        for (register, value) in [
+            (0x08, 59),
+            (0x09, 59),
+            (0x0A, 23),
+            (0x0B, 0xFF),
+            (0x0C, 0x01),
+        ] {
+            mbc.write_register(0x4000, register);
+            mbc.write_ram(0xA000, value);
+        }
+        mbc.tick(DOTS_PER_SECOND);
+        mbc.write_register(0x4000, 0x0C);
+        assert_eq!(mbc.read_ram(0xA000), 0x00);
+        mbc.write_register(0x6000, 0x00);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), RTC_DAY_CARRY);
+        mbc.write_register(0x4000, 0x08);
+        assert_eq!(mbc.read_ram(0xA000), 0);
+    }
+}
@@ -2,54 +2,647 @@ use std::fs::File;
 use std::io;
 use std::io::Read;
 use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-const MEM_BANK_SIZE: usize = 0xFFFF + 1; // 16 ^ 4
+use crate::apu::Apu;
+use crate::bess::{Bess, BessRtc};
+use crate::dma::{Hdma, HdmaTransfer, OamDma, HDMA_BLOCK_DOTS, HDMA_BLOCK_SIZE};
+use crate::infrared::Infrared;
+use crate::joypad::Joypad;
+use crate::mbc::Mbc;
+use crate::ppu::Ppu;
+use crate::serial::Serial;
+use crate::sgb::Sgb;
+use crate::state::{State, StateWriter};
+use crate::timer::Timer;
+use crate::util::crc32;
 
+const WRAM_BANK_SIZE: usize = 0x1000;
+const WRAM_SIZE: usize = WRAM_BANK_SIZE * 8;
+const HRAM_SIZE: usize = 0x7F;
+const IO_SIZE: usize = 0x80;
+
+// Cycles the CPU stays stopped for while switching speeds
+const SPEED_SWITCH_CYCLES: u32 = 8200;
+// Bound on the cycles the CPU can be halted for at once, by a speed switch
+// or a general-purpose HDMA of all 128 blocks at double speed
+const MAX_STALL_CYCLES: u32 = SPEED_SWITCH_CYCLES + 0x80 * HDMA_BLOCK_DOTS * 2;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum ColorMode {
     Color,
     NoColor,
 }
 
+/// Hardware being emulated. Games for the CGB only run in color on the CGB,
+/// and the Super Game Boy features only work for games that support them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Model {
+    Dmg,
+    Cgb,
+    Sgb,
+}
+
+/// Interrupt sources, with the value of their bit in the IF and IE registers
+#[derive(Clone, Copy)]
+pub enum Interrupt {
+    VBlank = 0b00001,
+    Stat = 0b00010,
+    Timer = 0b00100,
+    Serial = 0b01000,
+    Joypad = 0b10000,
+}
+
 /// Memory Management Unit (MMU)
 pub struct Mmu {
-    // TODO: Break into into memory sections (working ram, vram, oam, etc)
-    // TODO: Add support for more memory banks
-    memory: [u8; MEM_BANK_SIZE],
+    rom: Vec<u8>,
+    // CRC-32 of the ROM, which save states are tied to
+    rom_checksum: u32,
+    mbc: Mbc,
+    model: Model,
+    color_mode: ColorMode,
+    wram: [u8; WRAM_SIZE],
+    // WRAM bank mapped at 0xD000-0xDFFF (SVBK), from 1 to 7 in CGB mode
+    wram_bank: usize,
+    hram: [u8; HRAM_SIZE],
+    io: [u8; IO_SIZE],
+    interrupt_flag: u8,
+    interrupt_enable: u8,
+    pub ppu: Ppu,
+    pub dma: OamDma,
+    pub hdma: Hdma,
+    pub timer: Timer,
+    pub joypad: Joypad,
+    pub apu: Apu,
+    pub serial: Serial,
+    pub infrared: Infrared,
+    pub sgb: Option<Sgb>,
+    // Cycles the CPU has to be halted for while a DMA transfer runs
+    stall_cycles: u32,
+    // CGB double speed mode, and whether a switch has been requested through
+    // KEY1 to happen on the next STOP
+    double_speed: bool,
+    speed_switch_armed: bool,
+    // Whether an odd cycle in double speed is still owed to the PPU, which
+    // always runs at one dot per single speed cycle
+    half_dot: bool,
+    // Dots elapsed since power on, which measure real time regardless of
+    // the CPU speed
+    dots: u64,
+}
+
+impl Default for Mmu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Mmu {
     pub fn new() -> Self {
         Self {
-            memory: [0; MEM_BANK_SIZE],
+            rom: Vec::new(),
+            rom_checksum: crc32(&[]),
+            mbc: Mbc::new(&[]),
+            model: Model::Dmg,
+            color_mode: ColorMode::NoColor,
+            wram: [0; WRAM_SIZE],
+            wram_bank: 1,
+            hram: [0; HRAM_SIZE],
+            io: [0; IO_SIZE],
+            interrupt_flag: 0,
+            interrupt_enable: 0,
+            ppu: Ppu::new(),
+            dma: OamDma::new(),
+            hdma: Hdma::new(),
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            apu: Apu::new(),
+            serial: Serial::new(),
+            infrared: Infrared::new(),
+            sgb: None,
+            stall_cycles: 0,
+            double_speed: false,
+            speed_switch_armed: false,
+            half_dot: false,
+            dots: 0,
         }
     }
 
     pub fn load_rom(&mut self, rom_path: &Path) -> Result<(), io::Error> {
         let mut buffer = Vec::new();
-        File::open(&rom_path)?.read_to_end(&mut buffer)?;
-        // Copy data into memory
-        for (i, byte) in buffer.iter().enumerate() {
-            self.memory[i] = *byte;
+        File::open(rom_path)?.read_to_end(&mut buffer)?;
+        self.load_rom_data(buffer);
+        Ok(())
+    }
+
+    /// Load a ROM image, emulating the CGB for CGB games and the DMG for
+    /// the rest
+    pub fn load_rom_data(&mut self, rom: Vec<u8>) {
+        self.rom_checksum = crc32(&rom);
+        self.mbc = Mbc::new(&rom);
+        self.rom = rom;
+        let model = match self.color_mode() {
+            ColorMode::Color => Model::Cgb,
+            ColorMode::NoColor => Model::Dmg,
+        };
+        self.set_model(model);
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    /// Emulate the given hardware, which has to be chosen before running
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+        self.color_mode = match model {
+            Model::Cgb => self.color_mode(),
+            Model::Dmg | Model::Sgb => ColorMode::NoColor,
+        };
+        self.ppu.set_color_mode(self.color_mode);
+        self.apu.set_color_mode(self.color_mode);
+        self.serial.set_color_mode(self.color_mode);
+        self.sgb = match model {
+            Model::Sgb if self.supports_sgb() => Some(Sgb::new()),
+            _ => None,
+        };
+    }
+
+    /// Return the CRC-32 of the ROM image
+    pub fn rom_checksum(&self) -> u32 {
+        self.rom_checksum
+    }
+
+    /// Return the RAM of the cartridge, which a battery keeps on real ones
+    pub fn save_ram(&self) -> &[u8] {
+        self.mbc.ram()
+    }
+
+    /// Fill the RAM of the cartridge with the given data, the rest of it
+    /// being zeros if it is shorter
+    pub fn load_save_ram(&mut self, data: &[u8]) {
+        self.mbc.load_ram(data);
+    }
+
+    /// Set the real time clock of the cartridge, if it has one, to the given
+    /// number of seconds
+    pub fn set_rtc_seconds(&mut self, seconds: u64) {
+        self.mbc.set_rtc_seconds(seconds);
+    }
+
+    /// Write the sections of a save state for the hardware besides the CPU
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.section(b"INFO", |writer| {
+            writer.write_u32(self.rom_checksum);
+            writer.write_u32(self.model as u32);
+        });
+        writer.section(b"MEM ", |writer| {
+            writer.write_bytes(&self.wram);
+            writer.write_u32(self.wram_bank as u32);
+            writer.write_bytes(&self.hram);
+            writer.write_bytes(&self.io);
+            writer.write_u8(self.interrupt_flag);
+            writer.write_u8(self.interrupt_enable);
+            writer.write_u32(self.stall_cycles);
+            writer.write_bool(self.double_speed);
+            writer.write_bool(self.speed_switch_armed);
+            writer.write_bool(self.half_dot);
+            writer.write_u64(self.dots);
+        });
+        writer.section(b"MBC ", |writer| self.mbc.save_state(writer));
+        writer.section(b"PPU ", |writer| self.ppu.save_state(writer));
+        writer.section(b"APU ", |writer| self.apu.save_state(writer));
+        writer.section(b"TIMR", |writer| self.timer.save_state(writer));
+        writer.section(b"SERL", |writer| self.serial.save_state(writer));
+        writer.section(b"IR  ", |writer| self.infrared.save_state(writer));
+        writer.section(b"JOYP", |writer| self.joypad.save_state(writer));
+        writer.section(b"DMA ", |writer| self.dma.save_state(writer));
+        writer.section(b"HDMA", |writer| self.hdma.save_state(writer));
+        if let Some(sgb) = self.sgb.as_ref() {
+            writer.section(b"SGB ", |writer| sgb.save_state(writer));
+        }
+    }
+
+    /// Load the sections of a save state for the hardware besides the CPU,
+    /// which has to be of the same ROM
+    pub fn load_state(&mut self, state: &State) -> Result<(), io::Error> {
+        let (checksum, model) = state.read_section(b"INFO", |reader| {
+            let checksum = reader.read_u32()?;
+            let model = match reader.read_below(3)? {
+                0 => Model::Dmg,
+                1 => Model::Cgb,
+                _ => Model::Sgb,
+            };
+            Ok((checksum, model))
+        })?;
+        if checksum != self.rom_checksum {
+            let message = format!(
+                "The save state is of another ROM, with a CRC-32 of {:08X} rather than {:08X}",
+                checksum, self.rom_checksum
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        self.set_model(model);
+        state.read_section(b"MEM ", |reader| {
+            reader.read_bytes(&mut self.wram)?;
+            self.wram_bank = reader.read_below(8)?.max(1) as usize;
+            reader.read_bytes(&mut self.hram)?;
+            reader.read_bytes(&mut self.io)?;
+            self.interrupt_flag = reader.read_u8()? & 0x1F;
+            self.interrupt_enable = reader.read_u8()?;
+            self.stall_cycles = reader.read_below(MAX_STALL_CYCLES + 1)?;
+            self.double_speed = reader.read_bool()?;
+            self.speed_switch_armed = reader.read_bool()?;
+            self.half_dot = reader.read_bool()?;
+            self.dots = reader.read_u64()?;
+            Ok(())
+        })?;
+        state.read_section(b"MBC ", |reader| self.mbc.load_state(reader))?;
+        state.read_section(b"PPU ", |reader| self.ppu.load_state(reader))?;
+        state.read_section(b"APU ", |reader| self.apu.load_state(reader))?;
+        state.read_section(b"TIMR", |reader| self.timer.load_state(reader))?;
+        state.read_section(b"SERL", |reader| self.serial.load_state(reader))?;
+        state.read_section(b"IR  ", |reader| self.infrared.load_state(reader))?;
+        state.read_section(b"JOYP", |reader| self.joypad.load_state(reader))?;
+        state.read_section(b"DMA ", |reader| self.dma.load_state(reader))?;
+        state.read_section(b"HDMA", |reader| self.hdma.load_state(reader))?;
+        if let Some(sgb) = self.sgb.as_mut() {
+            state.read_section(b"SGB ", |reader| sgb.load_state(reader))?;
+        }
+        Ok(())
+    }
+
+    /// Fill in the hardware besides the CPU in a BESS state
+    pub fn export_bess(&self, bess: &mut Bess) {
+        let mut title = [0; 0x10];
+        for (i, byte) in title.iter_mut().enumerate() {
+            *byte = self.read_bus(0x134 + i as u16);
+        }
+        let checksum = u16::from_be_bytes([self.read_bus(0x14E), self.read_bus(0x14F)]);
+        bess.rom_info = Some((title, checksum));
+        bess.model = self.model;
+        bess.interrupt_enable = self.interrupt_enable;
+        for (i, value) in bess.io.iter_mut().enumerate() {
+            *value = match 0xFF00 + i as u16 {
+                address @ 0xFF10..=0xFF25 => {
+                    self.apu.written_registers()[address as usize - 0xFF10]
+                }
+                address => self.read_bus(address),
+            };
+        }
+        let (ram_size, vram_size) = if self.is_color() {
+            (WRAM_SIZE, 0x4000)
+        } else {
+            (WRAM_BANK_SIZE * 2, 0x2000)
+        };
+        bess.ram = self.wram[..ram_size].to_vec();
+        bess.vram = self.ppu.vram()[..vram_size].to_vec();
+        bess.mbc_ram = self.mbc.ram().to_vec();
+        bess.oam = self.ppu.oam().to_vec();
+        bess.hram = self.hram.to_vec();
+        if self.is_color() {
+            let (bg_palettes, obj_palettes) = self.ppu.palette_data();
+            bess.bg_palettes = bg_palettes.to_vec();
+            bess.obj_palettes = obj_palettes.to_vec();
+        }
+        bess.mbc_writes = self.mbc.register_writes();
+        bess.rtc = self
+            .mbc
+            .rtc_registers()
+            .map(|(registers, latched)| BessRtc {
+                registers,
+                latched,
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |duration| duration.as_secs()),
+            });
+        bess.sgb = self.sgb.as_ref().map(|sgb| {
+            let mut bess_sgb = sgb.export_bess();
+            let (players, player) = self.joypad.players();
+            bess_sgb.multiplayer = (players << 4 | player) as u8;
+            bess_sgb
+        });
+    }
+
+    /// Bring the hardware besides the CPU to the state of a BESS state, on
+    /// a best effort basis. Whatever the state does not cover starts over
+    /// from power on.
+    pub fn import_bess(&mut self, bess: &Bess) -> Result<(), io::Error> {
+        if let Some((title, checksum)) = bess.rom_info {
+            let rom_title = self.rom.get(0x134..0x144);
+            let rom_checksum = self.rom.get(0x14E..0x150);
+            if rom_title != Some(&title[..]) || rom_checksum != Some(&checksum.to_be_bytes()[..]) {
+                let title = String::from_utf8_lossy(&title);
+                let message = format!(
+                    "The save state is of another ROM, titled \"{}\"",
+                    title.trim_end_matches('\0')
+                );
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+        }
+        let mut power_on = Mmu::new();
+        power_on.load_rom_data(self.rom.clone());
+        let mut writer = StateWriter::new();
+        power_on.save_state(&mut writer);
+        self.load_state(&State::parse(&writer.finish())?)?;
+        self.set_model(bess.model);
+
+        let copy = |memory: &mut [u8], data: &[u8]| {
+            let length = memory.len().min(data.len());
+            memory[..length].copy_from_slice(&data[..length]);
+        };
+        copy(&mut self.wram, &bess.ram);
+        self.mbc.load_ram(&bess.mbc_ram);
+        copy(&mut self.hram, &bess.hram);
+        self.ppu
+            .restore_memory(&bess.vram, &bess.oam, &bess.bg_palettes, &bess.obj_palettes);
+        for &(address, value) in &bess.mbc_writes {
+            if address < 0x8000 {
+                self.write_byte_at(address, value);
+            }
+        }
+        // The clock counts emulated time, so the time that went by since the
+        // state was saved is left out
+        if let Some(rtc) = bess.rtc.as_ref() {
+            self.mbc.set_rtc_registers(rtc.registers, rtc.latched);
+        }
+
+        let io = |address: u16| bess.io[address as usize - 0xFF00];
+        self.io = bess.io;
+        self.joypad.write_register(io(0xFF00));
+        // The sound registers are written with NR52 first, as they are
+        // ignored while the APU is off, and the channels that were playing
+        // are triggered again
+        self.apu.write_register(0xFF26, io(0xFF26));
+        for address in 0xFF30..=0xFF3F {
+            self.apu.write_register(address, io(address));
+        }
+        for address in 0xFF10..=0xFF25 {
+            let channel = match address {
+                0xFF14 => 0x01,
+                0xFF19 => 0x02,
+                0xFF1E => 0x04,
+                0xFF23 => 0x08,
+                _ => 0,
+            };
+            let value = if io(0xFF26) & channel != 0 {
+                io(address) | 0x80
+            } else if channel != 0 {
+                io(address) & 0x7F
+            } else {
+                io(address)
+            };
+            self.apu.write_register(address, value);
+        }
+        // TAC goes first so that writing it does not increment TIMA
+        for &address in &[
+            0xFF07, 0xFF05, 0xFF06, 0xFF40, 0xFF41, 0xFF42, 0xFF43, 0xFF45, 0xFF47, 0xFF48, 0xFF49,
+            0xFF4A, 0xFF4B, 0xFF4F, 0xFF51, 0xFF52, 0xFF53, 0xFF54, 0xFF56, 0xFF68, 0xFF6A, 0xFF6C,
+            0xFF70, 0xFF01,
+        ] {
+            self.write_byte_at(address, io(address));
+        }
+        self.timer.set_divider((io(0xFF04) as u16) << 8);
+        self.ppu.set_ly(io(0xFF44));
+        self.double_speed = self.is_color() && io(0xFF4D) & 0x80 != 0;
+        self.interrupt_flag = io(0xFF0F) & 0x1F;
+        self.interrupt_enable = bess.interrupt_enable;
+        // SC goes last, as it may start a transfer with whatever is plugged in
+        self.write_byte_at(0xFF02, io(0xFF02));
+
+        if let (Some(sgb), Some(bess_sgb)) = (self.sgb.as_mut(), bess.sgb.as_ref()) {
+            sgb.import_bess(bess_sgb);
+            self.joypad
+                .set_players((bess_sgb.multiplayer >> 4) as usize);
+            self.joypad
+                .set_player((bess_sgb.multiplayer & 0x0F) as usize);
         }
         Ok(())
     }
 
+    /// Read a byte as seen by the CPU
     pub fn read_byte_at(&self, address: u16) -> u8 {
-        let mut address = address as usize;
+        if self.dma.conflicts_with(address) {
+            return self.dma.bus_value();
+        }
         match address {
-            0xE000..=0xFE00 => address -= 0x2000,
-            _ => {}
+            0xFE00..=0xFEFF if self.dma.is_active() => 0xFF,
+            0xFF46 => self.dma.read_register(),
+            _ => self.read_bus(address),
+        }
+    }
+
+    /// Read a byte from whatever is mapped at the address
+    fn read_bus(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x7FFF => {
+                let offset = self.mbc.rom_offset(address);
+                self.rom.get(offset).copied().unwrap_or(0xFF)
+            }
+            0x8000..=0x9FFF => self.ppu.read_vram(address),
+            0xA000..=0xBFFF => self.mbc.read_ram(address),
+            0xC000..=0xFDFF => self.wram[self.wram_offset(address)],
+            0xFE00..=0xFE9F => self.ppu.read_oam(address),
+            0xFEA0..=0xFEFF => 0xFF,
+            0xFF00 => self.joypad.read_register(),
+            0xFF01 | 0xFF02 => self.serial.read_register(address),
+            0xFF04..=0xFF07 => self.timer.read_register(address),
+            0xFF0F => 0xE0 | self.interrupt_flag,
+            0xFF10..=0xFF3F => self.apu.read_register(address),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6C => {
+                self.ppu.read_register(address)
+            }
+            0xFF51..=0xFF55 if self.is_color() => self.hdma.read_register(address),
+            0xFF4D if self.is_color() => {
+                let speed = if self.double_speed { 0x80 } else { 0 };
+                0x7E | speed | self.speed_switch_armed as u8
+            }
+            0xFF56 if self.is_color() => self.infrared.read_register(),
+            0xFF70 if self.is_color() => 0xF8 | self.wram_bank as u8,
+            0xFF76 | 0xFF77 if self.is_color() => self.apu.read_register(address),
+            0xFF80..=0xFFFE => self.hram[address as usize - 0xFF80],
+            0xFFFF => self.interrupt_enable,
+            _ => self.io[address as usize - 0xFF00],
         }
-        self.memory[address]
     }
 
+    /// Write a byte from the CPU
     pub fn write_byte_at(&mut self, address: u16, value: u8) {
-        let mut address = address as usize;
+        if self.dma.conflicts_with(address) {
+            return;
+        }
         match address {
-            0xE000..=0xFE00 => address -= 0x2000,
+            0x0000..=0x7FFF => self.mbc.write_register(address, value),
+            0x8000..=0x9FFF => self.ppu.write_vram(address, value),
+            0xA000..=0xBFFF => self.mbc.write_ram(address, value),
+            0xC000..=0xFDFF => self.wram[self.wram_offset(address)] = value,
+            0xFE00..=0xFEFF if self.dma.is_active() => {}
+            0xFE00..=0xFE9F => self.ppu.write_oam(address, value),
+            0xFEA0..=0xFEFF => {}
+            0xFF46 => self.dma.write_register(value),
+            0xFF00 => {
+                self.joypad.write_register(value);
+                if let Some(sgb) = self.sgb.as_mut() {
+                    sgb.write_joypad(value);
+                    if let Some(players) = sgb.take_players() {
+                        self.joypad.set_players(players);
+                    }
+                }
+            }
+            0xFF01 | 0xFF02 => self.serial.write_register(address, value),
+            0xFF04..=0xFF07 => self.timer.write_register(address, value),
+            0xFF0F => self.interrupt_flag = value & 0x1F,
+            0xFF10..=0xFF3F => self.apu.write_register(address, value),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6C => {
+                self.ppu.write_register(address, value)
+            }
+            0xFF51..=0xFF55 if self.is_color() => self.write_hdma(address, value),
+            0xFF4D if self.is_color() => self.speed_switch_armed = value & 0x01 != 0,
+            0xFF56 if self.is_color() => self.infrared.write_register(value),
+            0xFF70 if self.is_color() => self.wram_bank = ((value & 0x07) as usize).max(1),
+            0xFF80..=0xFFFE => self.hram[address as usize - 0xFF80] = value,
+            0xFFFF => self.interrupt_enable = value,
+            _ => self.io[address as usize - 0xFF00] = value,
+        }
+    }
+
+    fn is_color(&self) -> bool {
+        self.color_mode == ColorMode::Color
+    }
+
+    /// Return the offset into WRAM of an address in 0xC000-0xFDFF, which
+    /// includes the echo of 0xC000-0xDDFF at 0xE000
+    fn wram_offset(&self, address: u16) -> usize {
+        let address = address as usize & 0x1FFF;
+        if address < WRAM_BANK_SIZE {
+            address
+        } else {
+            self.wram_bank * WRAM_BANK_SIZE + address - WRAM_BANK_SIZE
+        }
+    }
+
+    fn write_hdma(&mut self, address: u16, value: u8) {
+        match self.hdma.write_register(address, value) {
+            Some(HdmaTransfer::GeneralPurpose) => {
+                // The transfer may stop early by running past the end of VRAM
+                while self.hdma.is_active() {
+                    self.copy_hdma_block();
+                }
+            }
+            // With the LCD off there is no HBlank to wait for, so the first
+            // block is copied right away
+            Some(HdmaTransfer::HBlank) if !self.ppu.lcd_enabled() => self.copy_hdma_block(),
             _ => {}
         }
-        self.memory[address] = value;
+    }
+
+    /// Copy the next block of an HDMA transfer into VRAM, halting the CPU
+    /// for as long as it takes
+    fn copy_hdma_block(&mut self) {
+        let (source, destination) = match self.hdma.next_block() {
+            Some(block) => block,
+            None => return,
+        };
+        for i in 0..HDMA_BLOCK_SIZE {
+            let value = self.read_dma_source(source.wrapping_add(i));
+            self.ppu.write_vram_dma(destination + i, value);
+        }
+        self.stall_cycles += self.dots_to_cycles(HDMA_BLOCK_DOTS);
+    }
+
+    /// Return the number of cycles the CPU has to stay halted for because of
+    /// DMA transfers, during which the rest of the hardware keeps running
+    pub fn take_stall_cycles(&mut self) -> u32 {
+        std::mem::take(&mut self.stall_cycles)
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    /// Called when the CPU executes STOP, which resets the divider and
+    /// switches the CPU speed if it was requested through KEY1. Return
+    /// whether the switch happened, in which case the CPU is halted until
+    /// it completes.
+    pub fn stop(&mut self) -> bool {
+        self.timer.reset_divider();
+        if !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        self.stall_cycles += SPEED_SWITCH_CYCLES;
+        true
+    }
+
+    /// Return the interrupts that are both requested and enabled
+    pub fn pending_interrupts(&self) -> u8 {
+        self.interrupt_flag & self.interrupt_enable & 0x1F
+    }
+
+    /// Clear the request of an interrupt as the CPU services it
+    pub fn acknowledge_interrupt(&mut self, interrupt: u8) {
+        self.interrupt_flag &= !interrupt;
+    }
+
+    /// Return the dots elapsed since power on
+    pub fn dots(&self) -> u64 {
+        self.dots
+    }
+
+    /// Convert CPU cycles into dots, which take twice as many cycles when
+    /// running at double speed
+    fn cycles_to_dots(&mut self, cycles: u32) -> u32 {
+        if !self.double_speed {
+            return cycles;
+        }
+        let half_cycles = cycles + self.half_dot as u32;
+        self.half_dot = half_cycles & 1 != 0;
+        half_cycles / 2
+    }
+
+    fn dots_to_cycles(&self, dots: u32) -> u32 {
+        if self.double_speed {
+            dots * 2
+        } else {
+            dots
+        }
+    }
+
+    /// Advance the rest of the hardware by the given number of CPU cycles.
+    /// Whatever is clocked along with the CPU runs twice as fast at double
+    /// speed, while the PPU, HDMA and APU keep running in real time.
+    pub fn tick(&mut self, cycles: u32) {
+        let dots = self.cycles_to_dots(cycles);
+        self.dots += dots as u64;
+        self.interrupt_flag |= self.ppu.tick(dots);
+        self.apu.tick(dots);
+        self.mbc.tick(dots);
+        self.interrupt_flag |= self.timer.tick(cycles);
+        self.interrupt_flag |= self.serial.tick(cycles, self.dots);
+        self.infrared.tick(self.dots);
+        self.interrupt_flag |= self.joypad.take_interrupts();
+        if let (true, Some(sgb)) = (self.ppu.take_vblank_start(), self.sgb.as_mut()) {
+            sgb.finish_frame(self.ppu.shades());
+        }
+        if self.ppu.take_hblank_start() && self.hdma.hblank_active() {
+            self.copy_hdma_block();
+        }
+        for _ in 0..self.dma.machine_cycles(cycles) {
+            if let Some((source, offset)) = self.dma.step() {
+                let value = self.read_dma_source(source);
+                self.dma.set_bus_value(value);
+                self.ppu.write_oam_dma(offset, value);
+            }
+        }
+    }
+
+    /// Read a byte for the OAM DMA, which bypasses the PPU's locking
+    fn read_dma_source(&self, address: u16) -> u8 {
+        match address {
+            0x8000..=0x9FFF => self.ppu.read_vram_dma(address),
+            _ => self.read_bus(address),
+        }
     }
 
     /// Return the game title as specified in the ROM data
@@ -64,8 +657,14 @@ impl Mmu {
     /// Return whether the ROM is for Game Boy Color or normal Game Boy
     pub fn color_mode(&self) -> ColorMode {
         match self.read_byte_at(0x143) {
-            0x80 => ColorMode::Color,
+            0x80 | 0xC0 => ColorMode::Color,
             _ => ColorMode::NoColor,
         }
     }
+
+    /// Return whether the ROM supports the Super Game Boy features, which
+    /// also takes the old licensee code to be 0x33
+    pub fn supports_sgb(&self) -> bool {
+        self.rom.get(0x146) == Some(&0x03) && self.rom.get(0x14B) == Some(&0x33)
+    }
 }
@@ -0,0 +1,495 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::cpu::Cpu;
+use crate::joypad::{Button, MAX_PLAYERS};
+use crate::memory::Model;
+use crate::ppu::DOTS_PER_FRAME;
+use crate::state::{StateReader, StateWriter};
+use crate::util::crc32;
+
+const MAGIC: &[u8; 4] = b"TZBM";
+/// Version of the movie format, to be bumped whenever its layout changes
+pub const VERSION: u16 = 1;
+
+/// Condition the machine starts a movie in
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Start {
+    PowerOn,
+    /// Power on with the cartridge RAM restored from a battery save
+    SaveRam(Vec<u8>),
+    /// Save state taken by `Cpu::save_state`
+    State(Vec<u8>),
+}
+
+/// Input movie, the joypads held on every frame of a run along with what
+/// it takes to play them back into the same run
+///
+/// The file starts with a magic number and the version of the format,
+/// followed by the CRC-32 of the ROM, the model, the RTC seed, the settings,
+/// the start condition, the frames and the frame hashes. Numbers are little
+/// endian.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Movie {
+    pub rom_checksum: u32,
+    pub model: Model,
+    // Seconds the real time clock of the cartridge, for those that have
+    // one, starts at when the movie starts from power on. A save state has
+    // the clock in it already.
+    pub rtc_seed: u64,
+    pub allow_opposite_directions: bool,
+    pub start: Start,
+    // Mask of the buttons held on each joypad during each frame
+    pub frames: Vec<[u8; MAX_PLAYERS]>,
+    // Number of frames between hashes, and the CRC-32 of the framebuffer at
+    // the end of every that many frames
+    pub hash_interval: u32,
+    pub hashes: Vec<u32>,
+}
+
+impl Movie {
+    pub fn parse(data: &[u8]) -> Result<Self, io::Error> {
+        if data.len() < 6 || &data[0..4] != MAGIC {
+            return Err(invalid_data("Not a tonzoboy movie".to_string()));
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version != VERSION {
+            let message = format!(
+                "The movie is of version {} of the format, while this build reads {}",
+                version, VERSION
+            );
+            return Err(invalid_data(message));
+        }
+        let mut reader = StateReader::new(&data[6..]);
+        let movie = Self::read(&mut reader)
+            .map_err(|error| invalid_data(format!("Invalid movie: {}", error)))?;
+        if !reader.is_at_end() {
+            return Err(invalid_data(
+                "The movie is longer than expected".to_string(),
+            ));
+        }
+        Ok(movie)
+    }
+
+    fn read(reader: &mut StateReader) -> Result<Self, io::Error> {
+        let rom_checksum = reader.read_u32()?;
+        let model = match reader.read_u8()? {
+            0 => Model::Dmg,
+            1 => Model::Cgb,
+            2 => Model::Sgb,
+            model => return Err(invalid_data(format!("unknown model {}", model))),
+        };
+        let rtc_seed = reader.read_u64()?;
+        let allow_opposite_directions = reader.read_bool()?;
+        let start = match reader.read_u8()? {
+            0 => Start::PowerOn,
+            1 => Start::SaveRam(read_block(reader)?),
+            2 => Start::State(read_block(reader)?),
+            start => return Err(invalid_data(format!("unknown start condition {}", start))),
+        };
+        let frame_count = reader.read_u32()? as usize;
+        let mut frames = Vec::new();
+        for _ in 0..frame_count {
+            let mut pressed = [0; MAX_PLAYERS];
+            reader.read_bytes(&mut pressed)?;
+            frames.push(pressed);
+        }
+        let hash_interval = reader.read_u32()?;
+        if hash_interval == 0 {
+            return Err(invalid_data("no interval between hashes".to_string()));
+        }
+        let hash_count = reader.read_u32()? as usize;
+        let mut hashes = Vec::new();
+        for _ in 0..hash_count {
+            hashes.push(reader.read_u32()?);
+        }
+        Ok(Self {
+            rom_checksum,
+            model,
+            rtc_seed,
+            allow_opposite_directions,
+            start,
+            frames,
+            hash_interval,
+            hashes,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::with_header(MAGIC, VERSION);
+        writer.write_u32(self.rom_checksum);
+        writer.write_u8(self.model as u8);
+        writer.write_u64(self.rtc_seed);
+        writer.write_bool(self.allow_opposite_directions);
+        match &self.start {
+            Start::PowerOn => writer.write_u8(0),
+            Start::SaveRam(save_ram) => {
+                writer.write_u8(1);
+                write_block(&mut writer, save_ram);
+            }
+            Start::State(state) => {
+                writer.write_u8(2);
+                write_block(&mut writer, state);
+            }
+        }
+        writer.write_u32(self.frames.len() as u32);
+        for pressed in &self.frames {
+            writer.write_bytes(pressed);
+        }
+        writer.write_u32(self.hash_interval);
+        writer.write_u32(self.hashes.len() as u32);
+        for &hash in &self.hashes {
+            writer.write_u32(hash);
+        }
+        writer.finish()
+    }
+}
+
+/// Write a length followed by that many bytes
+fn write_block(writer: &mut StateWriter, block: &[u8]) {
+    writer.write_u32(block.len() as u32);
+    writer.write_bytes(block);
+}
+
+/// Read a block written by `write_block`
+fn read_block(reader: &mut StateReader) -> Result<Vec<u8>, io::Error> {
+    let length = reader.read_u32()? as usize;
+    reader.read_vec(length)
+}
+
+/// What a `MoviePlayer` does with the joypads
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    /// Record the joypads as they are held
+    Recording,
+    /// Play the movie back, ignoring the joypads, up to its last frame
+    ReadOnly,
+    /// Play the movie back until the joypads are taken over or the movie
+    /// ends, then record the rest of the run over it
+    ReadWrite,
+}
+
+/// Recorder and player of a movie, running the machine one frame at a time
+/// and holding the joypads as the movie says or logging them into it
+pub struct MoviePlayer {
+    movie: Movie,
+    mode: Mode,
+    // Next frame to run, and the dots since power on that the movie
+    // started at, which frames are counted from
+    frame: usize,
+    start_dots: u64,
+}
+
+impl MoviePlayer {
+    /// Start recording a movie on a CPU that has just been created, from
+    /// the given start condition and seconds on the real time clock, hashing
+    /// the frames every given number of frames
+    pub fn record(
+        cpu: &mut Cpu,
+        start: Start,
+        rtc_seed: u64,
+        hash_interval: u32,
+    ) -> Result<Self, io::Error> {
+        apply_start(cpu, &start, rtc_seed)?;
+        let movie = Movie {
+            rom_checksum: cpu.rom_checksum(),
+            model: cpu.model(),
+            rtc_seed,
+            allow_opposite_directions: cpu.allow_opposite_directions(),
+            start,
+            frames: Vec::new(),
+            hash_interval: hash_interval.max(1),
+            hashes: Vec::new(),
+        };
+        Ok(Self {
+            movie,
+            mode: Mode::Recording,
+            frame: 0,
+            start_dots: cpu.dots(),
+        })
+    }
+
+    /// Start playing a movie back on a CPU that has just been created for
+    /// the same ROM, bringing it to the start condition and settings of the
+    /// movie
+    pub fn play(cpu: &mut Cpu, movie: Movie, mode: Mode) -> Result<Self, io::Error> {
+        if movie.rom_checksum != cpu.rom_checksum() {
+            let message = format!(
+                "The movie is of another ROM, with a CRC-32 of {:08X} rather than {:08X}",
+                movie.rom_checksum,
+                cpu.rom_checksum()
+            );
+            return Err(invalid_data(message));
+        }
+        cpu.set_model(movie.model);
+        cpu.set_allow_opposite_directions(movie.allow_opposite_directions);
+        apply_start(cpu, &movie.start, movie.rtc_seed)?;
+        let mode = match (mode, movie.frames.is_empty()) {
+            (Mode::ReadWrite, true) => Mode::Recording,
+            _ => mode,
+        };
+        Ok(Self {
+            movie,
+            mode,
+            frame: 0,
+            start_dots: cpu.dots(),
+        })
+    }
+
+    /// Run the next frame with the joypads held as given, or left as they
+    /// are with None, unless playing the movie back. Fails if the frame does
+    /// not hash to what the movie has for it, which means that the run has
+    /// desynced.
+    pub fn run_frame(
+        &mut self,
+        cpu: &mut Cpu,
+        pressed: Option<[u8; MAX_PLAYERS]>,
+    ) -> Result<(), io::Error> {
+        if self.mode == Mode::ReadWrite && pressed.is_some() {
+            self.take_over();
+        }
+        let pressed = match self.mode {
+            Mode::Recording => {
+                let pressed = pressed.unwrap_or_else(|| cpu.pressed());
+                self.movie.frames.push(pressed);
+                pressed
+            }
+            Mode::ReadOnly | Mode::ReadWrite => match self.movie.frames.get(self.frame) {
+                Some(&pressed) => pressed,
+                None => return Ok(()),
+            },
+        };
+        cpu.set_pressed(pressed);
+        self.frame += 1;
+        // Frames are counted from the start of the movie rather than run one
+        // after the other, so that instructions running past the end of a
+        // frame do not add up
+        let end = self.start_dots + self.frame as u64 * DOTS_PER_FRAME as u64;
+        while cpu.dots() < end {
+            cpu.step();
+        }
+        // Frames are hashed at the end of every interval
+        let interval = self.movie.hash_interval as usize;
+        let (intervals, rest) = (self.frame / interval, self.frame % interval);
+        if rest == 0 {
+            let hash = frame_hash(cpu);
+            let index = intervals - 1;
+            match self.mode {
+                Mode::Recording => self.movie.hashes.push(hash),
+                Mode::ReadOnly | Mode::ReadWrite => match self.movie.hashes.get(index) {
+                    Some(&expected) if expected != hash => {
+                        let message = format!(
+                            "Desync at frame {}, which hashes to {:08X} rather than {:08X}",
+                            self.frame, hash, expected
+                        );
+                        return Err(invalid_data(message));
+                    }
+                    _ => {}
+                },
+            }
+        }
+        if self.mode == Mode::ReadWrite && self.frame == self.movie.frames.len() {
+            self.take_over();
+        }
+        Ok(())
+    }
+
+    /// Stop playing the movie back and record over the rest of it
+    fn take_over(&mut self) {
+        self.movie.frames.truncate(self.frame);
+        let interval = self.movie.hash_interval as usize;
+        self.movie.hashes.truncate(self.frame / interval);
+        self.mode = Mode::Recording;
+    }
+
+    /// Whether a read-only playback has run all the frames of the movie
+    pub fn is_finished(&self) -> bool {
+        self.mode == Mode::ReadOnly && self.frame >= self.movie.frames.len()
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Return the number of frames run so far
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn into_movie(self) -> Movie {
+        self.movie
+    }
+}
+
+/// Joypad input written ahead of time, to record a movie with headless
+///
+/// Each line holds a frame, counting from 0, followed by the buttons held
+/// on each joypad from that frame on, such as `120 a,start` for the first
+/// joypad or `300 - up` for the second one alone. Buttons are separated by
+/// commas, `-` standing for none, and the frames go in order.
+pub struct InputScript {
+    // Frames the input changes on, and the buttons held from then on
+    changes: Vec<(usize, [u8; MAX_PLAYERS])>,
+}
+
+impl InputScript {
+    pub fn load(path: &Path) -> Result<Self, io::Error> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(script: &str) -> Result<Self, io::Error> {
+        let mut changes: Vec<(usize, [u8; MAX_PLAYERS])> = Vec::new();
+        for (number, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid_line = || {
+                let message = format!("Invalid line {} of the input script: {}", number + 1, line);
+                invalid_data(message)
+            };
+            let mut fields = line.split_whitespace();
+            let frame: usize = fields
+                .next()
+                .and_then(|field| field.parse().ok())
+                .ok_or_else(invalid_line)?;
+            if changes.last().is_some_and(|&(last, _)| frame <= last) {
+                return Err(invalid_line());
+            }
+            let mut pressed = [0; MAX_PLAYERS];
+            for (i, field) in fields.enumerate() {
+                let buttons = pressed.get_mut(i).ok_or_else(invalid_line)?;
+                *buttons = parse_buttons(field).ok_or_else(invalid_line)?;
+            }
+            changes.push((frame, pressed));
+        }
+        Ok(Self { changes })
+    }
+
+    /// Return the buttons held on each joypad during the given frame
+    pub fn pressed(&self, frame: usize) -> [u8; MAX_PLAYERS] {
+        self.changes
+            .iter()
+            .take_while(|&&(start, _)| start <= frame)
+            .last()
+            .map_or([0; MAX_PLAYERS], |&(_, pressed)| pressed)
+    }
+}
+
+/// Parse a comma separated list of buttons, or `-` for none, into a mask
+fn parse_buttons(field: &str) -> Option<u8> {
+    if field == "-" {
+        return Some(0);
+    }
+    field.split(',').try_fold(0, |mask, name| {
+        let button = match name.to_ascii_lowercase().as_str() {
+            "right" => Button::Right,
+            "left" => Button::Left,
+            "up" => Button::Up,
+            "down" => Button::Down,
+            "a" => Button::A,
+            "b" => Button::B,
+            "select" => Button::Select,
+            "start" => Button::Start,
+            _ => return None,
+        };
+        Some(mask | button as u8)
+    })
+}
+
+/// Bring a CPU that has just been created to the given start condition,
+/// with the real time clock at the given seconds when starting from power on
+fn apply_start(cpu: &mut Cpu, start: &Start, rtc_seed: u64) -> Result<(), io::Error> {
+    match start {
+        Start::State(state) => cpu.load_state(state),
+        Start::PowerOn | Start::SaveRam(_) if cpu.dots() != 0 => Err(invalid_data(
+            "The movie starts from power on, but the machine has already run".to_string(),
+        )),
+        Start::PowerOn => {
+            cpu.set_rtc_seconds(rtc_seed);
+            Ok(())
+        }
+        Start::SaveRam(save_ram) => {
+            cpu.load_save_ram(save_ram);
+            cpu.set_rtc_seconds(rtc_seed);
+            Ok(())
+        }
+    }
+}
+
+/// Return the CRC-32 of the framebuffer, as little endian RGB555 colors
+fn frame_hash(cpu: &Cpu) -> u32 {
+    let bytes: Vec<u8> = cpu
+        .framebuffer()
+        .iter()
+        .flat_map(|color| color.to_le_bytes())
+        .collect();
+    crc32(&bytes)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Code that keeps copying the buttons of the joypad into BGP, so that
+    /// the screen changes with them
+    const MIRROR: [u8; 10] = [
+        0x3E, 0x10, 0xE0, 0x00, // LD A,0x10; LDH (P1),A
+        0xF0, 0x00, 0xE0, 0x47, // LDH A,(P1); LDH (BGP),A
+        0x18, 0xF6, // JR -10
+    ];
+
+    #[test]
+    fn recorded_movies_play_back_in_sync() {
+        let script = InputScript::parse("10 a\n20 -\n").unwrap();
+        let mut cpu = Cpu::with_code(&MIRROR);
+        let mut recorder = MoviePlayer::record(&mut cpu, Start::PowerOn, 0, 5).unwrap();
+        for frame in 0..30 {
+            recorder
+                .run_frame(&mut cpu, Some(script.pressed(frame)))
+                .unwrap();
+        }
+        let movie = Movie::parse(&recorder.movie().to_bytes()).unwrap();
+        assert_eq!(&movie, recorder.movie());
+        assert_eq!(movie.hashes.len(), 6);
+        assert_ne!(movie.hashes[1], movie.hashes[2], "the input does not show");
+
+        let mut cpu = Cpu::with_code(&MIRROR);
+        let mut player = MoviePlayer::play(&mut cpu, movie.clone(), Mode::ReadOnly).unwrap();
+        while !player.is_finished() {
+            player.run_frame(&mut cpu, None).unwrap();
+        }
+        assert_eq!(player.frame(), 30);
+
+        let mut desynced = movie;
+        desynced.frames[10..20]
+            .iter_mut()
+            .for_each(|pressed| *pressed = [0; MAX_PLAYERS]);
+        let mut cpu = Cpu::with_code(&MIRROR);
+        let mut player = MoviePlayer::play(&mut cpu, desynced, Mode::ReadOnly).unwrap();
+        let result = (0..30).try_for_each(|_| player.run_frame(&mut cpu, None));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn input_script_holds_buttons_until_the_next_change() {
+        let script =
+            InputScript::parse("# Start, then hold A and Up on joypad 2\n10 Start\n20 - a,up\n")
+                .unwrap();
+        assert_eq!(script.pressed(0), [0; MAX_PLAYERS]);
+        assert_eq!(script.pressed(15), [0x80, 0, 0, 0]);
+        assert_eq!(script.pressed(500), [0, 0x14, 0, 0]);
+        assert!(InputScript::parse("10 a\n5 b\n").is_err());
+        assert!(InputScript::parse("10 jump\n").is_err());
+        assert!(InputScript::parse("10 - - - - a\n").is_err());
+    }
+}
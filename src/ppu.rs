@@ -0,0 +1,815 @@
+use std::io;
+
+use crate::memory::{ColorMode, Interrupt};
+use crate::state::{StateReader, StateWriter};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const VRAM_BANK_SIZE: usize = 0x2000;
+const VRAM_SIZE: usize = VRAM_BANK_SIZE * 2;
+const OAM_SIZE: usize = 0xA0;
+
+const DOTS_PER_LINE: u32 = 456;
+const OAM_SCAN_DOTS: u32 = 80;
+const DRAWING_MIN_DOTS: u32 = 172;
+const VBLANK_LINE: u8 = 144;
+const LINES_PER_FRAME: u8 = 154;
+pub const DOTS_PER_FRAME: u32 = DOTS_PER_LINE * LINES_PER_FRAME as u32;
+const MAX_SPRITES_PER_LINE: usize = 10;
+const PALETTE_RAM_SIZE: usize = 0x40;
+
+/// The four shades of the DMG in RGB555, from white to black
+const DMG_COLORS: [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+// Dots into a line after which LY is compared against LYC again
+const LY_COMPARE_DELAY: u32 = 4;
+
+// STAT interrupt source bits
+const STAT_HBLANK: u8 = 0x08;
+const STAT_VBLANK: u8 = 0x10;
+const STAT_OAM_SCAN: u8 = 0x20;
+const STAT_LYC: u8 = 0x40;
+
+/// The mode the PPU is in, as reported in the lower two bits of STAT
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+/// Picture Processing Unit (PPU)
+///
+/// Steps through the OAM scan, drawing, HBlank and VBlank modes of each
+/// line, rendering the line from VRAM and OAM into the framebuffer. It owns
+/// VRAM and OAM, since which of the two the CPU may access at any given
+/// time depends on the mode the PPU is in.
+pub struct Ppu {
+    vram: [u8; VRAM_SIZE],
+    // VRAM bank the CPU accesses (VBK), which is always 0 on the DMG
+    vram_bank: usize,
+    oam: [u8; OAM_SIZE],
+    lcdc: u8,
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    mode: Mode,
+    color_mode: ColorMode,
+    // Value LY is being compared against for the coincidence flag, which is
+    // None for a few dots after LY changes
+    ly_compare: Option<u8>,
+    // State of the internal STAT interrupt line, which is the OR of all the
+    // enabled sources. Interrupts are only requested on its rising edge.
+    stat_line: bool,
+    // Interrupts requested since the last tick, as a mask of IF bits
+    interrupts: u8,
+    // Dot within the current line, from 0 to 455
+    line_dot: u32,
+    // Dot of the current line at which mode 3 ends
+    drawing_end: u32,
+    // Internal line counter of the window, which only advances on lines
+    // where the window was actually drawn
+    window_line: u8,
+    // CGB palette memory (BCPS/BCPD and OCPS/OCPD)
+    bg_palettes: PaletteRam,
+    obj_palettes: PaletteRam,
+    // CGB object priority mode (OPRI), with bit 0 set to prioritize sprites
+    // by X coordinate like the DMG does
+    opri: u8,
+    framebuffer: [u16; SCREEN_WIDTH * SCREEN_HEIGHT],
+    // Shades of the DMG output, before they become colors
+    shades: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    frame_ready: bool,
+    hblank_started: bool,
+    vblank_started: bool,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Self {
+            vram: [0; VRAM_SIZE],
+            vram_bank: 0,
+            oam: [0; OAM_SIZE],
+            lcdc: 0x91,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+            mode: Mode::OamScan,
+            color_mode: ColorMode::NoColor,
+            ly_compare: Some(0),
+            stat_line: false,
+            interrupts: 0,
+            line_dot: 0,
+            drawing_end: OAM_SCAN_DOTS + DRAWING_MIN_DOTS,
+            window_line: 0,
+            bg_palettes: PaletteRam::new(),
+            obj_palettes: PaletteRam::new(),
+            opri: 0,
+            framebuffer: [DMG_COLORS[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            shades: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_ready: false,
+            hblank_started: false,
+            vblank_started: false,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn set_color_mode(&mut self, color_mode: ColorMode) {
+        self.color_mode = color_mode;
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcdc & 0x80 != 0
+    }
+
+    /// Whether the CPU can currently access VRAM. It is locked while the
+    /// PPU is drawing, from the dot the mode goes from 2 to 3 until the one
+    /// it goes to 0. The locks follow the mode as STAT reports it, so the
+    /// few dots by which the hardware locks the bus ahead of the mode
+    /// changing are not emulated.
+    pub fn vram_accessible(&self) -> bool {
+        self.mode != Mode::Drawing
+    }
+
+    /// Whether the CPU can currently access OAM. It is locked from the start
+    /// of the OAM scan until the mode goes from 3 to 0. The first line after
+    /// the LCD is switched on has no OAM scan, so there OAM stays accessible
+    /// until the mode goes to 3.
+    pub fn oam_accessible(&self) -> bool {
+        !matches!(self.mode, Mode::OamScan | Mode::Drawing)
+    }
+
+    /// Read a byte from VRAM as seen by the CPU, which gets 0xFF while locked
+    pub fn read_vram(&self, address: u16) -> u8 {
+        if !self.vram_accessible() {
+            return 0xFF;
+        }
+        self.vram[self.vram_offset(address)]
+    }
+
+    /// Write a byte to VRAM from the CPU, which is dropped while locked
+    pub fn write_vram(&mut self, address: u16, value: u8) {
+        if self.vram_accessible() {
+            self.vram[self.vram_offset(address)] = value;
+        }
+    }
+
+    /// Return the offset into VRAM of an address in the currently selected bank
+    fn vram_offset(&self, address: u16) -> usize {
+        self.vram_bank * VRAM_BANK_SIZE + (address as usize & (VRAM_BANK_SIZE - 1))
+    }
+
+    /// Read a byte from OAM as seen by the CPU, which gets 0xFF while locked
+    pub fn read_oam(&self, address: u16) -> u8 {
+        if !self.oam_accessible() {
+            return 0xFF;
+        }
+        self.oam[address as usize - 0xFE00]
+    }
+
+    /// Write a byte to OAM from the CPU, which is dropped while locked
+    pub fn write_oam(&mut self, address: u16, value: u8) {
+        if self.oam_accessible() {
+            self.oam[address as usize - 0xFE00] = value;
+        }
+    }
+
+    /// Read a byte from VRAM for a DMA transfer, regardless of the mode
+    pub fn read_vram_dma(&self, address: u16) -> u8 {
+        self.vram[self.vram_offset(address)]
+    }
+
+    /// Write a byte copied by the HDMA, which does not wait for the PPU
+    pub fn write_vram_dma(&mut self, address: u16, value: u8) {
+        let offset = self.vram_offset(address);
+        self.vram[offset] = value;
+    }
+
+    /// Write a byte copied by the OAM DMA, which has priority over the PPU
+    pub fn write_oam_dma(&mut self, offset: u16, value: u8) {
+        self.oam[offset as usize] = value;
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF40 => self.lcdc,
+            0xFF41 => {
+                let coincidence = if self.coincidence() { 0x04 } else { 0 };
+                0x80 | self.stat | coincidence | self.mode as u8
+            }
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly_register(),
+            0xFF45 => self.lyc,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            0xFF4F if self.is_color() => 0xFE | self.vram_bank as u8,
+            // Palette memory is locked while drawing, just like VRAM
+            0xFF68 if self.is_color() => self.bg_palettes.read_index(),
+            0xFF69 if self.is_color() && self.vram_accessible() => self.bg_palettes.read_data(),
+            0xFF6A if self.is_color() => self.obj_palettes.read_index(),
+            0xFF6B if self.is_color() && self.vram_accessible() => self.obj_palettes.read_data(),
+            0xFF6C if self.is_color() => 0xFE | self.opri,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF40 => self.write_lcdc(value),
+            0xFF41 => self.write_stat(value),
+            0xFF42 => self.scy = value,
+            0xFF43 => self.scx = value,
+            0xFF45 => {
+                self.lyc = value;
+                self.update_stat_line();
+            }
+            0xFF47 => self.bgp = value,
+            0xFF48 => self.obp0 = value,
+            0xFF49 => self.obp1 = value,
+            0xFF4A => self.wy = value,
+            0xFF4B => self.wx = value,
+            0xFF4F if self.is_color() => self.vram_bank = (value & 1) as usize,
+            0xFF68 if self.is_color() => self.bg_palettes.write_index(value),
+            0xFF69 if self.is_color() => {
+                let locked = !self.vram_accessible();
+                self.bg_palettes.write_data(value, locked);
+            }
+            0xFF6A if self.is_color() => self.obj_palettes.write_index(value),
+            0xFF6B if self.is_color() => {
+                let locked = !self.vram_accessible();
+                self.obj_palettes.write_data(value, locked);
+            }
+            0xFF6C if self.is_color() => self.opri = value & 0x01,
+            _ => {}
+        }
+    }
+
+    fn write_stat(&mut self, value: u8) {
+        if self.color_mode == ColorMode::NoColor && self.lcd_enabled() {
+            // On the DMG, writing STAT enables every source for one cycle,
+            // which triggers an interrupt in HBlank, VBlank or on LY=LYC
+            self.stat = STAT_HBLANK | STAT_VBLANK | STAT_LYC;
+            self.update_stat_line();
+        }
+        self.stat = value & 0x78;
+        self.update_stat_line();
+    }
+
+    fn write_lcdc(&mut self, value: u8) {
+        let was_enabled = self.lcd_enabled();
+        self.lcdc = value;
+        if was_enabled && !self.lcd_enabled() {
+            // Switching the LCD off resets LY and unlocks VRAM and OAM
+            self.ly = 0;
+            self.line_dot = 0;
+            self.window_line = 0;
+            self.mode = Mode::HBlank;
+            self.stat_line = false;
+        } else if !was_enabled && self.lcd_enabled() {
+            // The first line after switching the LCD on has no OAM scan
+            self.line_dot = 0;
+            self.mode = Mode::HBlank;
+            self.ly_compare = Some(0);
+            self.update_stat_line();
+        }
+    }
+
+    /// Return the last complete frame as RGB555 colors
+    pub fn framebuffer(&self) -> &[u16] {
+        &self.framebuffer
+    }
+
+    /// Return the shades of the last complete frame in DMG mode, from 0 for
+    /// white to 3 for black, which the Super Game Boy colors in
+    pub fn shades(&self) -> &[u8] {
+        &self.shades
+    }
+
+    /// Return whether a new frame was completed since the last call
+    pub fn take_frame(&mut self) -> bool {
+        std::mem::replace(&mut self.frame_ready, false)
+    }
+
+    /// Return whether HBlank began on a visible line since the last call
+    pub fn take_hblank_start(&mut self) -> bool {
+        std::mem::replace(&mut self.hblank_started, false)
+    }
+
+    /// Return whether VBlank began since the last call, which is separate
+    /// from `take_frame` for the hardware that follows the frames
+    pub fn take_vblank_start(&mut self) -> bool {
+        std::mem::replace(&mut self.vblank_started, false)
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.vram);
+        writer.write_u32(self.vram_bank as u32);
+        writer.write_bytes(&self.oam);
+        for &register in &[
+            self.lcdc, self.stat, self.scy, self.scx, self.lyc, self.bgp, self.obp0, self.obp1,
+            self.wy, self.wx, self.opri,
+        ] {
+            writer.write_u8(register);
+        }
+        writer.write_u32(self.ly as u32);
+        writer.write_u32(self.mode as u32);
+        writer.write_option(self.ly_compare, StateWriter::write_u8);
+        writer.write_bool(self.stat_line);
+        writer.write_u8(self.interrupts);
+        writer.write_u32(self.line_dot);
+        writer.write_u32(self.drawing_end);
+        writer.write_u8(self.window_line);
+        for palettes in &[&self.bg_palettes, &self.obj_palettes] {
+            writer.write_bytes(&palettes.data);
+            writer.write_u8(palettes.index);
+        }
+        writer.write_words(&self.framebuffer);
+        writer.write_bytes(&self.shades);
+        writer.write_bool(self.frame_ready);
+        writer.write_bool(self.hblank_started);
+        writer.write_bool(self.vblank_started);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        reader.read_bytes(&mut self.vram)?;
+        self.vram_bank = reader.read_below(2)? as usize;
+        reader.read_bytes(&mut self.oam)?;
+        for register in [
+            &mut self.lcdc,
+            &mut self.stat,
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.lyc,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+            &mut self.opri,
+        ] {
+            *register = reader.read_u8()?;
+        }
+        self.ly = reader.read_below(LINES_PER_FRAME as u32)? as u8;
+        self.mode = match reader.read_below(4)? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OamScan,
+            _ => Mode::Drawing,
+        };
+        self.ly_compare = reader.read_option(StateReader::read_u8)?;
+        self.stat_line = reader.read_bool()?;
+        self.interrupts = reader.read_u8()?;
+        self.line_dot = reader.read_below(DOTS_PER_LINE)?;
+        self.drawing_end = reader.read_below(DOTS_PER_LINE)?;
+        self.window_line = reader.read_u8()?;
+        for palettes in [&mut self.bg_palettes, &mut self.obj_palettes] {
+            reader.read_bytes(&mut palettes.data)?;
+            palettes.index = reader.read_u8()? & 0xBF;
+        }
+        reader.read_words(&mut self.framebuffer)?;
+        reader.read_bytes(&mut self.shades)?;
+        self.frame_ready = reader.read_bool()?;
+        self.hblank_started = reader.read_bool()?;
+        self.vblank_started = reader.read_bool()?;
+        Ok(())
+    }
+
+    pub fn vram(&self) -> &[u8] {
+        &self.vram
+    }
+
+    pub fn oam(&self) -> &[u8] {
+        &self.oam
+    }
+
+    /// Return the CGB background and sprite palette memory
+    pub fn palette_data(&self) -> (&[u8], &[u8]) {
+        (&self.bg_palettes.data, &self.obj_palettes.data)
+    }
+
+    /// Fill VRAM, OAM and the CGB palette memory, as far as the given data
+    /// goes, for loading states of other emulators
+    pub fn restore_memory(
+        &mut self,
+        vram: &[u8],
+        oam: &[u8],
+        bg_palettes: &[u8],
+        obj_palettes: &[u8],
+    ) {
+        for (memory, data) in [
+            (&mut self.vram[..], vram),
+            (&mut self.oam[..], oam),
+            (&mut self.bg_palettes.data[..], bg_palettes),
+            (&mut self.obj_palettes.data[..], obj_palettes),
+        ] {
+            let length = memory.len().min(data.len());
+            memory[..length].copy_from_slice(&data[..length]);
+        }
+    }
+
+    /// Move the LCD to the start of the given line, for loading states of
+    /// other emulators, which only have LY
+    pub fn set_ly(&mut self, ly: u8) {
+        if !self.lcd_enabled() {
+            return;
+        }
+        self.ly = ly % LINES_PER_FRAME;
+        self.line_dot = 0;
+        self.mode = if self.ly >= VBLANK_LINE {
+            Mode::VBlank
+        } else {
+            Mode::OamScan
+        };
+        self.ly_compare = Some(self.ly);
+        // The STAT line was already in this state, so no interrupt is due
+        self.update_stat_line();
+        self.interrupts = 0;
+    }
+
+    /// Advance the PPU by the given number of dots, returning the
+    /// interrupts it requested as a mask of IF bits
+    pub fn tick(&mut self, dots: u32) -> u8 {
+        if self.lcd_enabled() {
+            for _ in 0..dots {
+                self.tick_dot();
+                self.update_stat_line();
+            }
+        }
+        std::mem::take(&mut self.interrupts)
+    }
+
+    /// Return the value of LY as read by the CPU. Line 153 only reports
+    /// itself for a few dots, and then reads as line 0 for the rest of it.
+    fn ly_register(&self) -> u8 {
+        if self.ly == LINES_PER_FRAME - 1 && self.line_dot >= LY_COMPARE_DELAY * 2 {
+            0
+        } else {
+            self.ly
+        }
+    }
+
+    /// Recompute the internal STAT interrupt line from the enabled sources,
+    /// requesting the interrupt if it went from low to high. While the line
+    /// stays high, no other source can trigger the interrupt ("STAT blocking").
+    fn update_stat_line(&mut self) {
+        let line = self.lcd_enabled()
+            && ((self.stat & STAT_HBLANK != 0 && self.mode == Mode::HBlank)
+                || (self.stat & STAT_VBLANK != 0 && self.mode == Mode::VBlank)
+                || (self.stat & STAT_OAM_SCAN != 0 && self.oam_scan_starting())
+                || (self.stat & STAT_LYC != 0 && self.coincidence()));
+        if line && !self.stat_line {
+            self.interrupts |= Interrupt::Stat as u8;
+        }
+        self.stat_line = line;
+    }
+
+    /// Whether the LY=LYC coincidence flag is set
+    fn coincidence(&self) -> bool {
+        self.ly_compare == Some(self.lyc)
+    }
+
+    /// Whether the mode 2 interrupt source is active, which is during the
+    /// OAM scan and also on the first dot of VBlank
+    fn oam_scan_starting(&self) -> bool {
+        self.mode == Mode::OamScan || (self.ly == VBLANK_LINE && self.line_dot == 0)
+    }
+
+    fn tick_dot(&mut self) {
+        self.line_dot += 1;
+        let last_line = self.ly == LINES_PER_FRAME - 1;
+        match self.line_dot {
+            LY_COMPARE_DELAY => self.ly_compare = Some(self.ly),
+            // LY switches to 0 early on line 153, and after the usual
+            // delay it is compared against LYC as such
+            dot if last_line && dot == LY_COMPARE_DELAY * 2 => self.ly_compare = None,
+            dot if last_line && dot == LY_COMPARE_DELAY * 3 => self.ly_compare = Some(0),
+            _ => {}
+        }
+        if self.ly < VBLANK_LINE {
+            if self.line_dot == OAM_SCAN_DOTS {
+                self.mode = Mode::Drawing;
+                self.drawing_end = OAM_SCAN_DOTS + DRAWING_MIN_DOTS + self.render_line();
+            } else if self.mode == Mode::Drawing && self.line_dot == self.drawing_end {
+                self.mode = Mode::HBlank;
+                self.hblank_started = true;
+            }
+        }
+        if self.line_dot == DOTS_PER_LINE {
+            self.line_dot = 0;
+            self.ly += 1;
+            if self.ly == LINES_PER_FRAME {
+                // Line 153 has been compared as line 0 already
+                self.ly = 0;
+                self.window_line = 0;
+            } else {
+                self.ly_compare = None;
+            }
+            if self.ly < VBLANK_LINE {
+                self.mode = Mode::OamScan;
+            } else if self.ly == VBLANK_LINE {
+                self.mode = Mode::VBlank;
+                self.frame_ready = true;
+                self.vblank_started = true;
+                self.interrupts |= Interrupt::VBlank as u8;
+            }
+        }
+    }
+
+    /// Render the current line into the framebuffer, returning the number of
+    /// extra dots that mode 3 takes on top of its minimum length
+    fn render_line(&mut self) -> u32 {
+        let mut penalty = self.scx as u32 % 8;
+        let mut bg = [BgPixel::default(); SCREEN_WIDTH];
+        let row = self.ly as usize * SCREEN_WIDTH;
+
+        // On the DMG, LCDC bit 0 hides the background and the window, while
+        // on the CGB they are always drawn and it takes away their priority
+        if self.lcdc & 0x01 != 0 || self.is_color() {
+            let y = self.ly.wrapping_add(self.scy);
+            let map = if self.lcdc & 0x08 != 0 {
+                0x1C00
+            } else {
+                0x1800
+            };
+            for (x, pixel) in bg.iter_mut().enumerate() {
+                let x = (x as u8).wrapping_add(self.scx);
+                *pixel = self.tile_map_pixel(map, x, y);
+            }
+            let window_x = self.wx as i32 - 7;
+            if self.lcdc & 0x20 != 0 && self.ly >= self.wy && window_x < SCREEN_WIDTH as i32 {
+                let map = if self.lcdc & 0x40 != 0 {
+                    0x1C00
+                } else {
+                    0x1800
+                };
+                for x in window_x.max(0)..SCREEN_WIDTH as i32 {
+                    let wx = (x - window_x) as u8;
+                    bg[x as usize] = self.tile_map_pixel(map, wx, self.window_line);
+                }
+                self.window_line += 1;
+                penalty += 6;
+            }
+        }
+        for (x, pixel) in bg.iter().enumerate() {
+            self.framebuffer[row + x] = if self.is_color() {
+                self.bg_palettes.color(pixel.attributes & 0x07, pixel.color)
+            } else {
+                let shade = apply_palette(self.bgp, pixel.color);
+                self.shades[row + x] = shade;
+                DMG_COLORS[shade as usize]
+            };
+        }
+
+        if self.lcdc & 0x02 != 0 {
+            let mut sprites = self.line_sprites();
+            for &sprite in &sprites {
+                let x = self.oam[sprite + 1] as u32;
+                penalty += 11 - ((x + self.scx as u32) % 8).min(5);
+            }
+            // Sprites that come first in OAM are drawn on top, except in DMG
+            // mode or when OPRI asks for it, where the ones with a lower X
+            // coordinate win instead and OAM order only breaks ties. The first
+            // opaque sprite pixel claims its column even when it ends up
+            // behind the background.
+            if !self.is_color() || self.opri & 0x01 != 0 {
+                sprites.sort_by_key(|&sprite| (self.oam[sprite + 1], sprite));
+            }
+            let mut claimed = [false; SCREEN_WIDTH];
+            for &sprite in &sprites {
+                self.render_sprite(sprite, &bg, &mut claimed);
+            }
+        }
+        penalty
+    }
+
+    fn is_color(&self) -> bool {
+        self.color_mode == ColorMode::Color
+    }
+
+    /// Return a pixel of the background or window tile map. In CGB mode, the
+    /// attributes of each tile are at the same position of the map in bank 1.
+    fn tile_map_pixel(&self, map: usize, x: u8, y: u8) -> BgPixel {
+        let index = map + (y as usize / 8) * 32 + x as usize / 8;
+        let tile = self.vram[index];
+        let attributes = if self.is_color() {
+            self.vram[VRAM_BANK_SIZE + index]
+        } else {
+            0
+        };
+        let bank = if attributes & 0x08 != 0 {
+            VRAM_BANK_SIZE
+        } else {
+            0
+        };
+        let tile_x = if attributes & 0x20 != 0 {
+            7 - x % 8
+        } else {
+            x % 8
+        };
+        let tile_y = if attributes & 0x40 != 0 {
+            7 - y % 8
+        } else {
+            y % 8
+        };
+        BgPixel {
+            color: self.tile_pixel(bank + self.tile_address(tile), tile_x, tile_y),
+            attributes,
+        }
+    }
+
+    /// Return the address in VRAM of the background or window tile with the given index
+    fn tile_address(&self, tile: u8) -> usize {
+        if self.lcdc & 0x10 != 0 {
+            tile as usize * 16
+        } else {
+            (0x1000 + (tile as i8 as i32) * 16) as usize
+        }
+    }
+
+    fn tile_pixel(&self, tile_address: usize, x: u8, y: u8) -> u8 {
+        let lo = self.vram[tile_address + y as usize * 2];
+        let hi = self.vram[tile_address + y as usize * 2 + 1];
+        let bit = 7 - x;
+        (((hi >> bit) & 1) << 1) | ((lo >> bit) & 1)
+    }
+
+    fn sprite_height(&self) -> u8 {
+        if self.lcdc & 0x04 != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// Return the OAM offsets of the sprites on the current line, at most 10
+    /// and in OAM order
+    fn line_sprites(&self) -> Vec<usize> {
+        let height = self.sprite_height() as i32;
+        let ly = self.ly as i32;
+        (0..OAM_SIZE)
+            .step_by(4)
+            .filter(|&sprite| {
+                let y = self.oam[sprite] as i32 - 16;
+                ly >= y && ly < y + height
+            })
+            .take(MAX_SPRITES_PER_LINE)
+            .collect()
+    }
+
+    fn render_sprite(
+        &mut self,
+        sprite: usize,
+        bg: &[BgPixel; SCREEN_WIDTH],
+        claimed: &mut [bool; SCREEN_WIDTH],
+    ) {
+        let y = self.oam[sprite] as i32 - 16;
+        let x = self.oam[sprite + 1] as i32 - 8;
+        let mut tile = self.oam[sprite + 2];
+        let flags = self.oam[sprite + 3];
+        let height = self.sprite_height();
+        if height == 16 {
+            tile &= 0xFE;
+        }
+        let mut line = (self.ly as i32 - y) as u8;
+        if flags & 0x40 != 0 {
+            line = height - 1 - line;
+        }
+        let bank = if self.is_color() && flags & 0x08 != 0 {
+            VRAM_BANK_SIZE
+        } else {
+            0
+        };
+        let row = self.ly as usize * SCREEN_WIDTH;
+        for px in 0..8 {
+            let screen_x = x + px as i32;
+            if screen_x < 0 || screen_x >= SCREEN_WIDTH as i32 || claimed[screen_x as usize] {
+                continue;
+            }
+            let tile_x = if flags & 0x20 != 0 { 7 - px } else { px };
+            let color = self.tile_pixel(bank + tile as usize * 16, tile_x, line);
+            if color == 0 {
+                continue;
+            }
+            claimed[screen_x as usize] = true;
+            if self.bg_has_priority(bg[screen_x as usize], flags) {
+                continue;
+            }
+            self.framebuffer[row + screen_x as usize] = if self.is_color() {
+                self.obj_palettes.color(flags & 0x07, color)
+            } else {
+                let palette = if flags & 0x10 != 0 {
+                    self.obp1
+                } else {
+                    self.obp0
+                };
+                let shade = apply_palette(palette, color);
+                self.shades[row + screen_x as usize] = shade;
+                DMG_COLORS[shade as usize]
+            };
+        }
+    }
+
+    /// Whether the background pixel is drawn over an opaque sprite pixel with
+    /// the given flags. Color 0 of the background is always behind sprites.
+    /// In CGB mode, either the sprite or the tile attributes can give the
+    /// background priority, but clearing LCDC bit 0 overrides both.
+    fn bg_has_priority(&self, pixel: BgPixel, flags: u8) -> bool {
+        if pixel.color == 0 {
+            return false;
+        }
+        if self.is_color() {
+            self.lcdc & 0x01 != 0 && (flags & 0x80 != 0 || pixel.attributes & 0x80 != 0)
+        } else {
+            flags & 0x80 != 0
+        }
+    }
+}
+
+/// A pixel of the background or window, before going through a palette
+#[derive(Clone, Copy, Default)]
+struct BgPixel {
+    color: u8,
+    // Attributes of the tile it belongs to, which are always 0 on the DMG
+    attributes: u8,
+}
+
+/// CGB palette memory for either the background or sprites, with eight
+/// palettes of four RGB555 colors each. It is accessed through an index
+/// register (BCPS/OCPS) and a data register (BCPD/OCPD).
+struct PaletteRam {
+    data: [u8; PALETTE_RAM_SIZE],
+    // Byte selected by the index register, with bit 7 set to increment it
+    // after every write to the data register
+    index: u8,
+}
+
+impl PaletteRam {
+    fn new() -> Self {
+        Self {
+            data: [0xFF; PALETTE_RAM_SIZE],
+            index: 0,
+        }
+    }
+
+    fn read_index(&self) -> u8 {
+        0x40 | self.index
+    }
+
+    fn write_index(&mut self, value: u8) {
+        self.index = value & 0xBF;
+    }
+
+    fn read_data(&self) -> u8 {
+        self.data[(self.index & 0x3F) as usize]
+    }
+
+    /// Write to the selected byte, or only advance the index if the
+    /// palettes are locked because the PPU is drawing
+    fn write_data(&mut self, value: u8, locked: bool) {
+        if !locked {
+            self.data[(self.index & 0x3F) as usize] = value;
+        }
+        if self.index & 0x80 != 0 {
+            self.index = 0x80 | (self.index.wrapping_add(1) & 0x3F);
+        }
+    }
+
+    /// Return a color of one of the palettes in RGB555
+    fn color(&self, palette: u8, color: u8) -> u16 {
+        let offset = palette as usize * 8 + color as usize * 2;
+        (self.data[offset] as u16 | (self.data[offset + 1] as u16) << 8) & 0x7FFF
+    }
+}
+
+/// Map a color index to a shade through a DMG palette register
+fn apply_palette(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0x03
+}
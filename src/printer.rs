@@ -0,0 +1,344 @@
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use crate::image::write_pgm;
+use crate::serial::SerialDevice;
+
+const PAPER_WIDTH: usize = 160;
+// Bytes of image data per row of 20 tiles, which is 8 rows of pixels
+const TILE_ROW_SIZE: usize = 20 * 16;
+// The printer holds up to 9 data packets of 2 rows of tiles each
+const BUFFER_SIZE: usize = 9 * 2 * TILE_ROW_SIZE;
+// Rows of paper fed per unit of the margins of a print
+const MARGIN_ROWS: usize = 16;
+// Status inquiries the printer answers as busy after a print
+const PRINT_BUSY_INQUIRIES: u32 = 4;
+// Gray levels of the 4 shades, from white to black
+const SHADES: [f32; 4] = [0.0, 1.0 / 3.0, 2.0 / 3.0, 1.0];
+// Exposure of a print that leaves the shades as they are
+const NORMAL_EXPOSURE: u8 = 0x40;
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+const ALIVE: u8 = 0x81;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_BUSY: u8 = 0x02;
+const STATUS_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+const STATUS_PACKET_ERROR: u8 = 0x10;
+
+/// Part of a packet the next byte belongs to
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Magic(usize),
+    Command,
+    Compression,
+    Length(usize),
+    Data,
+    Checksum(usize),
+    Alive,
+    Status,
+}
+
+/// Game Boy Printer, which prints onto a strip of paper saved as a PGM image
+///
+/// The Game Boy sends it packets made of the magic bytes 0x88 0x33, a
+/// command, a compression flag, the length of the data, the data and a
+/// checksum of everything after the magic bytes. The printer answers the
+/// 2 bytes that follow with `ALIVE` and its status. Image data is sent in
+/// tiles, 20 per row, optionally compressed with RLE, and the print command
+/// prints it with a palette, margins and exposure of its own. Every print
+/// feeds the paper further, and the whole strip is saved after each one.
+pub struct Printer {
+    path: PathBuf,
+    paper: Vec<u8>,
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    // Image data received since the last print
+    buffer: Vec<u8>,
+    status: u8,
+    busy_inquiries: u32,
+    // First error hit while saving the paper, which the device cannot return
+    error: Option<io::Error>,
+}
+
+impl Printer {
+    /// Plug in a printer with blank paper, saving it to the given file
+    pub fn create(path: &Path) -> Result<Self, io::Error> {
+        let printer = Self {
+            path: path.to_path_buf(),
+            paper: Vec::new(),
+            state: State::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            buffer: Vec::new(),
+            status: 0,
+            busy_inquiries: 0,
+            error: None,
+        };
+        printer.save()?;
+        Ok(printer)
+    }
+
+    /// Return the paper printed so far as 8-bit gray levels, 160 pixels wide
+    pub fn paper(&self) -> &[u8] {
+        &self.paper
+    }
+
+    /// Return the first error hit while saving the paper, if any
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    fn save(&self) -> Result<(), io::Error> {
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        let height = self.paper.len() / PAPER_WIDTH;
+        write_pgm(&mut writer, PAPER_WIDTH, height, &self.paper)
+    }
+
+    /// Take in the next byte of a packet, returning the printer's answer
+    fn receive(&mut self, byte: u8) -> u8 {
+        let mut answer = 0x00;
+        self.state = match self.state {
+            State::Magic(index) if byte == MAGIC[index] => match index {
+                0 => State::Magic(1),
+                _ => State::Command,
+            },
+            State::Magic(_) if byte == MAGIC[0] => State::Magic(1),
+            State::Magic(_) => State::Magic(0),
+            State::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::Length(0)
+            }
+            State::Length(0) => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::Length(1)
+            }
+            State::Length(_) => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                match self.length {
+                    0 => State::Checksum(0),
+                    _ => State::Data,
+                }
+            }
+            State::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length as usize {
+                    State::Checksum(0)
+                } else {
+                    State::Data
+                }
+            }
+            State::Checksum(0) => {
+                self.checksum ^= byte as u16;
+                State::Checksum(1)
+            }
+            State::Checksum(_) => {
+                self.checksum ^= (byte as u16) << 8;
+                State::Alive
+            }
+            State::Alive => {
+                answer = ALIVE;
+                State::Status
+            }
+            State::Status => {
+                // The checksum was XORed with the one received, so it is 0
+                // if they match
+                if self.checksum == 0 {
+                    self.status &= !STATUS_CHECKSUM_ERROR;
+                    self.execute();
+                } else {
+                    self.status |= STATUS_CHECKSUM_ERROR;
+                }
+                answer = self.status;
+                State::Magic(0)
+            }
+        };
+        answer
+    }
+
+    fn execute(&mut self) {
+        self.status &= !STATUS_PACKET_ERROR;
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy_inquiries = 0;
+            }
+            COMMAND_DATA => {
+                let data = std::mem::take(&mut self.data);
+                if self.compressed {
+                    decompress(&data, &mut self.buffer);
+                } else {
+                    self.buffer.extend_from_slice(&data);
+                }
+                self.buffer.truncate(BUFFER_SIZE);
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+                if self.buffer.len() == BUFFER_SIZE {
+                    self.status |= STATUS_FULL;
+                }
+            }
+            COMMAND_PRINT if self.data.len() == 4 => {
+                let [sheets, margins, palette, exposure] =
+                    [self.data[0], self.data[1], self.data[2], self.data[3]];
+                self.print(sheets, margins, palette, exposure);
+                self.buffer.clear();
+                self.status &= !(STATUS_UNPROCESSED | STATUS_FULL);
+                self.status |= STATUS_BUSY;
+                self.busy_inquiries = PRINT_BUSY_INQUIRIES;
+            }
+            COMMAND_STATUS => {
+                if self.busy_inquiries > 0 {
+                    self.busy_inquiries -= 1;
+                    if self.busy_inquiries == 0 {
+                        self.status &= !STATUS_BUSY;
+                    }
+                }
+            }
+            _ => self.status |= STATUS_PACKET_ERROR,
+        }
+    }
+
+    /// Print the buffer the given number of times, which only feeds the
+    /// paper through the margins if 0, and save the paper
+    fn print(&mut self, sheets: u8, margins: u8, palette: u8, exposure: u8) {
+        self.feed((margins >> 4) as usize * MARGIN_ROWS);
+        // A palette of 0 prints as the usual one
+        let palette = if palette == 0 { 0xE4 } else { palette };
+        // Exposure ranges from 25% lighter to 25% darker
+        let darkness = 1.0 + ((exposure & 0x7F) as f32 - NORMAL_EXPOSURE as f32) / 256.0;
+        let rows = self.buffer.len() / TILE_ROW_SIZE * 8;
+        for _ in 0..sheets {
+            for y in 0..rows {
+                for x in 0..PAPER_WIDTH {
+                    let tile = y / 8 * 20 + x / 8;
+                    let offset = tile * 16 + y % 8 * 2;
+                    let bit = 7 - x % 8;
+                    let color = ((self.buffer[offset + 1] >> bit) & 1) << 1
+                        | ((self.buffer[offset] >> bit) & 1);
+                    let shade = (palette >> (color * 2)) & 0x03;
+                    let level = (SHADES[shade as usize] * darkness).min(1.0);
+                    self.paper.push(255 - (level * 255.0).round() as u8);
+                }
+            }
+        }
+        self.feed((margins & 0x0F) as usize * MARGIN_ROWS);
+        if let Err(error) = self.save() {
+            self.error.get_or_insert(error);
+        }
+    }
+
+    fn feed(&mut self, rows: usize) {
+        self.paper
+            .resize(self.paper.len() + rows * PAPER_WIDTH, 0xFF);
+    }
+}
+
+impl SerialDevice for Printer {
+    fn transfer(&mut self, outgoing: u8) -> Option<u8> {
+        Some(self.receive(outgoing))
+    }
+}
+
+/// Decompress image data, which is made of runs of bytes introduced by a
+/// control byte. With bit 7 set, the next byte repeats the low bits + 2
+/// times. Otherwise, the low bits + 1 bytes that follow are copied as is.
+fn decompress(data: &[u8], output: &mut Vec<u8>) {
+    let mut bytes = data.iter();
+    while let Some(&control) = bytes.next() {
+        if control & 0x80 != 0 {
+            let count = (control & 0x7F) as usize + 2;
+            if let Some(&byte) = bytes.next() {
+                output.extend(std::iter::repeat_n(byte, count));
+            }
+        } else {
+            let count = control as usize + 1;
+            output.extend(bytes.by_ref().take(count));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    /// Send a packet to the printer, returning its two answers
+    fn send(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> [u8; 2] {
+        let length = (data.len() as u16).to_le_bytes();
+        let mut packet = vec![command, compressed as u8, length[0], length[1]];
+        packet.extend_from_slice(data);
+        let checksum = packet
+            .iter()
+            .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        for &byte in MAGIC.iter().chain(&packet).chain(&checksum.to_le_bytes()) {
+            assert_eq!(printer.transfer(byte), Some(0x00));
+        }
+        [printer.transfer(0).unwrap(), printer.transfer(0).unwrap()]
+    }
+
+    #[test]
+    fn print_compressed_tiles() {
+        let path = env::temp_dir().join("tonzoboy-printer-test.pgm");
+        let mut printer = Printer::create(&path).unwrap();
+        assert_eq!(send(&mut printer, COMMAND_INIT, false, &[]), [ALIVE, 0x00]);
+
+        // A row of tiles, the first of color 1 as it is and the rest of
+        // color 3 in runs of 128, 128 and 48 bytes
+        let mut data = vec![0x0F];
+        for _ in 0..8 {
+            data.extend_from_slice(&[0xFF, 0x00]);
+        }
+        data.extend_from_slice(&[0xFE, 0xFF, 0xFE, 0xFF, 0xAE, 0xFF]);
+        let status = send(&mut printer, COMMAND_DATA, true, &data);
+        assert_eq!(status, [ALIVE, STATUS_UNPROCESSED]);
+
+        // One sheet without margins, with the usual palette and exposure
+        let print = [0x01, 0x00, 0xE4, NORMAL_EXPOSURE];
+        let status = send(&mut printer, COMMAND_PRINT, false, &print);
+        assert_eq!(status, [ALIVE, STATUS_BUSY]);
+        for _ in 1..PRINT_BUSY_INQUIRIES {
+            let status = send(&mut printer, COMMAND_STATUS, false, &[]);
+            assert_eq!(status, [ALIVE, STATUS_BUSY]);
+        }
+        assert_eq!(
+            send(&mut printer, COMMAND_STATUS, false, &[]),
+            [ALIVE, 0x00]
+        );
+
+        assert_eq!(printer.paper().len(), 8 * PAPER_WIDTH);
+        for row in printer.paper().chunks(PAPER_WIDTH) {
+            assert_eq!(row[..8], [170; 8]);
+            assert!(row[8..].iter().all(|&level| level == 0));
+        }
+        assert!(printer.take_error().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}
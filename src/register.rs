@@ -13,6 +13,12 @@ pub struct Register {
     pub pc: u16,
 }
 
+impl Default for Register {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Register {
     pub fn new() -> Self {
         Self {
@@ -45,12 +51,13 @@ impl Register {
     }
 
     pub fn hl(&self) -> u16 {
-        make_word(self.l, self.l)
+        make_word(self.h, self.l)
     }
 
     pub fn set_af(&mut self, value: u16) {
         self.a = msb(value);
-        self.f = lsb(value);
+        // The lower nibble of F is always 0
+        self.f = lsb(value) & 0xF0;
     }
 
     pub fn set_bc(&mut self, value: u16) {
@@ -0,0 +1,281 @@
+use std::collections::VecDeque;
+use std::mem;
+
+use crate::joypad::MAX_PLAYERS;
+
+/// Snapshot to go back to, with the dots since power on at which it was
+/// taken and the joypad changes logged after it
+pub struct Snapshot {
+    pub dots: u64,
+    pub state: Vec<u8>,
+    pub inputs: Vec<(u64, [u8; MAX_PLAYERS])>,
+}
+
+/// History of the machine for running the game backwards, as save states
+/// taken at a fixed interval of frames within a memory budget
+///
+/// Only the latest snapshot is kept in full. Each one before it is kept as
+/// the XOR with the one taken after it, which is mostly zeros, compressed
+/// with run-length encoding. Going back walks the chain from the latest
+/// snapshot, and the oldest ones are dropped once over budget. The joypad
+/// state is logged whenever it changes, so that the frames between
+/// snapshots can be emulated again exactly as they went, and that log counts
+/// toward the budget too.
+pub struct Rewind {
+    interval: u64,
+    budget: usize,
+    // Dots at which each snapshot before the latest was taken, from oldest
+    // to newest, with its delta against the next one
+    deltas: VecDeque<(u64, Vec<u8>)>,
+    deltas_size: usize,
+    latest: Option<(u64, Vec<u8>)>,
+    // Dots at which the joypads changed, and what they held from then on
+    inputs: VecDeque<(u64, [u8; MAX_PLAYERS])>,
+}
+
+impl Rewind {
+    /// Keep a snapshot every given number of frames, using up to the given
+    /// number of bytes
+    pub fn new(interval: u32, budget: usize) -> Self {
+        Self {
+            interval: interval.max(1) as u64,
+            budget,
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+            latest: None,
+            inputs: VecDeque::new(),
+        }
+    }
+
+    /// Whether a snapshot is due at the given dots since power on, given
+    /// the number of dots in a frame
+    pub fn is_due(&self, dots: u64, frame_dots: u64) -> bool {
+        self.latest
+            .as_ref()
+            .is_none_or(|&(latest, _)| dots >= latest + self.interval * frame_dots)
+    }
+
+    /// Add a snapshot taken at the given dots since power on
+    pub fn push(&mut self, dots: u64, state: Vec<u8>) {
+        match self.latest.take() {
+            Some((latest_dots, latest)) if latest.len() == state.len() => {
+                let delta = compress_delta(&latest, &state);
+                self.deltas_size += delta.len();
+                self.deltas.push_back((latest_dots, delta));
+            }
+            // States only change size along with the hardware emulated,
+            // which makes the history before that useless
+            _ => self.clear(),
+        }
+        self.latest = Some((dots, state));
+        self.drop_old_inputs();
+        while self.size() > self.budget {
+            match self.deltas.pop_front() {
+                Some((_, delta)) => self.deltas_size -= delta.len(),
+                None => break,
+            }
+            self.drop_old_inputs();
+        }
+    }
+
+    /// Return the number of bytes the history takes
+    fn size(&self) -> usize {
+        let latest_size = self.latest.as_ref().map_or(0, |(_, state)| state.len());
+        let inputs_size = self.inputs.len() * mem::size_of::<(u64, [u8; MAX_PLAYERS])>();
+        self.deltas_size + latest_size + inputs_size
+    }
+
+    /// Forget the joypad changes from before the oldest snapshot, whose
+    /// joypad state is part of it
+    fn drop_old_inputs(&mut self) {
+        let oldest = self.oldest_dots();
+        while self.inputs.front().is_some_and(|&(dots, _)| dots <= oldest) {
+            self.inputs.pop_front();
+        }
+    }
+
+    /// Log the joypads holding the given buttons from the given dots on
+    pub fn record_input(&mut self, dots: u64, pressed: [u8; MAX_PLAYERS]) {
+        if self.latest.is_some() {
+            self.inputs.push_back((dots, pressed));
+        }
+    }
+
+    /// Go back to the latest snapshot taken at or before the given dots, or
+    /// the oldest one if none goes that far, forgetting the ones after it
+    pub fn go_back(&mut self, dots: u64) -> Option<Snapshot> {
+        let (mut latest_dots, mut state) = self.latest.take()?;
+        while latest_dots > dots {
+            match self.deltas.pop_back() {
+                Some((previous_dots, delta)) => {
+                    self.deltas_size -= delta.len();
+                    apply_delta(&mut state, &delta);
+                    latest_dots = previous_dots;
+                }
+                None => break,
+            }
+        }
+        self.latest = Some((latest_dots, state.clone()));
+        let inputs = self
+            .inputs
+            .iter()
+            .filter(|&&(input_dots, _)| input_dots > latest_dots)
+            .copied()
+            .collect();
+        Some(Snapshot {
+            dots: latest_dots,
+            state,
+            inputs,
+        })
+    }
+
+    /// Forget the joypad changes logged at or after the given dots
+    pub fn truncate_inputs(&mut self, dots: u64) {
+        while self
+            .inputs
+            .back()
+            .is_some_and(|&(input_dots, _)| input_dots >= dots)
+        {
+            self.inputs.pop_back();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.deltas.clear();
+        self.deltas_size = 0;
+        self.latest = None;
+        self.inputs.clear();
+    }
+
+    fn oldest_dots(&self) -> u64 {
+        match (self.deltas.front(), self.latest.as_ref()) {
+            (Some(&(dots, _)), _) | (None, Some(&(dots, _))) => dots,
+            (None, None) => 0,
+        }
+    }
+}
+
+/// Encode the XOR of two states of the same size as runs of zeros, each
+/// followed by a run of literal bytes, with their lengths as LEB128
+fn compress_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut i = 0;
+    while i < new.len() {
+        let zeros = old[i..]
+            .iter()
+            .zip(&new[i..])
+            .take_while(|(old, new)| old == new)
+            .count();
+        i += zeros;
+        let literals = old[i..]
+            .iter()
+            .zip(&new[i..])
+            .take_while(|(old, new)| old != new)
+            .count();
+        write_length(&mut delta, zeros);
+        write_length(&mut delta, literals);
+        delta.extend(
+            old[i..i + literals]
+                .iter()
+                .zip(&new[i..])
+                .map(|(old, new)| old ^ new),
+        );
+        i += literals;
+    }
+    delta
+}
+
+/// XOR a delta made by `compress_delta` into a state, turning either of the
+/// two states it was made from into the other
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    let mut delta = delta.iter().copied();
+    let mut i = 0;
+    while let Some(zeros) = read_length(&mut delta) {
+        i += zeros;
+        let literals = read_length(&mut delta).unwrap_or(0);
+        for (byte, value) in state[i..i + literals].iter_mut().zip(&mut delta) {
+            *byte ^= value;
+        }
+        i += literals;
+    }
+}
+
+fn write_length(data: &mut Vec<u8>, mut length: usize) {
+    while length >= 0x80 {
+        data.push(0x80 | (length & 0x7F) as u8);
+        length >>= 7;
+    }
+    data.push(length as u8);
+}
+
+fn read_length(data: &mut impl Iterator<Item = u8>) -> Option<usize> {
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data.next()?;
+        length |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(length);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::joypad::Button;
+    use crate::ppu::DOTS_PER_FRAME;
+
+    #[test]
+    fn delta_turns_either_state_into_the_other() {
+        let old: Vec<u8> = (0..300).map(|i| (i % 7) as u8).collect();
+        let mut new = old.clone();
+        new[0] ^= 0x01;
+        for byte in &mut new[100..250] {
+            *byte = !*byte;
+        }
+        new[299] = 0xAA;
+        let delta = compress_delta(&old, &new);
+        let mut state = old.clone();
+        apply_delta(&mut state, &delta);
+        assert_eq!(state, new);
+        apply_delta(&mut state, &delta);
+        assert_eq!(state, old);
+        assert!(compress_delta(&old, &old).len() <= 3);
+    }
+
+    /// Code that keeps changing the background palette, differently
+    /// depending on the direction buttons held
+    const CODE: [u8; 12] = [
+        0x3E, 0x20, 0xE0, 0x00, // LD A,0x20; LDH (P1),A
+        0x04, // INC B
+        0xF0, 0x00, // LDH A,(P1)
+        0xA8, // XOR B
+        0xE0, 0x47, // LDH (BGP),A
+        0x18, 0xF8, // JR -8
+    ];
+
+    #[test]
+    fn rewind_matches_a_straight_run() {
+        let mut cpu = Cpu::with_code(&CODE);
+        cpu.set_rewind(Some(Rewind::new(4, 1 << 20)));
+        cpu.run_frames(5);
+        cpu.press(Button::Right);
+        cpu.run_frames(5);
+        // Going back 3 frames goes back to the snapshot of frame 4, from
+        // before the button was pressed
+        assert_eq!(cpu.rewind(3).unwrap(), 3);
+
+        let mut straight = Cpu::with_code(&CODE);
+        straight.run_frames(5);
+        straight.press(Button::Right);
+        while straight.dots() < cpu.dots() {
+            straight.step();
+        }
+        assert_eq!(straight.dots(), cpu.dots());
+        assert!(straight.framebuffer() == cpu.framebuffer());
+        assert!(cpu.dots() > 6 * DOTS_PER_FRAME as u64);
+    }
+}
@@ -0,0 +1,306 @@
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+use crate::memory::{ColorMode, Interrupt};
+use crate::serial_log::{Clock, Exchange, SerialLog};
+use crate::state::{StateReader, StateWriter};
+
+// Cycles per bit at the normal internal clock of 8192 Hz, and at the fast
+// CGB one of 262144 Hz. Both follow the CPU speed.
+const NORMAL_BIT_CYCLES: u32 = 512;
+const FAST_BIT_CYCLES: u32 = 16;
+
+const SC_TRANSFER: u8 = 0x80;
+const SC_FAST: u8 = 0x02;
+const SC_INTERNAL_CLOCK: u8 = 0x01;
+
+/// Whatever is plugged into the other end of the link port, such as
+/// another Game Boy, a printer or a logger
+pub trait SerialDevice {
+    /// Exchange a byte clocked by the Game Boy, which shifts out the given
+    /// byte and shifts in the returned one. Devices that cannot answer right
+    /// away return None, and the transfer holds its clock until
+    /// `poll_transfer` does.
+    fn transfer(&mut self, outgoing: u8) -> Option<u8>;
+
+    /// Called while a transfer clocked by the Game Boy waits for the device
+    /// to answer, returning the byte shifted in once there is one
+    fn poll_transfer(&mut self) -> Option<u8> {
+        Some(0xFF)
+    }
+
+    /// Called while the Game Boy waits for the device to drive the clock,
+    /// with the byte it would shift out. Return the byte shifted in once the
+    /// device has clocked a whole transfer.
+    fn poll_external(&mut self, _outgoing: u8) -> Option<u8> {
+        None
+    }
+
+    /// Called before the other methods with the dots elapsed since power
+    /// on, for devices that keep time
+    fn set_time(&mut self, _dots: u64) {}
+}
+
+impl<T: SerialDevice> SerialDevice for Rc<RefCell<T>> {
+    fn transfer(&mut self, outgoing: u8) -> Option<u8> {
+        self.borrow_mut().transfer(outgoing)
+    }
+
+    fn poll_transfer(&mut self) -> Option<u8> {
+        self.borrow_mut().poll_transfer()
+    }
+
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        self.borrow_mut().poll_external(outgoing)
+    }
+
+    fn set_time(&mut self, dots: u64) {
+        self.borrow_mut().set_time(dots);
+    }
+}
+
+/// Serial port (SB and SC)
+///
+/// Setting bit 7 of SC starts a transfer, which shifts SB out one bit at a
+/// time, most significant first, while shifting in the partner's byte.
+/// With the internal clock, the Game Boy drives the transfer at the rate
+/// selected by SC. With the external clock, it waits for the partner.
+pub struct Serial {
+    data: u8,
+    control: u8,
+    color_mode: ColorMode,
+    device: Option<Box<dyn SerialDevice>>,
+    // Byte being shifted in, bits left to shift, and cycles until the
+    // next one, during a transfer on the internal clock
+    incoming: u8,
+    bits: u8,
+    bit_cycles: u32,
+    // Whether the device has yet to answer the transfer, which holds the
+    // clock until it does
+    awaiting_reply: bool,
+    interrupts: u8,
+    // Dots elapsed since power on, to timestamp the exchanges
+    dots: u64,
+    log: Option<SerialLog>,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self {
+            data: 0,
+            control: 0,
+            color_mode: ColorMode::NoColor,
+            device: None,
+            incoming: 0xFF,
+            bits: 0,
+            bit_cycles: 0,
+            awaiting_reply: false,
+            interrupts: 0,
+            dots: 0,
+            log: None,
+        }
+    }
+
+    pub fn set_color_mode(&mut self, color_mode: ColorMode) {
+        self.color_mode = color_mode;
+    }
+
+    /// Plug a device into the link port, or unplug it with `None`. With
+    /// nothing plugged in, the received bits are all 1s.
+    pub fn set_device(&mut self, device: Option<Box<dyn SerialDevice>>) {
+        self.device = device;
+    }
+
+    /// Swap the device for another, or for none, returning the previous one
+    pub fn replace_device(
+        &mut self,
+        device: Option<Box<dyn SerialDevice>>,
+    ) -> Option<Box<dyn SerialDevice>> {
+        std::mem::replace(&mut self.device, device)
+    }
+
+    /// Log every byte exchanged from now on, or stop logging with `None`
+    pub fn set_log(&mut self, log: Option<SerialLog>) {
+        self.log = log;
+    }
+
+    pub fn take_log(&mut self) -> Option<SerialLog> {
+        self.log.take()
+    }
+
+    /// Swap the log for another, or for none, returning the previous one
+    pub fn replace_log(&mut self, log: Option<SerialLog>) -> Option<SerialLog> {
+        std::mem::replace(&mut self.log, log)
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF01 => self.data,
+            0xFF02 => match self.color_mode {
+                ColorMode::Color => 0x7C | self.control,
+                ColorMode::NoColor => 0x7E | self.control,
+            },
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF01 => self.data = value,
+            0xFF02 => {
+                let mask = match self.color_mode {
+                    ColorMode::Color => SC_TRANSFER | SC_FAST | SC_INTERNAL_CLOCK,
+                    ColorMode::NoColor => SC_TRANSFER | SC_INTERNAL_CLOCK,
+                };
+                self.control = value & mask;
+                self.bits = 0;
+                self.awaiting_reply = false;
+                if self.control & (SC_TRANSFER | SC_INTERNAL_CLOCK)
+                    == SC_TRANSFER | SC_INTERNAL_CLOCK
+                {
+                    self.start_internal_transfer();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The partner shifts its byte out at the same time as the Game Boy,
+    /// so it gets the whole outgoing byte up front
+    fn start_internal_transfer(&mut self) {
+        let incoming = match self.device.as_mut() {
+            Some(device) => {
+                device.set_time(self.dots);
+                device.transfer(self.data)
+            }
+            None => Some(0xFF),
+        };
+        self.bits = 8;
+        self.bit_cycles = self.bit_period();
+        match incoming {
+            Some(incoming) => self.receive(incoming),
+            None => self.awaiting_reply = true,
+        }
+    }
+
+    /// Take the partner's byte for the transfer clocked by the Game Boy
+    fn receive(&mut self, incoming: u8) {
+        self.incoming = incoming;
+        self.awaiting_reply = false;
+        self.log_exchange(Clock::Internal, incoming);
+    }
+
+    fn bit_period(&self) -> u32 {
+        if self.control & SC_FAST != 0 {
+            FAST_BIT_CYCLES
+        } else {
+            NORMAL_BIT_CYCLES
+        }
+    }
+
+    fn log_exchange(&mut self, clock: Clock, received: u8) {
+        if let Some(log) = self.log.as_mut() {
+            log.record(&Exchange {
+                dots: self.dots,
+                clock,
+                sent: self.data,
+                received,
+            });
+        }
+    }
+
+    /// Advance the port by the given number of CPU cycles, with the dots
+    /// elapsed since power on, returning the interrupts it requested as a
+    /// mask of IF bits
+    pub fn tick(&mut self, cycles: u32, dots: u64) -> u8 {
+        self.dots = dots;
+        if self.control & SC_TRANSFER != 0 {
+            if self.control & SC_INTERNAL_CLOCK != 0 {
+                self.tick_internal(cycles);
+            } else {
+                self.poll_external();
+            }
+        }
+        std::mem::take(&mut self.interrupts)
+    }
+
+    fn tick_internal(&mut self, mut cycles: u32) {
+        if self.awaiting_reply {
+            let dots = self.dots;
+            let incoming = match self.device.as_mut() {
+                Some(device) => {
+                    device.set_time(dots);
+                    device.poll_transfer()
+                }
+                None => Some(0xFF),
+            };
+            match incoming {
+                Some(incoming) => self.receive(incoming),
+                None => return,
+            }
+        }
+        while self.bits > 0 && cycles >= self.bit_cycles {
+            cycles -= self.bit_cycles;
+            self.bit_cycles = self.bit_period();
+            self.bits -= 1;
+            let bit = (self.incoming >> self.bits) & 1;
+            self.data = (self.data << 1) | bit;
+            if self.bits == 0 {
+                self.finish_transfer();
+            }
+        }
+        if self.bits > 0 {
+            self.bit_cycles -= cycles;
+        }
+    }
+
+    fn poll_external(&mut self) {
+        let (data, dots) = (self.data, self.dots);
+        let incoming = self.device.as_mut().and_then(|device| {
+            device.set_time(dots);
+            device.poll_external(data)
+        });
+        if let Some(incoming) = incoming {
+            self.log_exchange(Clock::External, incoming);
+            self.data = incoming;
+            self.finish_transfer();
+        }
+    }
+
+    fn finish_transfer(&mut self) {
+        self.control &= !SC_TRANSFER;
+        self.interrupts |= Interrupt::Serial as u8;
+    }
+
+    /// Save the port itself, without whatever is plugged into it
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.data);
+        writer.write_u8(self.control);
+        writer.write_u8(self.incoming);
+        writer.write_u8(self.bits);
+        writer.write_u32(self.bit_cycles);
+        writer.write_u8(self.interrupts);
+        writer.write_u64(self.dots);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.data = reader.read_u8()?;
+        self.control = reader.read_u8()? & (SC_TRANSFER | SC_FAST | SC_INTERNAL_CLOCK);
+        self.incoming = reader.read_u8()?;
+        self.bits = reader.read_u8()?.min(8);
+        // Whatever is plugged in is not part of the state, so a transfer
+        // saved while waiting for it goes on with the byte it had
+        self.awaiting_reply = false;
+        self.bit_cycles = reader.read_u32()?;
+        self.interrupts = reader.read_u8()?;
+        self.dots = reader.read_u64()?;
+        Ok(())
+    }
+}
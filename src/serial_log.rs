@@ -0,0 +1,243 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+
+use crate::serial::SerialDevice;
+
+const HEADER: &str = "# dots clock sent received";
+
+/// Side that drove the clock of an exchange
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Clock {
+    Internal,
+    External,
+}
+
+/// Byte exchanged over the link port, as the Game Boy sent it and received
+/// it from the partner, with the dots since power on when it happened
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Exchange {
+    pub dots: u64,
+    pub clock: Clock,
+    pub sent: u8,
+    pub received: u8,
+}
+
+impl fmt::Display for Exchange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let clock = match self.clock {
+            Clock::Internal => "internal",
+            Clock::External => "external",
+        };
+        write!(
+            f,
+            "{} {} {:02X} {:02X}",
+            self.dots, clock, self.sent, self.received
+        )
+    }
+}
+
+impl Exchange {
+    fn parse(fields: &[&str]) -> Option<Self> {
+        match *fields {
+            [dots, clock, sent, received] => Some(Self {
+                dots: dots.parse().ok()?,
+                clock: match clock {
+                    "internal" => Clock::Internal,
+                    "external" => Clock::External,
+                    _ => return None,
+                },
+                sent: parse_byte(sent)?,
+                received: parse_byte(received)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Log of the bytes exchanged over the link port, written as text with one
+/// exchange per line as they happen
+pub struct SerialLog {
+    writer: Box<dyn Write>,
+    // First error hit while writing, which the log cannot return
+    error: Option<io::Error>,
+}
+
+impl SerialLog {
+    pub fn create(path: &Path) -> Result<Self, io::Error> {
+        Self::new(Box::new(LineWriter::new(File::create(path)?)))
+    }
+
+    pub fn new(mut writer: Box<dyn Write>) -> Result<Self, io::Error> {
+        writeln!(writer, "{}", HEADER)?;
+        Ok(Self {
+            writer,
+            error: None,
+        })
+    }
+
+    pub fn record(&mut self, exchange: &Exchange) {
+        if self.error.is_none() {
+            if let Err(error) = writeln!(self.writer, "{}", exchange) {
+                self.error = Some(error);
+            }
+        }
+    }
+
+    /// Flush the log, returning any error hit while writing it
+    pub fn finish(&mut self) -> Result<(), io::Error> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.writer.flush()
+    }
+}
+
+/// Byte a rule answers with
+#[derive(Clone, Copy)]
+enum Reply {
+    Byte(u8),
+    // The byte received
+    Echo,
+}
+
+/// Rule of a scripted partner, answering the given byte, or any byte if
+/// `None`, with a reply
+struct Rule {
+    on: Option<u8>,
+    reply: Reply,
+}
+
+/// Fake link partner replaying a script, which is a serial log of a
+/// previous session along with rules
+///
+/// The partner answers the Game Boy with the bytes it received in the log,
+/// in order. On exchanges the partner clocked, it waits for as long after
+/// the previous exchange as it did in the log. Rules make the partner answer
+/// based on what it receives, for the parts of a session that do not go the
+/// same way every time. A line `on 01 reply 02` makes the partner answer
+/// the next exchange with 02 after receiving 01, in place of the log, like
+/// a partner loading its reply once it has the byte it replies to. `*`
+/// matches any byte and `echo` replies with the byte received.
+pub struct SerialReplay {
+    exchanges: VecDeque<Exchange>,
+    rules: Vec<Rule>,
+    // Reply to the next exchange chosen by a rule
+    pending: Option<u8>,
+    dots: u64,
+    // Dots at the last exchange, live and in the log
+    last_dots: u64,
+    last_logged_dots: u64,
+}
+
+impl SerialReplay {
+    pub fn load(path: &Path) -> Result<Self, io::Error> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(script: &str) -> Result<Self, io::Error> {
+        let mut replay = Self {
+            exchanges: VecDeque::new(),
+            rules: Vec::new(),
+            pending: None,
+            dots: 0,
+            last_dots: 0,
+            last_logged_dots: 0,
+        };
+        for (number, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields[0] == "on" {
+                let rule = parse_rule(&fields).ok_or_else(|| invalid_line(number, line))?;
+                replay.rules.push(rule);
+            } else {
+                let exchange =
+                    Exchange::parse(&fields).ok_or_else(|| invalid_line(number, line))?;
+                replay.exchanges.push_back(exchange);
+            }
+        }
+        Ok(replay)
+    }
+
+    /// Take the reply to the exchange in progress, and apply the rules to
+    /// the byte received in it
+    fn exchange(&mut self, outgoing: u8) -> u8 {
+        let logged = self.exchanges.pop_front();
+        if let Some(exchange) = logged {
+            self.last_logged_dots = exchange.dots;
+        }
+        self.last_dots = self.dots;
+        let reply = self
+            .pending
+            .take()
+            .or(logged.map(|exchange| exchange.received))
+            .unwrap_or(0xFF);
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| rule.on.is_none_or(|on| on == outgoing));
+        self.pending = rule.map(|rule| match rule.reply {
+            Reply::Byte(byte) => byte,
+            Reply::Echo => outgoing,
+        });
+        reply
+    }
+}
+
+impl SerialDevice for SerialReplay {
+    fn transfer(&mut self, outgoing: u8) -> Option<u8> {
+        Some(self.exchange(outgoing))
+    }
+
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        let due = match self.exchanges.front() {
+            _ if self.pending.is_some() => true,
+            Some(exchange) if exchange.clock == Clock::External => {
+                let delay = exchange.dots.saturating_sub(self.last_logged_dots);
+                self.dots.saturating_sub(self.last_dots) >= delay
+            }
+            _ => false,
+        };
+        if due {
+            Some(self.exchange(outgoing))
+        } else {
+            None
+        }
+    }
+
+    fn set_time(&mut self, dots: u64) {
+        self.dots = dots;
+    }
+}
+
+fn parse_rule(fields: &[&str]) -> Option<Rule> {
+    match *fields {
+        ["on", on, "reply", reply] => Some(Rule {
+            on: match on {
+                "*" => None,
+                _ => Some(parse_byte(on)?),
+            },
+            reply: match reply {
+                "echo" => Reply::Echo,
+                _ => Reply::Byte(parse_byte(reply)?),
+            },
+        }),
+        _ => None,
+    }
+}
+
+fn parse_byte(text: &str) -> Option<u8> {
+    u8::from_str_radix(text, 16).ok()
+}
+
+fn invalid_line(index: usize, line: &str) -> io::Error {
+    let message = format!("Invalid line {} of the serial script: {}", index + 1, line);
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
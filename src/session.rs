@@ -0,0 +1,198 @@
+use std::cell::RefCell;
+use std::io;
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::cpu::Cpu;
+use crate::image::write_ppm;
+use crate::infrared::InfraredDevice;
+use crate::ppu::{DOTS_PER_FRAME, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::serial::SerialDevice;
+
+/// State of a link cable between two Game Boys in the same process
+struct Wire {
+    // Byte each side is ready to shift out while waiting on the external
+    // clock, and the byte delivered to it once the other side clocks it
+    offers: [Option<u8>; 2],
+    deliveries: [Option<u8>; 2],
+    // Whether the infrared LED of each side is on
+    leds: [bool; 2],
+}
+
+/// One end of a `Wire`, plugged into the serial port of one of the sides or
+/// facing its infrared port
+struct WireEnd {
+    wire: Rc<RefCell<Wire>>,
+    side: usize,
+}
+
+impl SerialDevice for WireEnd {
+    fn transfer(&mut self, outgoing: u8) -> Option<u8> {
+        let mut wire = self.wire.borrow_mut();
+        let other = 1 - self.side;
+        match wire.offers[other].take() {
+            Some(incoming) => {
+                wire.deliveries[other] = Some(outgoing);
+                Some(incoming)
+            }
+            // The other side is not waiting for a transfer
+            None => Some(0xFF),
+        }
+    }
+
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        let mut wire = self.wire.borrow_mut();
+        let delivery = wire.deliveries[self.side].take();
+        wire.offers[self.side] = match delivery {
+            Some(_) => None,
+            None => Some(outgoing),
+        };
+        delivery
+    }
+}
+
+impl InfraredDevice for WireEnd {
+    fn set_led(&mut self, on: bool, _dots: u64) {
+        self.wire.borrow_mut().leds[self.side] = on;
+    }
+
+    fn light(&mut self, _dots: u64) -> bool {
+        self.wire.borrow().leds[1 - self.side]
+    }
+}
+
+/// Two Game Boys with their serial ports linked and their infrared ports
+/// facing each other, run in instruction lockstep on one thread so that
+/// link play is fully reproducible
+///
+/// Whichever side is behind in real time is always the one stepped next, a
+/// whole instruction at a time, so neither gets ahead of the other by more
+/// than an instruction. This is not cycle lockstep: what one side sees of
+/// the other can be up to an instruction out of date, which is as close as
+/// the link needs.
+pub struct LinkedSession {
+    pub left: Cpu,
+    pub right: Cpu,
+    wire: Rc<RefCell<Wire>>,
+}
+
+impl LinkedSession {
+    pub fn new(mut left: Cpu, mut right: Cpu) -> Self {
+        let wire = Rc::new(RefCell::new(Wire {
+            offers: [None; 2],
+            deliveries: [None; 2],
+            leds: [false; 2],
+        }));
+        for (side, cpu) in [&mut left, &mut right].iter_mut().enumerate() {
+            let end = || WireEnd {
+                wire: Rc::clone(&wire),
+                side,
+            };
+            cpu.set_serial_device(Some(Box::new(end())));
+            cpu.set_infrared_device(Some(Box::new(end())));
+        }
+        Self { left, right, wire }
+    }
+
+    /// Execute the next instruction of the side that is behind
+    pub fn step_instruction(&mut self) {
+        let side = if self.left.dots() <= self.right.dots() {
+            0
+        } else {
+            1
+        };
+        // A side only offers a byte for as long as it keeps waiting on the
+        // external clock, which it renews on every cycle it does
+        self.wire.borrow_mut().offers[side] = None;
+        match side {
+            0 => self.left.step(),
+            _ => self.right.step(),
+        };
+    }
+
+    /// Run both sides for the given number of frames worth of real time
+    pub fn run_frames(&mut self, frames: u32) {
+        let end = self.left.dots() + frames as u64 * DOTS_PER_FRAME as u64;
+        while self.left.dots() < end || self.right.dots() < end {
+            self.step_instruction();
+        }
+    }
+
+    /// Run both sides for the given number of frames, writing both screens
+    /// side by side as a PPM image after each one
+    pub fn record_frames(&mut self, frames: u32, writer: &mut dyn Write) -> Result<(), io::Error> {
+        for _ in 0..frames {
+            self.run_frames(1);
+            write_ppm(writer, SCREEN_WIDTH * 2, SCREEN_HEIGHT, &self.screens())?;
+        }
+        Ok(())
+    }
+
+    /// Return both screens side by side, the left one first, as RGB555 colors
+    pub fn screens(&self) -> Vec<u16> {
+        let mut pixels = Vec::with_capacity(SCREEN_WIDTH * 2 * SCREEN_HEIGHT);
+        let left = self.left.framebuffer().chunks(SCREEN_WIDTH);
+        let right = self.right.framebuffer().chunks(SCREEN_WIDTH);
+        for (left_row, right_row) in left.zip(right) {
+            pixels.extend_from_slice(left_row);
+            pixels.extend_from_slice(right_row);
+        }
+        pixels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Code exchanging the three bytes at 0x0200 over the link port, storing
+    /// the answers from 0xC000, with the given value of SC starting each one
+    /// after waiting for the given number of loops
+    fn exchange(control: u8, delay: u8, outgoing: [u8; 3]) -> Vec<u8> {
+        let mut code = vec![
+            0x0E, delay, // LD C,delay
+            0x0D, 0x20, 0xFD, // DEC C; JR NZ,-3
+            0x21, 0x00, 0x02, // LD HL,0x0200
+            0x11, 0x00, 0xC0, // LD DE,0xC000
+            0x06, 0x03, // LD B,3
+            0x2A, 0xE0, 0x01, // LD A,(HL+); LDH (SB),A
+            0x3E, control, 0xE0, 0x02, // LD A,control; LDH (SC),A
+            0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA, // wait until SC bit 7 clears
+            0xF0, 0x01, 0x12, 0x13, // LDH A,(SB); LD (DE),A; INC DE
+            0x05, 0x20, 0xEC, // DEC B; JR NZ,next byte
+            0x18, 0xFE, // JR -2
+        ];
+        code.resize(0x100, 0);
+        code.extend_from_slice(&outgoing);
+        code
+    }
+
+    #[test]
+    fn scripted_serial_exchange() {
+        // The master waits for the slave to listen before clocking a byte
+        let master = Cpu::with_code(&exchange(0x81, 0x20, [0x11, 0x22, 0x33]));
+        let slave = Cpu::with_code(&exchange(0x80, 0x01, [0xA1, 0xA2, 0xA3]));
+        let mut session = LinkedSession::new(master, slave);
+        let end = DOTS_PER_FRAME as u64;
+        while session.left.dots() < end || session.right.dots() < end {
+            session.step_instruction();
+            // Neither side gets further ahead than its longest instruction
+            let gap = session.left.dots() as i64 - session.right.dots() as i64;
+            assert!(gap.abs() <= 24, "the sides drifted {} dots apart", gap);
+        }
+
+        let received = |cpu: &Cpu| [cpu.peek(0xC000), cpu.peek(0xC001), cpu.peek(0xC002)];
+        assert_eq!(received(&session.left), [0xA1, 0xA2, 0xA3]);
+        assert_eq!(received(&session.right), [0x11, 0x22, 0x33]);
+        for cpu in [&session.left, &session.right].iter() {
+            assert_eq!(cpu.peek(0xFF02) & 0x80, 0, "a transfer is still running");
+            assert_ne!(
+                cpu.peek(0xFF0F) & 0x08,
+                0,
+                "no serial interrupt was requested"
+            );
+        }
+        assert_eq!(session.left.peek(0xFF01), 0xA3);
+        assert_eq!(session.right.peek(0xFF01), 0x33);
+    }
+}
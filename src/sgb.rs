@@ -0,0 +1,694 @@
+use std::io;
+
+use crate::bess::BessSgb;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::state::{StateReader, StateWriter};
+
+pub const SGB_SCREEN_WIDTH: usize = 256;
+pub const SGB_SCREEN_HEIGHT: usize = 224;
+// Position of the Game Boy screen within the border
+const GAME_X: usize = 48;
+const GAME_Y: usize = 40;
+
+// The attribute map gives a palette to each 8x8 cell of the Game Boy screen
+const CELLS_X: usize = SCREEN_WIDTH / 8;
+const CELLS_Y: usize = SCREEN_HEIGHT / 8;
+const ATTRIBUTE_FILE_SIZE: usize = CELLS_X * CELLS_Y / 4;
+const ATTRIBUTE_FILES: usize = 45;
+const SYSTEM_PALETTES: usize = 512;
+
+const PACKET_SIZE: usize = 16;
+// Size of the data copied from the Game Boy screen by a VRAM transfer
+const TRANSFER_SIZE: usize = 0x1000;
+// The border is a 32x28 map of 8x8 SNES tiles of 4 bits per pixel
+const BORDER_TILES_SIZE: usize = 0x2000;
+const BORDER_MAP_WIDTH: usize = 32;
+const BORDER_TILE_SIZE: usize = 32;
+
+// Colors the game is shown in until it sets palettes of its own
+const DEFAULT_PALETTE: [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+const COMMAND_PAL01: u8 = 0x00;
+const COMMAND_PAL23: u8 = 0x01;
+const COMMAND_PAL03: u8 = 0x02;
+const COMMAND_PAL12: u8 = 0x03;
+const COMMAND_ATTR_BLK: u8 = 0x04;
+const COMMAND_ATTR_LIN: u8 = 0x05;
+const COMMAND_ATTR_DIV: u8 = 0x06;
+const COMMAND_ATTR_CHR: u8 = 0x07;
+const COMMAND_PAL_SET: u8 = 0x0A;
+const COMMAND_PAL_TRN: u8 = 0x0B;
+const COMMAND_MLT_REQ: u8 = 0x11;
+const COMMAND_CHR_TRN: u8 = 0x13;
+const COMMAND_PCT_TRN: u8 = 0x14;
+const COMMAND_ATTR_TRN: u8 = 0x15;
+const COMMAND_ATTR_SET: u8 = 0x16;
+const COMMAND_MASK_EN: u8 = 0x17;
+
+/// What the Super Game Boy shows in place of the Game Boy screen
+#[derive(Clone, Copy, PartialEq)]
+enum Mask {
+    None,
+    Freeze,
+    Black,
+    Color0,
+}
+
+/// Data a VRAM transfer copies from the Game Boy screen
+#[derive(Clone, Copy)]
+enum Transfer {
+    Palettes,
+    // Border tiles, with the half they go to
+    Tiles(usize),
+    Border,
+    Attributes,
+}
+
+/// Super Game Boy, which takes commands from the game in packets sent
+/// through P1, colors the Game Boy screen in, and draws a border around it
+///
+/// Each packet starts with a pulse where both P14 and P15 go low, followed
+/// by 128 bits, each a pulse on P14 for a 0 or on P15 for a 1, least
+/// significant bit first, and a final 0. The first byte of a command holds
+/// its code in the upper 5 bits and the number of packets it takes in the
+/// lower 3.
+///
+/// The Game Boy screen is colored in with 4 palettes, picked for each 8x8
+/// cell by an attribute map. Larger data, such as the border, is sent by
+/// showing it on the Game Boy screen as tiles, which the Super Game Boy
+/// copies on the next frame.
+pub struct Sgb {
+    // P14 and P15 as last written
+    lines: u8,
+    // Packet being received, the number of bits received so far, or None
+    // when waiting for the start of a packet
+    packet: [u8; PACKET_SIZE],
+    bits: Option<usize>,
+    // Packets of the command being received
+    command: Vec<u8>,
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<[u16; 4]>,
+    attributes: [u8; CELLS_X * CELLS_Y],
+    attribute_files: Vec<u8>,
+    mask: Mask,
+    border_tiles: Vec<u8>,
+    border_map: Vec<u8>,
+    border_palettes: [[u16; 16]; 4],
+    // Transfer asked for, and the VBlanks left before the screen shows it
+    transfer: Option<(Transfer, u8)>,
+    // Number of joypads asked for by MLT_REQ, not taken yet
+    players: Option<usize>,
+    // Colors of the Game Boy screen as last shown, kept while frozen
+    game_screen: Vec<u16>,
+    screen: Vec<u16>,
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Self {
+            lines: 0x30,
+            packet: [0; PACKET_SIZE],
+            bits: None,
+            command: Vec::new(),
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![DEFAULT_PALETTE; SYSTEM_PALETTES],
+            attributes: [0; CELLS_X * CELLS_Y],
+            attribute_files: vec![0; ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE],
+            mask: Mask::None,
+            border_tiles: vec![0; BORDER_TILES_SIZE],
+            border_map: vec![0; 2 * BORDER_MAP_WIDTH * BORDER_MAP_WIDTH],
+            border_palettes: [[0; 16]; 4],
+            transfer: None,
+            players: None,
+            game_screen: vec![DEFAULT_PALETTE[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            screen: vec![DEFAULT_PALETTE[0]; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT],
+        }
+    }
+
+    /// Return the last frame, with the border, as RGB555 colors
+    pub fn screen(&self) -> &[u16] {
+        &self.screen
+    }
+
+    /// Return the number of joypads the game asked for since the last call
+    pub fn take_players(&mut self) -> Option<usize> {
+        self.players.take()
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.lines);
+        writer.write_bytes(&self.packet);
+        writer.write_option(self.bits, |writer, bits| writer.write_u32(bits as u32));
+        writer.write_u32(self.command.len() as u32);
+        writer.write_bytes(&self.command);
+        for palette in self.palettes.iter().chain(&self.system_palettes) {
+            writer.write_words(palette);
+        }
+        writer.write_bytes(&self.attributes);
+        writer.write_bytes(&self.attribute_files);
+        writer.write_u32(self.mask as u32);
+        writer.write_bytes(&self.border_tiles);
+        writer.write_bytes(&self.border_map);
+        for palette in &self.border_palettes {
+            writer.write_words(palette);
+        }
+        writer.write_option(self.transfer, |writer, (transfer, frames)| {
+            let code = match transfer {
+                Transfer::Palettes => 0,
+                Transfer::Tiles(half) => 1 + half as u32,
+                Transfer::Border => 3,
+                Transfer::Attributes => 4,
+            };
+            writer.write_u32(code);
+            writer.write_u8(frames);
+        });
+        writer.write_option(self.players, |writer, players| {
+            writer.write_u32(players as u32 - 1)
+        });
+        writer.write_words(&self.game_screen);
+        writer.write_words(&self.screen);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.lines = reader.read_u8()? & 0x30;
+        reader.read_bytes(&mut self.packet)?;
+        let bit_count = PACKET_SIZE as u32 * 8 + 1;
+        self.bits = reader.read_option(|reader| Ok(reader.read_below(bit_count)? as usize))?;
+        // A command takes 7 packets at most, the last of which completes it
+        let length = reader.read_below(7 * PACKET_SIZE as u32)? as usize / PACKET_SIZE;
+        self.command = vec![0; length * PACKET_SIZE];
+        reader.read_bytes(&mut self.command)?;
+        for palette in self.palettes.iter_mut().chain(&mut self.system_palettes) {
+            reader.read_words(palette)?;
+        }
+        reader.read_bytes(&mut self.attributes)?;
+        for attribute in self.attributes.iter_mut() {
+            *attribute &= 0x03;
+        }
+        reader.read_bytes(&mut self.attribute_files)?;
+        self.mask = match reader.read_below(4)? {
+            0 => Mask::None,
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            _ => Mask::Color0,
+        };
+        reader.read_bytes(&mut self.border_tiles)?;
+        reader.read_bytes(&mut self.border_map)?;
+        for palette in self.border_palettes.iter_mut() {
+            reader.read_words(palette)?;
+        }
+        self.transfer = reader.read_option(|reader| {
+            let transfer = match reader.read_below(5)? {
+                0 => Transfer::Palettes,
+                code @ 1..=2 => Transfer::Tiles(code as usize - 1),
+                3 => Transfer::Border,
+                _ => Transfer::Attributes,
+            };
+            Ok((transfer, reader.read_u8()?))
+        })?;
+        self.players = reader.read_option(|reader| Ok(reader.read_below(4)? as usize + 1))?;
+        reader.read_words(&mut self.game_screen)?;
+        reader.read_words(&mut self.screen)
+    }
+
+    /// Return the buffers of a BESS SGB block, leaving the multiplayer state
+    /// to the joypad
+    pub fn export_bess(&self) -> BessSgb {
+        let words = |words: &[u16]| -> Vec<u8> {
+            words.iter().flat_map(|word| word.to_le_bytes()).collect()
+        };
+        BessSgb {
+            border_tiles: self.border_tiles.clone(),
+            border_map: self.border_map.clone(),
+            border_palettes: words(&self.border_palettes.concat()),
+            palettes: words(&self.palettes.concat()),
+            system_palettes: words(&self.system_palettes.concat()),
+            attributes: self.attributes.to_vec(),
+            attribute_files: self.attribute_files.clone(),
+            multiplayer: 0,
+        }
+    }
+
+    /// Load the buffers of a BESS SGB block, skipping the ones that are not
+    /// of the expected size
+    pub fn import_bess(&mut self, sgb: &BessSgb) {
+        let copy = |memory: &mut [u8], data: &[u8]| {
+            if memory.len() == data.len() {
+                memory.copy_from_slice(data);
+            }
+        };
+        copy(&mut self.border_tiles, &sgb.border_tiles);
+        copy(&mut self.border_map, &sgb.border_map);
+        copy(&mut self.attributes, &sgb.attributes);
+        copy(&mut self.attribute_files, &sgb.attribute_files);
+        for attribute in self.attributes.iter_mut() {
+            *attribute &= 0x03;
+        }
+        let color =
+            |data: &[u8], index: usize| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+        if sgb.border_palettes.len() == 4 * 16 * 2 {
+            for (i, color_value) in self.border_palettes.iter_mut().flatten().enumerate() {
+                *color_value = color(&sgb.border_palettes, i);
+            }
+        }
+        if sgb.palettes.len() == 4 * 4 * 2 {
+            for (i, color_value) in self.palettes.iter_mut().flatten().enumerate() {
+                *color_value = color(&sgb.palettes, i);
+            }
+        }
+        if sgb.system_palettes.len() == SYSTEM_PALETTES * 4 * 2 {
+            for (i, color_value) in self.system_palettes.iter_mut().flatten().enumerate() {
+                *color_value = color(&sgb.system_palettes, i);
+            }
+        }
+        self.render();
+    }
+
+    /// Take in a write to P1, where the packets come from
+    pub fn write_joypad(&mut self, value: u8) {
+        let lines = value & 0x30;
+        let previous = std::mem::replace(&mut self.lines, lines);
+        if lines == 0x00 {
+            self.bits = Some(0);
+            self.packet = [0; PACKET_SIZE];
+            return;
+        }
+        // Bits are pulses from both lines high
+        let bits = match self.bits {
+            Some(bits) if previous == 0x30 && lines != 0x30 => bits,
+            _ => return,
+        };
+        let bit = lines == 0x10;
+        if bits == PACKET_SIZE * 8 {
+            // The stop bit, which has to be a 0
+            self.bits = None;
+            if !bit {
+                self.receive_packet();
+            }
+            return;
+        }
+        if bit {
+            self.packet[bits / 8] |= 1 << (bits % 8);
+        }
+        self.bits = Some(bits + 1);
+    }
+
+    fn receive_packet(&mut self) {
+        self.command.extend_from_slice(&self.packet);
+        let packets = (self.command[0] & 0x07).max(1) as usize;
+        if self.command.len() == packets * PACKET_SIZE {
+            let command = std::mem::take(&mut self.command);
+            self.execute(&command);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            COMMAND_PAL01 => self.set_palettes(0, 1, &data[1..]),
+            COMMAND_PAL23 => self.set_palettes(2, 3, &data[1..]),
+            COMMAND_PAL03 => self.set_palettes(0, 3, &data[1..]),
+            COMMAND_PAL12 => self.set_palettes(1, 2, &data[1..]),
+            COMMAND_ATTR_BLK => self.attribute_blocks(&data[1..]),
+            COMMAND_ATTR_LIN => self.attribute_lines(&data[1..]),
+            COMMAND_ATTR_DIV => self.attribute_division(data[1], data[2] as usize),
+            COMMAND_ATTR_CHR => self.attribute_cells(&data[1..]),
+            COMMAND_PAL_SET => {
+                for palette in 0..4 {
+                    let number = u16::from_le_bytes([data[1 + palette * 2], data[2 + palette * 2]]);
+                    self.palettes[palette] =
+                        self.system_palettes[number as usize % SYSTEM_PALETTES];
+                }
+                self.share_color0(self.palettes[0][0]);
+                self.set_attribute_file(data[9]);
+            }
+            COMMAND_ATTR_SET => self.set_attribute_file(data[1] | 0x80),
+            COMMAND_MLT_REQ => {
+                self.players = Some(match data[1] & 0x03 {
+                    0 => 1,
+                    1 => 2,
+                    _ => 4,
+                })
+            }
+            COMMAND_MASK_EN => {
+                self.mask = match data[1] & 0x03 {
+                    0 => Mask::None,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                }
+            }
+            COMMAND_PAL_TRN => self.start_transfer(Transfer::Palettes),
+            COMMAND_CHR_TRN => self.start_transfer(Transfer::Tiles(data[1] as usize & 0x01)),
+            COMMAND_PCT_TRN => self.start_transfer(Transfer::Border),
+            COMMAND_ATTR_TRN => self.start_transfer(Transfer::Attributes),
+            // The sound and SNES related commands have nothing to act on
+            _ => {}
+        }
+    }
+
+    /// Set color 0 shared by all palettes and colors 1-3 of two of them
+    fn set_palettes(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |index: usize| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+        self.share_color0(color(0));
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    fn share_color0(&mut self, color: u16) {
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color;
+        }
+    }
+
+    /// Apply data sets of 6 bytes, each giving a palette to the cells
+    /// inside, on the border and outside of a rectangle. Setting only the
+    /// inside or the outside also sets the border.
+    fn attribute_blocks(&mut self, data: &[u8]) {
+        let count = (data[0] & 0x1F) as usize;
+        for block in data[1..].chunks_exact(6).take(count) {
+            let mut control = block[0] & 0x07;
+            let (inside, border, outside) = (
+                block[1] & 0x03,
+                (block[1] >> 2) & 0x03,
+                (block[1] >> 4) & 0x03,
+            );
+            let border = match control {
+                0x01 => inside,
+                0x04 => outside,
+                _ => border,
+            };
+            if control == 0x01 || control == 0x04 {
+                control |= 0x02;
+            }
+            let (x1, y1, x2, y2) = (
+                block[2] as usize & 0x1F,
+                block[3] as usize & 0x1F,
+                block[4] as usize & 0x1F,
+                block[5] as usize & 0x1F,
+            );
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let within = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+                    let on_edge = within && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let cell = &mut self.attributes[y * CELLS_X + x];
+                    if on_edge {
+                        if control & 0x02 != 0 {
+                            *cell = border;
+                        }
+                    } else if within {
+                        if control & 0x01 != 0 {
+                            *cell = inside;
+                        }
+                    } else if control & 0x04 != 0 {
+                        *cell = outside;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Apply bytes each giving a palette in bits 5-6 to a row, with bit 7
+    /// set, or a column of cells, numbered in bits 0-4
+    fn attribute_lines(&mut self, data: &[u8]) {
+        let count = data[0] as usize;
+        for &line in data[1..].iter().take(count) {
+            let number = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 != 0 {
+                if number < CELLS_Y {
+                    self.attributes[number * CELLS_X..(number + 1) * CELLS_X].fill(palette);
+                }
+            } else if number < CELLS_X {
+                for y in 0..CELLS_Y {
+                    self.attributes[y * CELLS_X + number] = palette;
+                }
+            }
+        }
+    }
+
+    /// Split the cells along a row, with bit 6 set, or a column, giving a
+    /// palette to each side and to the line itself
+    fn attribute_division(&mut self, control: u8, line: usize) {
+        let after = control & 0x03;
+        let before = (control >> 2) & 0x03;
+        let on_line = (control >> 4) & 0x03;
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let position = if control & 0x40 != 0 { y } else { x };
+                self.attributes[y * CELLS_X + x] = match position.cmp(&line) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    /// Give palettes to a run of cells from a starting one, going right or
+    /// down, from 2 bits each, most significant first
+    fn attribute_cells(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[0] as usize % CELLS_X, data[1] as usize % CELLS_Y);
+        let count = (u16::from_le_bytes([data[2], data[3]]) as usize).min(CELLS_X * CELLS_Y);
+        let vertical = data[4] & 0x01 != 0;
+        for i in 0..count {
+            let byte = match data.get(5 + i / 4) {
+                Some(&byte) => byte,
+                None => break,
+            };
+            self.attributes[y * CELLS_X + x] = (byte >> (6 - 2 * (i % 4))) & 0x03;
+            if vertical {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x = (x + 1) % CELLS_X;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y = (y + 1) % CELLS_Y;
+                }
+            }
+        }
+    }
+
+    /// Apply the attribute file numbered in bits 0-5 if bit 7 is set, and
+    /// cancel the mask if bit 6 is set
+    fn set_attribute_file(&mut self, control: u8) {
+        if control & 0x80 != 0 {
+            let file = (control & 0x3F) as usize % ATTRIBUTE_FILES;
+            let data = &self.attribute_files[file * ATTRIBUTE_FILE_SIZE..][..ATTRIBUTE_FILE_SIZE];
+            for (cell, attribute) in self.attributes.iter_mut().enumerate() {
+                *attribute = (data[cell / 4] >> (6 - 2 * (cell % 4))) & 0x03;
+            }
+        }
+        if control & 0x40 != 0 {
+            self.mask = Mask::None;
+        }
+    }
+
+    /// Copy the data from the screen once a whole frame has shown it, which
+    /// takes until the second VBlank
+    fn start_transfer(&mut self, transfer: Transfer) {
+        self.transfer = Some((transfer, 2));
+    }
+
+    /// Take in a complete frame of the Game Boy screen as shades, to carry
+    /// out transfers and update the colored screen
+    pub fn finish_frame(&mut self, shades: &[u8]) {
+        if let Some((transfer, frames)) = self.transfer {
+            if frames > 1 {
+                self.transfer = Some((transfer, frames - 1));
+            } else {
+                self.transfer = None;
+                self.receive_transfer(transfer, &screen_tiles(shades));
+            }
+        }
+        if self.mask != Mask::Freeze {
+            for (i, &shade) in shades.iter().enumerate() {
+                let (x, y) = (i % SCREEN_WIDTH, i / SCREEN_WIDTH);
+                self.game_screen[i] = match self.mask {
+                    Mask::Black => 0x0000,
+                    Mask::Color0 => self.palettes[0][0],
+                    _ => {
+                        let palette = self.attributes[y / 8 * CELLS_X + x / 8] as usize;
+                        self.palettes[palette][shade as usize]
+                    }
+                };
+            }
+        }
+        self.render();
+    }
+
+    fn receive_transfer(&mut self, transfer: Transfer, data: &[u8]) {
+        let color = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        match transfer {
+            Transfer::Palettes => {
+                for (i, palette) in self.system_palettes.iter_mut().enumerate() {
+                    for (j, color_value) in palette.iter_mut().enumerate() {
+                        *color_value = color(i * 8 + j * 2);
+                    }
+                }
+            }
+            Transfer::Tiles(half) => {
+                self.border_tiles[half * TRANSFER_SIZE..][..TRANSFER_SIZE].copy_from_slice(data)
+            }
+            Transfer::Border => {
+                let map_size = self.border_map.len();
+                self.border_map.copy_from_slice(&data[..map_size]);
+                for (i, palette) in self.border_palettes.iter_mut().enumerate() {
+                    for (j, color_value) in palette.iter_mut().enumerate() {
+                        *color_value = color(map_size + i * 32 + j * 2);
+                    }
+                }
+            }
+            Transfer::Attributes => {
+                let size = self.attribute_files.len();
+                self.attribute_files.copy_from_slice(&data[..size]);
+            }
+        }
+    }
+
+    /// Draw the Game Boy screen on the backdrop, and the border over it
+    fn render(&mut self) {
+        self.screen.fill(self.palettes[0][0]);
+        for (y, row) in self.game_screen.chunks(SCREEN_WIDTH).enumerate() {
+            let start = (GAME_Y + y) * SGB_SCREEN_WIDTH + GAME_X;
+            self.screen[start..start + SCREEN_WIDTH].copy_from_slice(row);
+        }
+        for y in 0..SGB_SCREEN_HEIGHT {
+            for x in 0..SGB_SCREEN_WIDTH {
+                let offset = 2 * ((y / 8) * BORDER_MAP_WIDTH + x / 8);
+                let entry =
+                    u16::from_le_bytes([self.border_map[offset], self.border_map[offset + 1]]);
+                let tile = (entry & 0xFF) as usize;
+                let palette = ((entry >> 10) & 0x03) as usize;
+                let tile_x = if entry & 0x4000 != 0 {
+                    7 - x % 8
+                } else {
+                    x % 8
+                };
+                let tile_y = if entry & 0x8000 != 0 {
+                    7 - y % 8
+                } else {
+                    y % 8
+                };
+                let color = self.border_pixel(tile, tile_x, tile_y);
+                if color != 0 {
+                    self.screen[y * SGB_SCREEN_WIDTH + x] = self.border_palettes[palette][color];
+                }
+            }
+        }
+    }
+
+    /// Return the color of a pixel of a border tile, whose rows have the
+    /// bitplanes 0 and 1 interleaved in the first 16 bytes, and 2 and 3 in
+    /// the next 16
+    fn border_pixel(&self, tile: usize, x: usize, y: usize) -> usize {
+        let data = &self.border_tiles[tile * BORDER_TILE_SIZE..][..BORDER_TILE_SIZE];
+        let bit = 7 - x;
+        [
+            data[y * 2],
+            data[y * 2 + 1],
+            data[16 + y * 2],
+            data[17 + y * 2],
+        ]
+        .iter()
+        .enumerate()
+        .map(|(plane, &byte)| (((byte >> bit) & 1) as usize) << plane)
+        .sum()
+    }
+}
+
+/// Read the first 256 tiles shown on the screen, in rows of 20, back into
+/// tile data of 2 bits per pixel, as a VRAM transfer does
+fn screen_tiles(shades: &[u8]) -> Vec<u8> {
+    let mut data = vec![0; TRANSFER_SIZE];
+    for (tile, bytes) in data.chunks_exact_mut(16).enumerate() {
+        let (tile_x, tile_y) = (tile % CELLS_X * 8, tile / CELLS_X * 8);
+        for y in 0..8 {
+            for x in 0..8 {
+                let shade = shades[(tile_y + y) * SCREEN_WIDTH + tile_x + x];
+                bytes[y * 2] |= (shade & 1) << (7 - x);
+                bytes[y * 2 + 1] |= (shade >> 1) << (7 - x);
+            }
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{Mmu, Model};
+
+    fn sgb_mmu() -> Mmu {
+        let mut rom = vec![0; 0x8000];
+        rom[0x146] = 0x03;
+        rom[0x14B] = 0x33;
+        let mut mmu = Mmu::new();
+        mmu.load_rom_data(rom);
+        mmu.set_model(Model::Sgb);
+        mmu
+    }
+
+    /// Pulse a packet through P1, least significant bit first
+    fn send_packet(mmu: &mut Mmu, packet: [u8; PACKET_SIZE]) {
+        mmu.write_byte_at(0xFF00, 0x00);
+        mmu.write_byte_at(0xFF00, 0x30);
+        for bit in 0..PACKET_SIZE * 8 {
+            let one = (packet[bit / 8] >> (bit % 8)) & 1 != 0;
+            mmu.write_byte_at(0xFF00, if one { 0x10 } else { 0x20 });
+            mmu.write_byte_at(0xFF00, 0x30);
+        }
+        // The stop bit
+        mmu.write_byte_at(0xFF00, 0x20);
+        mmu.write_byte_at(0xFF00, 0x30);
+    }
+
+    #[test]
+    fn palettes_from_pal01() {
+        let mut mmu = sgb_mmu();
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = COMMAND_PAL01 << 3 | 1;
+        let colors = [0x1234, 0x0001, 0x0002, 0x0003, 0x0011, 0x0012, 0x0013];
+        for (i, color) in colors.iter().enumerate() {
+            packet[1 + i * 2..3 + i * 2].copy_from_slice(&u16::to_le_bytes(*color));
+        }
+        send_packet(&mut mmu, packet);
+        let palettes = mmu.sgb.as_ref().unwrap().palettes;
+        assert_eq!(palettes[0], [0x1234, 0x0001, 0x0002, 0x0003]);
+        assert_eq!(palettes[1], [0x1234, 0x0011, 0x0012, 0x0013]);
+        for palette in &palettes[2..] {
+            assert_eq!(palette[0], 0x1234);
+            assert_eq!(palette[1..], DEFAULT_PALETTE[1..]);
+        }
+    }
+
+    #[test]
+    fn players_from_mlt_req() {
+        let mut mmu = sgb_mmu();
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = COMMAND_MLT_REQ << 3 | 1;
+        packet[1] = 0x03;
+        send_packet(&mut mmu, packet);
+        // With none of the groups selected, P1 reads the number of the
+        // current joypad, which moves on to the next one of the 4 every
+        // time P15 goes high
+        let mut joypads = Vec::new();
+        for _ in 0..5 {
+            joypads.push(mmu.read_byte_at(0xFF00) & 0x0F);
+            mmu.write_byte_at(0xFF00, 0x10);
+            mmu.write_byte_at(0xFF00, 0x30);
+        }
+        assert_eq!(joypads, [0x0F, 0x0E, 0x0D, 0x0C, 0x0F]);
+    }
+}
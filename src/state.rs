@@ -0,0 +1,270 @@
+use std::io;
+use std::ops::RangeInclusive;
+
+const MAGIC: &[u8; 4] = b"TZBS";
+/// Version of the save state format, to be bumped whenever the content of
+/// a section changes
+pub const VERSION: u16 = 1;
+
+/// Writer of a save state, a binary snapshot of the whole machine
+///
+/// A state starts with a magic number and the version of the format,
+/// followed by sections, each made of a 4 character tag, the length of its
+/// content and the content itself. Numbers are little endian.
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::with_header(MAGIC, VERSION)
+    }
+
+    /// Start a file of another format laid out like a state, with the given
+    /// magic number and version
+    pub fn with_header(magic: &[u8; 4], version: u16) -> Self {
+        let mut data = magic.to_vec();
+        data.extend_from_slice(&version.to_le_bytes());
+        Self { data }
+    }
+
+    /// Write a section with the content written by the given function
+    pub fn section(&mut self, tag: &[u8; 4], write: impl FnOnce(&mut Self)) {
+        self.data.extend_from_slice(tag);
+        let length_offset = self.data.len();
+        self.write_u32(0);
+        write(self);
+        let length = (self.data.len() - length_offset - 4) as u32;
+        self.data[length_offset..length_offset + 4].copy_from_slice(&length.to_le_bytes());
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn write_words(&mut self, words: &[u16]) {
+        for &word in words {
+            self.write_u16(word);
+        }
+    }
+
+    /// Write whether there is a value, followed by the value if so
+    pub fn write_option<T>(&mut self, value: Option<T>, write: impl FnOnce(&mut Self, T)) {
+        self.write_bool(value.is_some());
+        if let Some(value) = value {
+            write(self, value);
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Save state split into its sections, checked to be of a version this
+/// build can read
+pub struct State<'a> {
+    sections: Vec<([u8; 4], &'a [u8])>,
+}
+
+impl<'a> State<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, io::Error> {
+        if data.len() < 6 || &data[0..4] != MAGIC {
+            return Err(invalid_data("Not a tonzoboy save state".to_string()));
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        // There has only ever been one version so far. States of older ones
+        // are to be converted here once there are.
+        if version > VERSION {
+            let message = format!(
+                "The save state is of version {} of the format, newer than the {} this build reads",
+                version, VERSION
+            );
+            return Err(invalid_data(message));
+        } else if version < VERSION {
+            let message = format!(
+                "The save state is of version {} of the format, which is no longer supported",
+                version
+            );
+            return Err(invalid_data(message));
+        }
+        let mut sections = Vec::new();
+        let mut rest = &data[6..];
+        while !rest.is_empty() {
+            if rest.len() < 8 {
+                return Err(invalid_data("The save state is truncated".to_string()));
+            }
+            let tag = [rest[0], rest[1], rest[2], rest[3]];
+            let length = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+            if rest.len() - 8 < length {
+                return Err(invalid_data("The save state is truncated".to_string()));
+            }
+            sections.push((tag, &rest[8..8 + length]));
+            rest = &rest[8 + length..];
+        }
+        Ok(Self { sections })
+    }
+
+    /// Read a section with the given function, which has to read all of it
+    pub fn read_section<T>(
+        &self,
+        tag: &[u8; 4],
+        read: impl FnOnce(&mut StateReader) -> Result<T, io::Error>,
+    ) -> Result<T, io::Error> {
+        let name = String::from_utf8_lossy(tag).trim_end().to_string();
+        let data = self
+            .sections
+            .iter()
+            .find(|(section, _)| section == tag)
+            .map(|&(_, data)| data)
+            .ok_or_else(|| invalid_data(format!("The save state has no {} section", name)))?;
+        let mut reader = StateReader::new(data);
+        let value = read(&mut reader)
+            .map_err(|error| invalid_data(format!("Invalid {} section: {}", name, error)))?;
+        if !reader.is_at_end() {
+            let message = format!("The {} section is longer than expected", name);
+            return Err(invalid_data(message));
+        }
+        Ok(value)
+    }
+}
+
+/// Reader of the content of a section of a save state
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    /// Read the given data, for files of other formats laid out like a state
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    /// Whether all of the data has been read
+    pub fn is_at_end(&self) -> bool {
+        self.position == self.data.len()
+    }
+
+    fn take(&mut self, length: usize) -> Result<&[u8], io::Error> {
+        if self.data.len() - self.position < length {
+            return Err(invalid_data("too short".to_string()));
+        }
+        self.position += length;
+        Ok(&self.data[self.position - length..self.position])
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, io::Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, io::Error> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(invalid_data(format!("{} is not a boolean", value))),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, io::Error> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, io::Error> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, io::Error> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Read a value that has to be below the given limit, such as an index
+    pub fn read_below(&mut self, limit: u32) -> Result<u32, io::Error> {
+        let value = self.read_u32()?;
+        if value >= limit {
+            let message = format!("{} is out of range, the limit being {}", value, limit);
+            return Err(invalid_data(message));
+        }
+        Ok(value)
+    }
+
+    /// Read a byte that has to be within the given range, such as a delay
+    /// that counts down to 1
+    pub fn read_u8_within(&mut self, range: RangeInclusive<u8>) -> Result<u8, io::Error> {
+        let value = self.read_u8()?;
+        if !range.contains(&value) {
+            let message = format!(
+                "{} is out of range, which is {} to {}",
+                value,
+                range.start(),
+                range.end()
+            );
+            return Err(invalid_data(message));
+        }
+        Ok(value)
+    }
+
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), io::Error> {
+        bytes.copy_from_slice(self.take(bytes.len())?);
+        Ok(())
+    }
+
+    /// Read the given number of bytes into a new vector
+    pub fn read_vec(&mut self, length: usize) -> Result<Vec<u8>, io::Error> {
+        Ok(self.take(length)?.to_vec())
+    }
+
+    pub fn read_words(&mut self, words: &mut [u16]) -> Result<(), io::Error> {
+        for word in words.iter_mut() {
+            *word = self.read_u16()?;
+        }
+        Ok(())
+    }
+
+    /// Read a value written by `StateWriter::write_option`
+    pub fn read_option<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<T, io::Error>,
+    ) -> Result<Option<T>, io::Error> {
+        if self.read_bool()? {
+            Ok(Some(read(self)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,160 @@
+use std::io;
+
+use crate::memory::Interrupt;
+use crate::state::{StateReader, StateWriter};
+
+// Cycles between TIMA overflowing and being reloaded from TMA, and how long
+// the reload itself lasts
+const RELOAD_DELAY: u8 = 4;
+
+/// Timer and divider (DIV, TIMA, TMA and TAC)
+///
+/// DIV is the upper byte of an internal 16-bit counter that goes up every
+/// cycle. TIMA is incremented on the falling edges of one of the counter's
+/// bits, selected by TAC and ANDed with its enable bit, which is why
+/// writing to DIV or TAC can increment it too.
+pub struct Timer {
+    divider: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // Cycles left until TIMA is reloaded after overflowing, during which
+    // it reads 0 and a write to it cancels the reload
+    overflow_cycles: Option<u8>,
+    // Cycles left of the reload, during which writes to TIMA are ignored
+    // and writes to TMA go through to TIMA as well
+    reload_cycles: u8,
+    interrupts: u8,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self {
+            divider: 0xABCC,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow_cycles: None,
+            reload_cycles: 0,
+            interrupts: 0,
+        }
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF04 => (self.divider >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => 0xF8 | self.tac,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF04 => self.reset_divider(),
+            // Writes during the reload are ignored
+            0xFF05 if self.reload_cycles == 0 => {
+                self.tima = value;
+                self.overflow_cycles = None;
+            }
+            0xFF06 => {
+                self.tma = value;
+                if self.reload_cycles > 0 {
+                    self.tima = value;
+                }
+            }
+            0xFF07 => {
+                let signal = self.signal();
+                self.tac = value & 0x07;
+                self.detect_falling_edge(signal);
+            }
+            _ => {}
+        }
+    }
+
+    /// Reset the internal counter, as done by writing to DIV or executing STOP
+    pub fn reset_divider(&mut self) {
+        let signal = self.signal();
+        self.divider = 0;
+        self.detect_falling_edge(signal);
+    }
+
+    /// Set the internal counter, for loading states of other emulators,
+    /// which only have DIV
+    pub fn set_divider(&mut self, divider: u16) {
+        self.divider = divider;
+    }
+
+    /// Advance the timer by the given number of CPU cycles, returning the
+    /// interrupts it requested as a mask of IF bits
+    pub fn tick(&mut self, cycles: u32) -> u8 {
+        for _ in 0..cycles {
+            self.reload_cycles = self.reload_cycles.saturating_sub(1);
+            match self.overflow_cycles {
+                Some(1) => {
+                    self.overflow_cycles = None;
+                    self.tima = self.tma;
+                    self.reload_cycles = RELOAD_DELAY;
+                    self.interrupts |= Interrupt::Timer as u8;
+                }
+                Some(cycles) => self.overflow_cycles = Some(cycles - 1),
+                None => {}
+            }
+            let signal = self.signal();
+            self.divider = self.divider.wrapping_add(1);
+            self.detect_falling_edge(signal);
+        }
+        std::mem::take(&mut self.interrupts)
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.divider);
+        writer.write_u8(self.tima);
+        writer.write_u8(self.tma);
+        writer.write_u8(self.tac);
+        writer.write_option(self.overflow_cycles, StateWriter::write_u8);
+        writer.write_u8(self.reload_cycles);
+        writer.write_u8(self.interrupts);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.divider = reader.read_u16()?;
+        self.tima = reader.read_u8()?;
+        self.tma = reader.read_u8()?;
+        self.tac = reader.read_u8()? & 0x07;
+        self.overflow_cycles =
+            reader.read_option(|reader| reader.read_u8_within(1..=RELOAD_DELAY))?;
+        self.reload_cycles = reader.read_u8_within(0..=RELOAD_DELAY)?;
+        self.interrupts = reader.read_u8()?;
+        Ok(())
+    }
+
+    /// Return the bit of the internal counter selected by TAC, ANDed with
+    /// the enable bit of TAC
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7,
+        };
+        self.tac & 0x04 != 0 && self.divider & (1 << bit) != 0
+    }
+
+    fn detect_falling_edge(&mut self, old_signal: bool) {
+        if old_signal && !self.signal() {
+            let (tima, overflow) = self.tima.overflowing_add(1);
+            self.tima = tima;
+            if overflow {
+                self.overflow_cycles = Some(RELOAD_DELAY);
+            }
+        }
+    }
+}
@@ -19,15 +19,28 @@ pub fn msb(word: u16) -> u8 {
 /// Swap upper and lower nibbles of the byte and return the result
 #[inline]
 pub fn swap(byte: u8) -> u8 {
-    (byte << 4) | (byte >> 4)
+    byte.rotate_left(4)
 }
 
 #[inline]
 pub fn rotate_left(byte: u8, amount: u8) -> u8 {
-    (byte << amount) | (byte >> (8 - amount))
+    byte.rotate_left(amount as u32)
 }
 
 #[inline]
 pub fn rotate_right(byte: u8, amount: u8) -> u8 {
-    (byte >> amount) | (byte << (8 - amount))
+    byte.rotate_right(amount as u32)
+}
+
+/// Compute the CRC-32 of the data, as used by zip and PNG
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
 }
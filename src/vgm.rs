@@ -0,0 +1,244 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::Hasher;
+use std::io;
+use std::path::Path;
+
+use crate::apu::CLOCK_RATE;
+use crate::ppu::DOTS_PER_FRAME;
+
+// VGM files are always timed in samples at this rate
+const VGM_SAMPLE_RATE: u64 = 44100;
+const VGM_VERSION: u32 = 0x161;
+const HEADER_SIZE: usize = 0x100;
+
+const COMMAND_DMG_WRITE: u8 = 0xB3;
+const COMMAND_WAIT: u8 = 0x61;
+const COMMAND_WAIT_NTSC_FRAME: u8 = 0x62;
+const COMMAND_WAIT_PAL_FRAME: u8 = 0x63;
+const COMMAND_WAIT_SHORT: u8 = 0x70;
+const COMMAND_END: u8 = 0x66;
+
+// Longest loop searched for, which at about three minutes is longer than
+// the music of any game, and keeps the search from growing with the square
+// of the log's length
+const MAX_LOOP_PERIOD: usize = 10800;
+
+/// Start of a frame of the log, as a position in the command data and in
+/// samples, along with a fingerprint of the APU state at that point and of
+/// the writes made during the frame
+struct Frame {
+    offset: usize,
+    samples: u64,
+    fingerprint: u64,
+    has_writes: bool,
+}
+
+/// Log of the writes to the APU registers, saved as a VGM file
+///
+/// The log is split into frames of real time, each fingerprinted by the
+/// state of the APU when it started and the writes made during it. Music
+/// drivers update the APU once per frame, so a loop in the music shows up as
+/// the fingerprints repeating until the end of the log.
+pub struct VgmLog {
+    data: Vec<u8>,
+    // Dots elapsed since logging started, and since the frame in progress
+    // started
+    clock: u64,
+    frame_dots: u32,
+    // Samples covered by the waits in the data so far
+    samples: u64,
+    frames: Vec<Frame>,
+    // Start of the frame in progress, the hasher fed with its writes, and
+    // whether it has any
+    frame_start: (usize, u64),
+    frame_hasher: DefaultHasher,
+    frame_has_writes: bool,
+}
+
+impl VgmLog {
+    /// Start a log, given the APU state fingerprint and the register writes
+    /// that bring a freshly reset APU to the current state
+    pub fn new(fingerprint: u64, initial_writes: &[(u16, u8)]) -> Self {
+        let mut log = Self {
+            data: Vec::new(),
+            clock: 0,
+            frame_dots: 0,
+            samples: 0,
+            frames: Vec::new(),
+            frame_start: (0, 0),
+            frame_hasher: DefaultHasher::new(),
+            frame_has_writes: false,
+        };
+        for &(address, value) in initial_writes {
+            log.push_write(address, value);
+        }
+        log.start_frame(fingerprint);
+        log
+    }
+
+    /// Log a write to one of the registers from 0xFF10 to 0xFF3F
+    pub fn write(&mut self, address: u16, value: u8) {
+        self.write_waits();
+        self.push_write(address, value);
+        self.frame_has_writes = true;
+        self.frame_hasher.write_u32(self.frame_dots);
+        self.frame_hasher.write_u16(address);
+        self.frame_hasher.write_u8(value);
+    }
+
+    /// Advance the log by one dot, returning whether a new frame starts,
+    /// after which `start_frame` has to be called
+    pub fn tick(&mut self) -> bool {
+        self.clock += 1;
+        self.frame_dots += 1;
+        if self.frame_dots == DOTS_PER_FRAME {
+            self.frame_dots = 0;
+            return true;
+        }
+        false
+    }
+
+    /// Close the frame in progress and start a new one from the given APU
+    /// state fingerprint
+    pub fn start_frame(&mut self, fingerprint: u64) {
+        self.write_waits();
+        if self.clock > 0 {
+            let (offset, samples) = self.frame_start;
+            self.frames.push(Frame {
+                offset,
+                samples,
+                fingerprint: self.frame_hasher.finish(),
+                has_writes: self.frame_has_writes,
+            });
+        }
+        self.frame_start = (self.data.len(), self.samples);
+        self.frame_hasher = DefaultHasher::new();
+        self.frame_hasher.write_u64(fingerprint);
+        self.frame_has_writes = false;
+    }
+
+    /// Write the log to a VGM file. If a loop was found, the log ends after
+    /// its first iteration and loops back to its start.
+    pub fn save(&mut self, path: &Path) -> Result<(), io::Error> {
+        self.write_waits();
+        let (end_offset, end_samples, loop_start) = match self.find_loop() {
+            Some((start, period)) => {
+                let end = &self.frames[start + period];
+                let start = &self.frames[start];
+                (end.offset, end.samples, Some((start.offset, start.samples)))
+            }
+            None => (self.data.len(), self.samples, None),
+        };
+        let mut file = vec![0; HEADER_SIZE];
+        file.extend_from_slice(&self.data[..end_offset]);
+        file.push(COMMAND_END);
+
+        let mut set = |offset: usize, value: u32| {
+            file[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        set(0x08, VGM_VERSION);
+        set(0x18, end_samples as u32);
+        if let Some((offset, samples)) = loop_start {
+            // Offsets in the header are relative to their own position
+            set(0x1C, (HEADER_SIZE + offset - 0x1C) as u32);
+            set(0x20, (end_samples - samples) as u32);
+        }
+        set(0x34, (HEADER_SIZE - 0x34) as u32);
+        set(0x80, CLOCK_RATE);
+        file[0..4].copy_from_slice(b"Vgm ");
+        let eof_offset = (file.len() - 4) as u32;
+        file[4..8].copy_from_slice(&eof_offset.to_le_bytes());
+        fs::write(path, file)
+    }
+
+    /// Find the earliest frame from which the fingerprints repeat with some
+    /// period until the end of the log, for at least two periods. Loops of
+    /// frames without any writes are silence and not music, so they do not
+    /// count. Return the start frame and the period, which is at most
+    /// `MAX_LOOP_PERIOD`.
+    fn find_loop(&self) -> Option<(usize, usize)> {
+        let frames = self.frames.len();
+        let mut best: Option<(usize, usize)> = None;
+        for period in 1..=(frames / 2).min(MAX_LOOP_PERIOD) {
+            let mut start = frames - period;
+            while start > 0
+                && self.frames[start - 1].fingerprint == self.frames[start - 1 + period].fingerprint
+            {
+                start -= 1;
+            }
+            if frames - start < 2 * period {
+                continue;
+            }
+            let has_writes = self.frames[start..start + period]
+                .iter()
+                .any(|frame| frame.has_writes);
+            if has_writes && best.is_none_or(|(best_start, _)| start < best_start) {
+                best = Some((start, period));
+            }
+        }
+        best
+    }
+
+    fn push_write(&mut self, address: u16, value: u8) {
+        self.data
+            .extend_from_slice(&[COMMAND_DMG_WRITE, (address - 0xFF10) as u8, value]);
+    }
+
+    /// Write the waits that bring the data up to the current clock
+    fn write_waits(&mut self) {
+        let samples = self.clock * VGM_SAMPLE_RATE / CLOCK_RATE as u64;
+        let mut remaining = samples - self.samples;
+        self.samples = samples;
+        while remaining > 0 {
+            let wait = remaining.min(0xFFFF);
+            remaining -= wait;
+            match wait {
+                735 => self.data.push(COMMAND_WAIT_NTSC_FRAME),
+                882 => self.data.push(COMMAND_WAIT_PAL_FRAME),
+                1..=16 => self.data.push(COMMAND_WAIT_SHORT + wait as u8 - 1),
+                _ => {
+                    self.data.push(COMMAND_WAIT);
+                    self.data.extend_from_slice(&(wait as u16).to_le_bytes());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_of(frames: &[(u64, bool)]) -> VgmLog {
+        let mut log = VgmLog::new(0, &[]);
+        for &(fingerprint, has_writes) in frames {
+            log.frames.push(Frame {
+                offset: 0,
+                samples: 0,
+                fingerprint,
+                has_writes,
+            });
+        }
+        log
+    }
+
+    #[test]
+    fn loop_is_found_after_the_intro() {
+        let mut frames = vec![(10, true), (11, true)];
+        for _ in 0..3 {
+            frames.extend_from_slice(&[(1, true), (2, false), (3, true)]);
+        }
+        // The loop is cut off partway through its last iteration
+        frames.push((1, true));
+        assert_eq!(log_of(&frames).find_loop(), Some((2, 3)));
+    }
+
+    #[test]
+    fn loop_needs_two_periods_and_writes() {
+        let frames = [(1, true), (2, true), (3, true), (1, true), (2, true)];
+        assert_eq!(log_of(&frames).find_loop(), None);
+        let frames = [(5, true), (1, false), (1, false), (1, false), (1, false)];
+        assert_eq!(log_of(&frames).find_loop(), None);
+    }
+}
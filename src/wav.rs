@@ -0,0 +1,114 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::apu::AudioSink;
+
+const HEADER_SIZE: u32 = 44;
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+const BLOCK_ALIGN: u16 = CHANNELS * BITS_PER_SAMPLE / 8;
+// Most sample bytes the 32-bit RIFF size can account for, in whole blocks
+const MAX_DATA_SIZE: u32 = (u32::MAX - (HEADER_SIZE - 8)) / BLOCK_ALIGN as u32 * BLOCK_ALIGN as u32;
+
+/// Audio sink that writes 16-bit stereo PCM to a WAV file
+///
+/// The sizes in the header are only known once recording ends, so they are
+/// filled in when the writer is finalized, or dropped. Those sizes are 32
+/// bits, so recording stops once the file reaches the 4 GiB they allow.
+pub struct WavWriter {
+    writer: BufWriter<File>,
+    sample_rate: u32,
+    byte_rate: u32,
+    data_size: u32,
+    // First error hit while writing samples, which the sink cannot return
+    error: Option<io::Error>,
+    finalized: bool,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32) -> Result<Self, io::Error> {
+        let byte_rate = sample_rate
+            .checked_mul(BLOCK_ALIGN as u32)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Sample rate too high"))?;
+        let mut wav = Self {
+            writer: BufWriter::new(File::create(path)?),
+            sample_rate,
+            byte_rate,
+            data_size: 0,
+            error: None,
+            finalized: false,
+        };
+        wav.write_header()?;
+        Ok(wav)
+    }
+
+    /// Fill in the sizes in the header and flush the file, returning any
+    /// error hit while recording
+    fn finalize(&mut self) -> Result<(), io::Error> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        if !self.finalized {
+            self.finalized = true;
+            self.writer.seek(SeekFrom::Start(0))?;
+            self.write_header()?;
+            self.writer.seek(SeekFrom::End(0))?;
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> Result<(), io::Error> {
+        let w = &mut self.writer;
+        w.write_all(b"RIFF")?;
+        w.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        w.write_all(b"WAVE")?;
+        w.write_all(b"fmt ")?;
+        w.write_all(&16u32.to_le_bytes())?;
+        // PCM format
+        w.write_all(&1u16.to_le_bytes())?;
+        w.write_all(&CHANNELS.to_le_bytes())?;
+        w.write_all(&self.sample_rate.to_le_bytes())?;
+        w.write_all(&self.byte_rate.to_le_bytes())?;
+        w.write_all(&BLOCK_ALIGN.to_le_bytes())?;
+        w.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        w.write_all(b"data")?;
+        w.write_all(&self.data_size.to_le_bytes())?;
+        Ok(())
+    }
+
+    fn write_sample(&mut self, left: f32, right: f32) -> Result<(), io::Error> {
+        if self.data_size == MAX_DATA_SIZE {
+            return Ok(());
+        }
+        for &sample in &[left, right] {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_size += BLOCK_ALIGN as u32;
+        Ok(())
+    }
+}
+
+impl AudioSink for WavWriter {
+    fn push_sample(&mut self, left: f32, right: f32) {
+        if self.error.is_some() || self.finalized {
+            return;
+        }
+        if let Err(error) = self.write_sample(left, right) {
+            self.error = Some(error);
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), io::Error> {
+        self.finalize()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        let _ = self.finalize();
+    }
+}